@@ -0,0 +1,285 @@
+//! Derive macros for the [gutters](https://docs.rs/gutters) crate.
+//!
+//! Use them through the `derive` feature of gutters rather than directly.
+
+use proc_macro::{Delimiter, TokenStream, TokenTree};
+
+/// Derive `gutters::Log` for a plain old data struct.
+///
+/// The struct must be `#[repr(C)]` (or `#[repr(transparent)]`), have no
+/// padding, and only contain fields that are logs themselves. Each of
+/// these requirements is checked at compile time.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```
+/// use gutters::GutterLog;
+///
+/// #[derive(GutterLog, Default, Debug, PartialEq)]
+/// #[repr(C)]
+/// struct Sample {
+///     timestamp: u64,
+///     channels: [f32; 4],
+/// }
+///
+/// gutters::testing::assert_roundtrip(&Sample { timestamp: 7, channels: [0.5; 4] });
+/// ```
+///
+/// Structs with padding are rejected:
+///
+/// ```compile_fail
+/// # use gutters::GutterLog;
+/// #[derive(GutterLog)]
+/// #[repr(C)]
+/// struct Padded {
+///     flag: u8,
+///     value: u32,
+/// }
+/// ```
+///
+/// So are structs without a defined layout:
+///
+/// ```compile_fail
+/// # use gutters::GutterLog;
+/// #[derive(GutterLog)]
+/// struct Unordered {
+///     a: u32,
+///     b: u32,
+/// }
+/// ```
+///
+/// And fields that aren't logs:
+///
+/// ```compile_fail
+/// # use gutters::GutterLog;
+/// #[derive(GutterLog)]
+/// #[repr(C)]
+/// struct Flagged {
+///     flag: bool,
+/// }
+/// ```
+#[proc_macro_derive(GutterLog)]
+pub fn derive_gutter_log(input: TokenStream) -> TokenStream {
+    expand(parse(input).and_then(gutter_log))
+}
+
+/// Derive `gutters::endian::SwapBytes` for a struct, by reversing the
+/// byte order of each of its fields.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```
+/// use gutters::endian::{self, SwapBytes};
+/// use gutters::GutterLog;
+/// use std::io::Cursor;
+///
+/// #[derive(GutterLog, SwapBytes, Clone, Default)]
+/// #[repr(C)]
+/// struct Sample {
+///     timestamp: u64,
+///     channels: [f32; 4],
+/// }
+///
+/// let mut gutter = Cursor::new(Vec::new());
+/// endian::throw_be(&mut gutter, &Sample { timestamp: 7, ..Sample::default() })?;
+/// assert_eq!(gutter.get_ref()[..8], [0, 0, 0, 0, 0, 0, 0, 7]);
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[proc_macro_derive(SwapBytes)]
+pub fn derive_swap_bytes(input: TokenStream) -> TokenStream {
+    expand(parse(input).and_then(swap_bytes))
+}
+
+fn expand(output: Result<String, String>) -> TokenStream {
+    let output = output.unwrap_or_else(|message| format!("::core::compile_error!({:?});", message));
+    output.parse().unwrap()
+}
+
+/// A struct the macros are derived for.
+struct Struct {
+    name: String,
+    repr_c: bool,
+    /// Accessors and types of the fields, as source text.
+    fields: Vec<(String, String)>,
+}
+
+fn gutter_log(input: Struct) -> Result<String, String> {
+    let Struct {
+        name,
+        repr_c,
+        fields,
+    } = input;
+    if !repr_c {
+        return Err(format!(
+            "`{}` must be #[repr(C)] or #[repr(transparent)] to derive GutterLog",
+            name
+        ));
+    }
+
+    let mut checks = String::new();
+    let mut size = String::from("0");
+    for (_, field) in &fields {
+        checks.push_str(&format!("let _ = assert_log::<{}>;\n", field));
+        size.push_str(&format!(" + ::core::mem::size_of::<{}>()", field));
+    }
+
+    Ok(format!(
+        "const _: () = {{
+            fn assert_log<T: ::gutters::Log>() {{}}
+            {checks}
+            ::core::assert!(
+                ::core::mem::size_of::<{name}>() == {size},
+                \"`{name}` has padding bytes, so GutterLog can't be derived for it\",
+            );
+        }};
+        unsafe impl ::gutters::Log for {name} {{}}",
+        checks = checks,
+        name = name,
+        size = size,
+    ))
+}
+
+fn swap_bytes(input: Struct) -> Result<String, String> {
+    let mut body = String::new();
+    for (field, _) in &input.fields {
+        body.push_str(&format!(
+            "::gutters::endian::SwapBytes::swap_bytes_in_place(&mut self.{});\n",
+            field
+        ));
+    }
+    Ok(format!(
+        "impl ::gutters::endian::SwapBytes for {} {{
+            fn swap_bytes_in_place(&mut self) {{
+                {}
+            }}
+        }}",
+        input.name, body
+    ))
+}
+
+fn parse(input: TokenStream) -> Result<Struct, String> {
+    let mut tokens = input.into_iter().peekable();
+
+    let mut repr_c = false;
+    while let Some(TokenTree::Punct(punct)) = tokens.peek() {
+        if punct.as_char() != '#' {
+            break;
+        }
+        tokens.next();
+        if let Some(TokenTree::Group(attribute)) = tokens.next() {
+            repr_c |= is_repr_c(attribute.stream());
+        }
+    }
+    skip_visibility(&mut tokens);
+
+    match tokens.next() {
+        Some(TokenTree::Ident(ident)) if ident.to_string() == "struct" => {}
+        _ => return Err("gutters macros can only be derived for structs".to_owned()),
+    }
+    let name = match tokens.next() {
+        Some(TokenTree::Ident(ident)) => ident.to_string(),
+        _ => return Err("expected a struct name".to_owned()),
+    };
+
+    let fields = match tokens.next() {
+        Some(TokenTree::Group(group)) if group.delimiter() == Delimiter::Brace => {
+            struct_fields(group.stream(), true)
+        }
+        Some(TokenTree::Group(group)) if group.delimiter() == Delimiter::Parenthesis => {
+            struct_fields(group.stream(), false)
+        }
+        Some(TokenTree::Punct(punct)) if punct.as_char() == ';' => Vec::new(),
+        Some(TokenTree::Punct(punct)) if punct.as_char() == '<' => {
+            return Err(format!(
+                "gutters macros can't be derived for generic struct `{}`",
+                name
+            ))
+        }
+        _ => return Err(format!("unexpected tokens after `{}`", name)),
+    };
+
+    Ok(Struct {
+        name,
+        repr_c,
+        fields,
+    })
+}
+
+/// Check whether the content of an attribute is `repr(C)` or
+/// `repr(transparent)`, possibly among other representation hints.
+fn is_repr_c(attribute: TokenStream) -> bool {
+    let mut tokens = attribute.into_iter();
+    match tokens.next() {
+        Some(TokenTree::Ident(ident)) if ident.to_string() == "repr" => {}
+        _ => return false,
+    }
+    match tokens.next() {
+        Some(TokenTree::Group(hints)) => hints.stream().into_iter().any(|hint| {
+            matches!(&hint, TokenTree::Ident(ident) if ident.to_string() == "C" || ident.to_string() == "transparent")
+        }),
+        _ => false,
+    }
+}
+
+fn skip_visibility<I: Iterator<Item = TokenTree>>(tokens: &mut std::iter::Peekable<I>) {
+    if let Some(TokenTree::Ident(ident)) = tokens.peek() {
+        if ident.to_string() == "pub" {
+            tokens.next();
+            if let Some(TokenTree::Group(group)) = tokens.peek() {
+                if group.delimiter() == Delimiter::Parenthesis {
+                    tokens.next();
+                }
+            }
+        }
+    }
+}
+
+/// Extract the accessors and types of the fields of a struct body, as
+/// source text.
+fn struct_fields(body: TokenStream, named: bool) -> Vec<(String, String)> {
+    // Split on top level commas, minding the ones inside generic
+    // arguments, which aren't token groups.
+    let mut fields = vec![Vec::new()];
+    let mut depth = 0usize;
+    for token in body {
+        if let TokenTree::Punct(punct) = &token {
+            match punct.as_char() {
+                '<' => depth += 1,
+                '>' => depth = depth.saturating_sub(1),
+                ',' if depth == 0 => {
+                    fields.push(Vec::new());
+                    continue;
+                }
+                _ => {}
+            }
+        }
+        fields.last_mut().unwrap().push(token);
+    }
+
+    fields
+        .into_iter()
+        .filter(|field| !field.is_empty())
+        .enumerate()
+        .map(|(index, field)| {
+            let mut tokens = field.into_iter().peekable();
+            while matches!(tokens.peek(), Some(TokenTree::Punct(punct)) if punct.as_char() == '#') {
+                tokens.next();
+                tokens.next();
+            }
+            skip_visibility(&mut tokens);
+            let accessor = if named {
+                // Take the name, and skip the colon.
+                let name = tokens.next().map(|name| name.to_string());
+                tokens.next();
+                name.unwrap_or_default()
+            } else {
+                index.to_string()
+            };
+            (accessor, tokens.collect::<TokenStream>().to_string())
+        })
+        .collect()
+}
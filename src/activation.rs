@@ -0,0 +1,220 @@
+//! Socket activation by systemd.
+//!
+//! With socket activation, systemd binds the listening sockets of a
+//! service itself, and passes them to the service when it starts.
+//! Connections arriving while the service restarts wait in the backlog
+//! of the socket instead of being refused. [`listen_fds`] adopts these
+//! sockets, which can then be turned into a `TcpListener`, a
+//! `UnixListener` or a [`LocalListener`](crate::local::LocalListener),
+//! e.g. for a [`Drain`](crate::drain::Drain), and [`notify_ready`] tells
+//! systemd once the service is up.
+//!
+//! All functions do nothing when the service wasn't started by systemd,
+//! so the same binary can still bind its own sockets.
+
+use std::env;
+use std::io::{Error, ErrorKind, Result};
+use std::os::fd::{FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixDatagram;
+
+/// First file descriptor passed by systemd.
+const LISTEN_FDS_START: RawFd = 3;
+
+extern "C" {
+    fn fcntl(fd: i32, cmd: i32, ...) -> i32;
+}
+
+const F_SETFD: i32 = 2;
+const FD_CLOEXEC: i32 = 1;
+
+/// Take the sockets passed by systemd, in the order of the socket unit.
+///
+/// This returns no socket if the process wasn't socket-activated. The
+/// environment variables describing the sockets are removed, so that
+/// child processes don't take them as theirs, and calling this function
+/// again returns no socket either. As it modifies the environment, this
+/// function should be called early on, before spawning any thread.
+///
+/// This function fails with [`ErrorKind::InvalidData`] if the
+/// environment variables are malformed.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use gutters::activation;
+/// use gutters::drain::Drain;
+/// use std::net::TcpListener;
+///
+/// let listener = match activation::listen_fds()?.pop() {
+///     Some(fd) => TcpListener::from(fd),
+///     None => TcpListener::bind("0.0.0.0:34567")?,
+/// };
+/// activation::notify_ready()?;
+/// Drain::new(listener).run(|_gutter| Ok(()))?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn listen_fds() -> Result<Vec<OwnedFd>> {
+    Ok(listen_fds_with_names()?
+        .into_iter()
+        .map(|(_, fd)| fd)
+        .collect())
+}
+
+/// Take the sockets passed by systemd, along with their names, as set
+/// with `FileDescriptorName=` in the socket unit.
+///
+/// Sockets without a name are named `unknown`, as systemd does. See
+/// [`listen_fds`].
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```
+/// use gutters::activation;
+/// use std::env;
+/// use std::io::ErrorKind;
+///
+/// let pid = std::process::id().to_string();
+///
+/// // Sockets meant for another process are left alone.
+/// env::set_var("LISTEN_PID", "1");
+/// env::set_var("LISTEN_FDS", "1");
+/// assert!(activation::listen_fds_with_names()?.is_empty());
+/// assert!(env::var_os("LISTEN_FDS").is_none());
+///
+/// env::set_var("LISTEN_PID", &pid);
+/// env::set_var("LISTEN_FDS", "0");
+/// assert!(activation::listen_fds_with_names()?.is_empty());
+///
+/// env::set_var("LISTEN_PID", &pid);
+/// env::set_var("LISTEN_FDS", "two");
+/// let error = activation::listen_fds_with_names().unwrap_err();
+/// assert_eq!(error.kind(), ErrorKind::InvalidData);
+///
+/// env::set_var("LISTEN_PID", &pid);
+/// env::set_var("LISTEN_FDS", "2");
+/// env::set_var("LISTEN_FDNAMES", "http");
+/// let error = activation::listen_fds_with_names().unwrap_err();
+/// assert_eq!(error.kind(), ErrorKind::InvalidData);
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn listen_fds_with_names() -> Result<Vec<(String, OwnedFd)>> {
+    let pid = env::var("LISTEN_PID");
+    let count = env::var("LISTEN_FDS");
+    let names = env::var("LISTEN_FDNAMES");
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    let invalid = |message| Error::new(ErrorKind::InvalidData, message);
+    let (Ok(pid), Ok(count)) = (pid, count) else {
+        return Ok(Vec::new());
+    };
+    let pid: u32 = pid.parse().map_err(|_| invalid("invalid LISTEN_PID"))?;
+    if pid != std::process::id() {
+        // The sockets were meant for another process.
+        return Ok(Vec::new());
+    }
+    let count: RawFd = count.parse().map_err(|_| invalid("invalid LISTEN_FDS"))?;
+    if !(0..=RawFd::MAX - LISTEN_FDS_START).contains(&count) {
+        return Err(invalid("invalid LISTEN_FDS"));
+    }
+    let names: Vec<String> = match names {
+        Ok(names) => names.split(':').map(str::to_owned).collect(),
+        Err(_) => Vec::new(),
+    };
+    if !names.is_empty() && names.len() != count as usize {
+        return Err(invalid("LISTEN_FDNAMES doesn't match LISTEN_FDS"));
+    }
+
+    let mut fds = Vec::with_capacity(count as usize);
+    for (i, fd) in (LISTEN_FDS_START..LISTEN_FDS_START + count).enumerate() {
+        // SAFETY: systemd passed these descriptors to this process, which
+        // takes ownership of them exactly once, as their description is
+        // removed from the environment.
+        let fd = unsafe {
+            if fcntl(fd, F_SETFD, FD_CLOEXEC) == -1 {
+                return Err(Error::last_os_error());
+            }
+            OwnedFd::from_raw_fd(fd)
+        };
+        let name = names
+            .get(i)
+            .cloned()
+            .unwrap_or_else(|| "unknown".to_owned());
+        fds.push((name, fd));
+    }
+    Ok(fds)
+}
+
+/// Send `state` to systemd, e.g. `READY=1` or `STATUS=...`, one
+/// assignment per line.
+///
+/// This returns `false` if the process wasn't started by systemd with
+/// a notification socket.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```
+/// use gutters::activation;
+/// use std::env;
+/// use std::os::unix::net::UnixDatagram;
+///
+/// env::remove_var("NOTIFY_SOCKET");
+/// assert!(!activation::notify("STATUS=starting")?);
+///
+/// let path = env::temp_dir().join(format!("gutters-notify-{}", std::process::id()));
+/// let systemd = UnixDatagram::bind(&path)?;
+/// env::set_var("NOTIFY_SOCKET", &path);
+/// assert!(activation::notify_ready()?);
+///
+/// let mut state = [0u8; 16];
+/// let len = systemd.recv(&mut state)?;
+/// assert_eq!(&state[..len], b"READY=1");
+/// std::fs::remove_file(&path)?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn notify(state: &str) -> Result<bool> {
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return Ok(false);
+    };
+    let socket = UnixDatagram::unbound()?;
+    let bytes = path.as_encoded_bytes();
+    if let Some(name) = bytes.strip_prefix(b"@") {
+        send_abstract(&socket, name, state)?;
+    } else {
+        socket.send_to(state.as_bytes(), path)?;
+    }
+    Ok(true)
+}
+
+/// Tell systemd that the service is ready to serve, once its sockets
+/// are listening.
+///
+/// See [`notify`].
+pub fn notify_ready() -> Result<bool> {
+    notify("READY=1")
+}
+
+#[cfg(target_os = "linux")]
+fn send_abstract(socket: &UnixDatagram, name: &[u8], state: &str) -> Result<()> {
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::net::SocketAddr;
+
+    let address = SocketAddr::from_abstract_name(name)?;
+    socket.send_to_addr(state.as_bytes(), &address)?;
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn send_abstract(_socket: &UnixDatagram, _name: &[u8], _state: &str) -> Result<()> {
+    Err(Error::new(
+        ErrorKind::Unsupported,
+        "abstract notification sockets are only supported on Linux",
+    ))
+}
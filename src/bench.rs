@@ -0,0 +1,135 @@
+//! A standardized throughput and latency test.
+//!
+//! Run [`echo_server`] on one end of a gutter and [`measure`] on the
+//! other one. The results are comparable between transports and
+//! tunings as long as the same message size and duration are used.
+
+use std::io::{ErrorKind, Read, Result, Write};
+use std::time::{Duration, Instant};
+
+/// Results of a [`measure`] run.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Report {
+    /// Number of messages that made a full round trip.
+    pub messages: u64,
+    /// Size of each message, in bytes.
+    pub msg_size: usize,
+    /// Total duration of the run.
+    pub elapsed: Duration,
+    /// Round trips per second.
+    pub msgs_per_sec: f64,
+    /// Megabytes (10^6 bytes) sent per second, in one direction.
+    pub mb_per_sec: f64,
+    /// Median round trip time.
+    pub p50: Duration,
+    /// 99th percentile round trip time.
+    pub p99: Duration,
+}
+
+/// Send messages of `msg_size` bytes down the `gutter` for `duration`,
+/// waiting for each one to be echoed back.
+///
+/// The peer must be running [`echo_server`]. At least one message is
+/// always sent, even if `duration` is zero.
+///
+/// This function is blocking.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```
+/// use gutters::bench::{echo_server, measure};
+/// use std::net::{TcpListener, TcpStream};
+/// use std::time::Duration;
+///
+/// let listener = TcpListener::bind("127.0.0.1:0")?;
+/// let address = listener.local_addr()?;
+/// std::thread::spawn(move || {
+///     let (mut stream, _) = listener.accept().unwrap();
+///     echo_server(&mut stream).unwrap();
+/// });
+///
+/// let mut stream = TcpStream::connect(address)?;
+/// let report = measure(&mut stream, 64, Duration::from_millis(50))?;
+/// assert!(report.messages > 0);
+/// println!("{:.0} msgs/s, p99 = {:?}", report.msgs_per_sec, report.p99);
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn measure<G: Read + Write>(
+    gutter: &mut G,
+    msg_size: usize,
+    duration: Duration,
+) -> Result<Report> {
+    let message: Vec<u8> = (0..msg_size).map(|i| i as u8).collect();
+    let mut echo = vec![0u8; msg_size];
+    let mut rtts = Vec::new();
+
+    let start = Instant::now();
+    loop {
+        let sent = Instant::now();
+        gutter.write_all(&message)?;
+        gutter.flush()?;
+        gutter.read_exact(&mut echo)?;
+        rtts.push(sent.elapsed());
+
+        if start.elapsed() >= duration {
+            break;
+        }
+    }
+    let elapsed = start.elapsed();
+
+    rtts.sort_unstable();
+    let messages = rtts.len() as u64;
+    let seconds = elapsed.as_secs_f64();
+    Ok(Report {
+        messages,
+        msg_size,
+        elapsed,
+        msgs_per_sec: messages as f64 / seconds,
+        mb_per_sec: (messages as f64 * msg_size as f64) / seconds / 1e6,
+        p50: percentile(&rtts, 0.50),
+        p99: percentile(&rtts, 0.99),
+    })
+}
+
+/// Send back everything received from the `gutter`, until the peer
+/// disconnects.
+///
+/// This is the peer expected by [`measure`]. Since it echoes raw bytes, it
+/// works for any message size.
+///
+/// This function is blocking.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use gutters::bench::echo_server;
+/// use std::net::TcpListener;
+///
+/// let listener = TcpListener::bind("127.0.0.1:34567")?;
+/// for stream in listener.incoming() {
+///     echo_server(&mut stream?)?;
+/// }
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn echo_server<G: Read + Write>(gutter: &mut G) -> Result<()> {
+    let mut buffer = [0u8; 64 * 1024];
+    loop {
+        let n = match gutter.read(&mut buffer) {
+            Ok(0) => return Ok(()),
+            Ok(n) => n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        gutter.write_all(&buffer[..n])?;
+        gutter.flush()?;
+    }
+}
+
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    let index = ((sorted.len() - 1) as f64 * p).round() as usize;
+    sorted[index]
+}
@@ -0,0 +1,222 @@
+//! Link aggregation over several gutters.
+//!
+//! A [`BondedGutter`] spreads logs across several underlying gutters,
+//! either [striping](Bonding::Stripe) them to add up the throughput of
+//! the links, or [duplicating](Bonding::Duplicate) them to survive the
+//! loss of any but one. Each log is preceded by a sequence number, so
+//! that the receiving end delivers logs in order and exactly once.
+//!
+//! Both ends must use the same bonding, and the same links in the same
+//! order.
+
+use std::io::{Error, ErrorKind, Read, Result, Write};
+
+use crate::{as_u8_slice, as_u8_slice_mut, Log};
+
+/// How logs are spread across the links of a [`BondedGutter`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bonding {
+    /// Throw each log down a single link, in turn.
+    ///
+    /// Throughput adds up, but the failure of any link is fatal.
+    Stripe,
+    /// Throw each log down every link.
+    ///
+    /// Links that fail are dropped, and the gutter keeps working as long
+    /// as one link remains, but it is only as fast as the slowest link.
+    ///
+    /// Only links that fail with an error are survived: links are read
+    /// in turn, so one that stalls silently blocks the receiving end,
+    /// even though the others hold the log. Give each link a read
+    /// timeout longer than the interval between logs, e.g. with
+    /// [`TcpStream::set_read_timeout`](std::net::TcpStream::set_read_timeout),
+    /// for a stall to count as a failure.
+    Duplicate,
+}
+
+/// A gutter made of several underlying links.
+#[derive(Debug)]
+pub struct BondedGutter<G> {
+    links: Vec<G>,
+    bonding: Bonding,
+    next_link: usize,
+    thrown: u64,
+    picked_up: u64,
+}
+
+impl<G: Read + Write> BondedGutter<G> {
+    /// Create a gutter spreading logs across `links`.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `links` is empty.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```no_run
+    /// use gutters::bonded::{BondedGutter, Bonding};
+    /// use std::net::TcpStream;
+    ///
+    /// let links = (0..4)
+    ///     .map(|_| TcpStream::connect("127.0.0.1:34567"))
+    ///     .collect::<Result<Vec<_>, _>>()?;
+    /// let mut gutter = BondedGutter::new(links, Bonding::Stripe);
+    ///
+    /// gutter.throw(&64.0)?;
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    pub fn new(links: Vec<G>, bonding: Bonding) -> Self {
+        assert!(!links.is_empty(), "bonded gutter has no link");
+        BondedGutter {
+            links,
+            bonding,
+            next_link: 0,
+            thrown: 0,
+            picked_up: 0,
+        }
+    }
+
+    /// Return the number of links still in use.
+    pub fn links(&self) -> usize {
+        self.links.len()
+    }
+
+    /// Send a message of type `T`, preceded by its sequence number.
+    ///
+    /// This function is blocking.
+    ///
+    /// With [`Bonding::Duplicate`], a link that fails is dropped, and an
+    /// error is only returned once all links have failed.
+    ///
+    /// This function doesn't change endianness, so it must be the
+    /// same between the peers.
+    pub fn throw<T: Log>(&mut self, buffer: &T) -> Result<()> {
+        if self.links.is_empty() {
+            return Err(all_links_failed());
+        }
+        let bytes = as_u8_slice(buffer);
+        self.thrown += 1;
+        let sequence = self.thrown;
+
+        match self.bonding {
+            Bonding::Stripe => {
+                let link = self.next_link;
+                self.next_link = (link + 1) % self.links.len();
+                send(&mut self.links[link], sequence, bytes)
+            }
+            Bonding::Duplicate => {
+                let mut error = None;
+                self.links
+                    .retain_mut(|link| match send(link, sequence, bytes) {
+                        Ok(()) => true,
+                        Err(e) => {
+                            error = Some(e);
+                            false
+                        }
+                    });
+                match error {
+                    Some(e) if self.links.is_empty() => Err(e),
+                    _ => Ok(()),
+                }
+            }
+        }
+    }
+
+    /// Read the next message of type `T`, in the order it was thrown.
+    ///
+    /// This function is blocking.
+    ///
+    /// With [`Bonding::Stripe`], this function fails with
+    /// [`ErrorKind::InvalidData`] if a log arrives out of sequence. With
+    /// [`Bonding::Duplicate`], a link that fails is dropped, and an error
+    /// is only returned once all links have failed, but a link that
+    /// stalls without failing blocks this function.
+    ///
+    /// This function doesn't change endianness, so it must be the
+    /// same between the peers.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```
+    /// use gutters::bonded::{BondedGutter, Bonding};
+    /// use std::io::Cursor;
+    ///
+    /// let links = vec![Cursor::new(Vec::new()), Cursor::new(Vec::new())];
+    /// let mut sender = BondedGutter::new(links, Bonding::Stripe);
+    /// for i in 0..5u32 {
+    ///     sender.throw(&i)?;
+    /// }
+    ///
+    /// let mut links = sender.into_inner();
+    /// links.iter_mut().for_each(|link| link.set_position(0));
+    /// let mut receiver = BondedGutter::new(links, Bonding::Stripe);
+    /// let mut data = 0u32;
+    /// for i in 0..5u32 {
+    ///     receiver.pick_up(&mut data)?;
+    ///     assert_eq!(data, i);
+    /// }
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    pub fn pick_up<T: Log>(&mut self, buffer: &mut T) -> Result<()> {
+        let bytes = as_u8_slice_mut(buffer);
+        loop {
+            if self.links.is_empty() {
+                return Err(all_links_failed());
+            }
+            self.next_link %= self.links.len();
+
+            let sequence = match receive(&mut self.links[self.next_link], bytes) {
+                Ok(sequence) => sequence,
+                Err(e) if self.bonding == Bonding::Stripe => return Err(e),
+                Err(e) => {
+                    self.links.remove(self.next_link);
+                    if self.links.is_empty() {
+                        return Err(e);
+                    }
+                    continue;
+                }
+            };
+            self.next_link += 1;
+
+            match self.bonding {
+                Bonding::Stripe if sequence != self.picked_up + 1 => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        "bonded links are out of sequence",
+                    ));
+                }
+                Bonding::Duplicate if sequence <= self.picked_up => continue,
+                _ => {
+                    self.picked_up = sequence;
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// Move the links out, leaving out those that were dropped.
+    pub fn into_inner(self) -> Vec<G> {
+        self.links
+    }
+}
+
+fn all_links_failed() -> Error {
+    Error::new(ErrorKind::NotConnected, "all bonded links have failed")
+}
+
+fn send<G: Write>(gutter: &mut G, sequence: u64, bytes: &[u8]) -> Result<()> {
+    gutter.write_all(&sequence.to_ne_bytes())?;
+    gutter.write_all(bytes)?;
+    gutter.flush()
+}
+
+fn receive<G: Read>(gutter: &mut G, bytes: &mut [u8]) -> Result<u64> {
+    let mut sequence = [0u8; 8];
+    gutter.read_exact(&mut sequence)?;
+    gutter.read_exact(bytes)?;
+    Ok(u64::from_ne_bytes(sequence))
+}
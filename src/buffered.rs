@@ -0,0 +1,374 @@
+//! A buffered gutter.
+//!
+//! The free functions of this crate hit the gutter directly, which costs
+//! a system call per log on sockets. A [`Gutter`] instead buffers both
+//! directions, so that thousands of small logs can be thrown with a
+//! handful of writes. Thrown logs only reach the peer once the gutter is
+//! [flushed](Gutter::flush), which also happens before every read, so
+//! that a peer is never left waiting for a log still in the buffer.
+//!
+//! On a non-blocking gutter, [`try_pick_up`](Gutter::try_pick_up) and
+//! [`try_wait`](Gutter::try_wait) return `None` until a whole log has
+//! arrived, keeping the part of it already read for the next call.
+
+use std::io::{self, BufReader, Error, ErrorKind, Read, Result, Write};
+
+use crate::{as_u8_slice, as_u8_slice_mut, Log};
+
+const DEFAULT_CAPACITY: usize = 8 * 1024;
+
+/// A gutter with read and write buffers.
+///
+/// `Gutter` implements [`Read`] and [`Write`] itself, so it can also be
+/// passed to any function of this crate. Pending logs are flushed when
+/// it is dropped, ignoring errors.
+#[derive(Debug)]
+pub struct Gutter<G: Write> {
+    // Only taken by `into_inner`, so that the gutter can be moved out
+    // despite the `Drop` implementation.
+    reader: Option<BufReader<G>>,
+    buffer: Vec<u8>,
+    partial: Vec<u8>,
+}
+
+impl<G: Read + Write> Gutter<G> {
+    /// Wrap `gutter` with buffers of the default capacity, currently
+    /// 8 KiB each.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```no_run
+    /// use gutters::Gutter;
+    /// use std::net::TcpStream;
+    ///
+    /// let mut gutter = Gutter::new(TcpStream::connect("127.0.0.1:34567")?);
+    /// for i in 0..10_000u32 {
+    ///     gutter.throw(&i)?;
+    /// }
+    /// gutter.flush()?;
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    pub fn new(gutter: G) -> Self {
+        Gutter::with_capacity(DEFAULT_CAPACITY, DEFAULT_CAPACITY, gutter)
+    }
+
+    /// Wrap `gutter` with buffers of `read_capacity` and
+    /// `write_capacity` bytes.
+    pub fn with_capacity(read_capacity: usize, write_capacity: usize, gutter: G) -> Self {
+        Gutter {
+            reader: Some(BufReader::with_capacity(read_capacity, gutter)),
+            buffer: Vec::with_capacity(write_capacity),
+            partial: Vec::new(),
+        }
+    }
+
+    /// Buffer a message of type `T` to be sent.
+    ///
+    /// This function only blocks when the write buffer is full.
+    ///
+    /// This function doesn't change endianness, so it must be the
+    /// same between the peers.
+    pub fn throw<T: Log>(&mut self, buffer: &T) -> Result<()> {
+        self.write_all(as_u8_slice(buffer))
+    }
+
+    /// Read a message of type `T`, flushing pending logs first.
+    ///
+    /// This function is blocking.
+    ///
+    /// This function doesn't change endianness, so it must be the
+    /// same between the peers.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```
+    /// use gutters::Gutter;
+    /// use std::io::Cursor;
+    ///
+    /// let mut gutter = Gutter::new(Cursor::new(Vec::new()));
+    /// gutter.throw(&64.0f64)?;
+    ///
+    /// let mut cursor = gutter.into_inner()?;
+    /// cursor.set_position(0);
+    /// let mut gutter = Gutter::new(cursor);
+    /// let mut data = 0.0f64;
+    /// gutter.pick_up(&mut data)?;
+    /// assert_eq!(data, 64.0);
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    pub fn pick_up<T: Log>(&mut self, buffer: &mut T) -> Result<()> {
+        crate::pick_up(self, buffer)
+    }
+
+    /// Read a message of type `T` if it has fully arrived, flushing
+    /// pending logs first.
+    ///
+    /// This function is meant for non-blocking gutters. It returns
+    /// `None` when the message hasn't fully arrived yet, in which case
+    /// the bytes already read are kept for the next call.
+    ///
+    /// This function doesn't change endianness, so it must be the
+    /// same between the peers.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```
+    /// use gutters::{throw, Gutter};
+    /// use std::net::{TcpListener, TcpStream};
+    ///
+    /// let listener = TcpListener::bind("127.0.0.1:0")?;
+    /// let stream = TcpStream::connect(listener.local_addr()?)?;
+    /// stream.set_nonblocking(true)?;
+    /// let mut gutter = Gutter::new(stream);
+    /// let (mut peer, _) = listener.accept()?;
+    ///
+    /// assert_eq!(gutter.try_pick_up::<f64>()?, None);
+    ///
+    /// throw(&mut peer, &64.0f64)?;
+    /// let data = loop {
+    ///     if let Some(data) = gutter.try_pick_up::<f64>()? {
+    ///         break data;
+    ///     }
+    ///     // Do something else in the meantime.
+    /// };
+    /// assert_eq!(data, 64.0);
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    pub fn try_pick_up<T: Log + Default>(&mut self) -> Result<Option<T>> {
+        let mut log = T::default();
+        let bytes = as_u8_slice_mut(&mut log);
+        if !self.try_fill(bytes.len())? {
+            return Ok(None);
+        }
+        bytes.copy_from_slice(&self.partial[..bytes.len()]);
+        self.partial.drain(..bytes.len());
+        Ok(Some(log))
+    }
+
+    /// Wait for an acknowledgment if it has arrived, flushing pending
+    /// logs first.
+    ///
+    /// This function is meant for non-blocking gutters. It returns
+    /// `None` when no acknowledgment has arrived yet.
+    pub fn try_wait(&mut self) -> Result<Option<()>> {
+        if !self.try_fill(1)? {
+            return Ok(None);
+        }
+        self.partial.remove(0);
+        Ok(Some(()))
+    }
+
+    /// Return the number of bytes received but not picked up yet.
+    pub fn received(&self) -> usize {
+        self.partial.len() + self.reader().buffer().len()
+    }
+
+    /// Buffer an acknowledgment to be sent.
+    ///
+    /// See [`hail`](crate::hail).
+    pub fn hail(&mut self) -> Result<()> {
+        self.write_all(b"\n")
+    }
+
+    /// Wait for an acknowledgment, flushing pending logs first.
+    ///
+    /// This function is blocking.
+    ///
+    /// See [`wait`](crate::wait).
+    pub fn wait(&mut self) -> Result<()> {
+        crate::wait(self)
+    }
+
+    /// Send all pending logs down the gutter.
+    ///
+    /// This function is blocking.
+    pub fn flush(&mut self) -> Result<()> {
+        self.flush_buffer()?;
+        self.reader_mut().get_mut().flush()
+    }
+
+    pub(crate) fn write_capacity(&self) -> usize {
+        self.buffer.capacity()
+    }
+
+    /// Get a reference to the underlying gutter.
+    pub fn get_ref(&self) -> &G {
+        self.reader().get_ref()
+    }
+
+    /// Get a mutable reference to the underlying gutter.
+    ///
+    /// Reading from or writing to it directly, while logs are buffered,
+    /// mixes up the streams.
+    pub fn get_mut(&mut self) -> &mut G {
+        self.reader_mut().get_mut()
+    }
+
+    /// Flush pending logs, and give the underlying gutter back.
+    ///
+    /// Any data already read from the gutter but not picked up yet is
+    /// lost.
+    pub fn into_inner(mut self) -> Result<G> {
+        self.flush()?;
+        // The write buffer is empty once flushed, so dropping `self`
+        // without its reader has nothing left to write.
+        let reader = self.reader.take().expect("the gutter is still there");
+        Ok(reader.into_inner())
+    }
+
+    /// Flush pending logs, and give the underlying gutter back along with
+    /// the data already read from it but not picked up yet.
+    pub(crate) fn into_parts(mut self) -> Result<(G, Vec<u8>)> {
+        let mut unread = std::mem::take(&mut self.partial);
+        unread.extend_from_slice(self.reader().buffer());
+        Ok((self.into_inner()?, unread))
+    }
+
+    /// Wrap `gutter` with buffers of the default capacity, as if `unread`
+    /// had already been read from it.
+    pub(crate) fn from_parts(gutter: G, unread: Vec<u8>) -> Self {
+        let mut gutter = Gutter::new(gutter);
+        gutter.partial = unread;
+        gutter
+    }
+
+    /// Check whether the peer has closed the gutter, and no data is left
+    /// to pick up.
+    ///
+    /// This blocks until data arrives, or the gutter is closed.
+    pub(crate) fn at_eof(&mut self) -> Result<bool> {
+        use std::io::BufRead;
+
+        if !self.buffer.is_empty() {
+            self.flush()?;
+        }
+        if !self.partial.is_empty() {
+            return Ok(false);
+        }
+        loop {
+            match self.reader_mut().fill_buf() {
+                Ok(buf) => return Ok(buf.is_empty()),
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Read into the partial log until it holds at least `len` bytes,
+    /// returning whether it does.
+    fn try_fill(&mut self, len: usize) -> Result<bool> {
+        if !self.buffer.is_empty() {
+            // The unsent logs are kept for the next flush, so a full
+            // write side doesn't stop reading.
+            match self.flush() {
+                Err(e) if e.kind() != ErrorKind::WouldBlock => return Err(e),
+                _ => {}
+            }
+        }
+        while self.partial.len() < len {
+            let start = self.partial.len();
+            self.partial.resize(len, 0);
+            let reader = self.reader.as_mut().expect("the gutter is still there");
+            match reader.read(&mut self.partial[start..]) {
+                Ok(0) => {
+                    self.partial.truncate(start);
+                    return Err(Error::new(
+                        ErrorKind::UnexpectedEof,
+                        "failed to fill whole buffer",
+                    ));
+                }
+                Ok(n) => self.partial.truncate(start + n),
+                Err(e) => {
+                    self.partial.truncate(start);
+                    match e.kind() {
+                        ErrorKind::WouldBlock => return Ok(false),
+                        ErrorKind::Interrupted => {}
+                        _ => return Err(e),
+                    }
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    /// Write the buffered logs out, keeping whatever wasn't written, so
+    /// that no byte is sent twice after an error, e.g. `WouldBlock`.
+    fn flush_buffer(&mut self) -> Result<()> {
+        while !self.buffer.is_empty() {
+            let reader = self.reader.as_mut().expect("the gutter is still there");
+            match reader.get_mut().write(&self.buffer) {
+                Ok(0) => {
+                    return Err(Error::new(
+                        ErrorKind::WriteZero,
+                        "failed to write the buffered logs",
+                    ))
+                }
+                Ok(n) => {
+                    self.buffer.drain(..n);
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<G: Read + Write> Read for Gutter<G> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if !self.buffer.is_empty() {
+            self.flush()?;
+        }
+        if !self.partial.is_empty() {
+            let n = buf.len().min(self.partial.len());
+            buf[..n].copy_from_slice(&self.partial[..n]);
+            self.partial.drain(..n);
+            return Ok(n);
+        }
+        self.reader_mut().read(buf)
+    }
+}
+
+impl<G: Read + Write> Write for Gutter<G> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        if self.buffer.len() + buf.len() > self.buffer.capacity() {
+            self.flush_buffer()?;
+        }
+        if buf.len() >= self.buffer.capacity() {
+            self.reader_mut().get_mut().write(buf)
+        } else {
+            self.buffer.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Gutter::flush(self)
+    }
+}
+
+impl<G: Write> Gutter<G> {
+    fn reader(&self) -> &BufReader<G> {
+        self.reader.as_ref().expect("the gutter is still there")
+    }
+
+    fn reader_mut(&mut self) -> &mut BufReader<G> {
+        self.reader.as_mut().expect("the gutter is still there")
+    }
+}
+
+impl<G: Write> Drop for Gutter<G> {
+    fn drop(&mut self) {
+        if let Some(reader) = &mut self.reader {
+            if !self.buffer.is_empty() {
+                let _: io::Result<()> = reader.get_mut().write_all(&self.buffer);
+            }
+        }
+    }
+}
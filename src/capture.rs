@@ -0,0 +1,402 @@
+//! Recording and replaying gutter traffic.
+//!
+//! A capture starts with a header holding the time it started and the
+//! [fingerprints](crate::schema::fingerprint) of the logs it is expected
+//! to contain. It is followed by frames, each holding the bytes that
+//! went through a gutter in one direction at once, along with the time
+//! they did and the id of the peer on the other end. Unlike raw byte
+//! dumps, captures of several peers can be merged and inspected later
+//! without losing track of who said what.
+//!
+//! A [`RecordingGutter`] appends the traffic of the gutter it wraps to a
+//! [`CaptureWriter`], and a [`CaptureReader`] reads the frames back,
+//! either to inspect or to [replay](CaptureReader::replay) them.
+//!
+//! # Format
+//!
+//! All integers are little-endian, so that captures can be read on any
+//! machine. The header is the magic `GUTCAP`, a `u16` version (1), the
+//! start time as a `u64` of nanoseconds since the Unix epoch, a `u32`
+//! count of fingerprints, and the `u64` fingerprints. Each frame is a
+//! direction byte (0 for sent, 1 for received), a `u32` peer id, the
+//! time as a `u64` of nanoseconds since the start, a `u32` length, and
+//! the bytes themselves. The logs within the frames are left as they
+//! went through the gutter.
+
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::DEFAULT_MAX_FRAME_LEN;
+
+const MAGIC: &[u8; 6] = b"GUTCAP";
+const VERSION: u16 = 1;
+
+/// Direction of the bytes of a [`Frame`], from the point of view of the
+/// recording end.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Direction {
+    /// Bytes written to the gutter.
+    Sent,
+    /// Bytes read from the gutter.
+    Received,
+}
+
+/// Header of a capture.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    /// Time the capture started.
+    pub started: SystemTime,
+    /// Fingerprints of the logs the capture is expected to contain.
+    pub schemas: Vec<u64>,
+}
+
+/// Bytes that went through a gutter in one direction at once.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    /// Direction of the bytes.
+    pub direction: Direction,
+    /// Id of the peer on the other end of the gutter.
+    pub peer: u32,
+    /// Time since the capture started.
+    pub timestamp: Duration,
+    /// The bytes themselves.
+    pub bytes: Vec<u8>,
+}
+
+/// Writing end of a capture.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```
+/// use gutters::capture::{CaptureReader, CaptureWriter, Direction};
+/// use gutters::schema::fingerprint;
+///
+/// let mut writer = CaptureWriter::new(Vec::new(), &[fingerprint::<f64>()])?;
+/// writer.write_frame(Direction::Sent, 1, &64.0f64.to_ne_bytes())?;
+/// let capture = writer.into_inner();
+///
+/// let mut reader = CaptureReader::new(&capture[..])?;
+/// assert_eq!(reader.header().schemas, [fingerprint::<f64>()]);
+/// let frame = reader.read_frame()?.unwrap();
+/// assert_eq!((frame.direction, frame.peer), (Direction::Sent, 1));
+/// assert_eq!(frame.bytes, 64.0f64.to_ne_bytes());
+/// assert!(reader.read_frame()?.is_none());
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Debug)]
+pub struct CaptureWriter<W> {
+    writer: W,
+    started: Instant,
+}
+
+impl<W: Write> CaptureWriter<W> {
+    /// Start a capture expected to contain logs with the given
+    /// fingerprints, writing its header to `writer`.
+    pub fn new(mut writer: W, schemas: &[u64]) -> Result<Self> {
+        let started = Instant::now();
+        let since_epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let count = u32::try_from(schemas.len())
+            .map_err(|_| Error::new(ErrorKind::InvalidInput, "too many schemas"))?;
+
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        writer.write_all(&nanos(since_epoch).to_le_bytes())?;
+        writer.write_all(&count.to_le_bytes())?;
+        for schema in schemas {
+            writer.write_all(&schema.to_le_bytes())?;
+        }
+        Ok(CaptureWriter { writer, started })
+    }
+
+    /// Append a frame of `bytes` exchanged with `peer`, timestamped
+    /// now.
+    ///
+    /// This function fails with [`ErrorKind::InvalidInput`] if `bytes`
+    /// is longer than [`DEFAULT_MAX_FRAME_LEN`].
+    pub fn write_frame(&mut self, direction: Direction, peer: u32, bytes: &[u8]) -> Result<()> {
+        self.write_frame_at(direction, peer, Instant::now(), bytes)
+    }
+
+    /// Append a frame of `bytes` exchanged with `peer` at `instant`.
+    pub(crate) fn write_frame_at(
+        &mut self,
+        direction: Direction,
+        peer: u32,
+        instant: Instant,
+        bytes: &[u8],
+    ) -> Result<()> {
+        if bytes.len() > DEFAULT_MAX_FRAME_LEN {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "frame is too long for a capture",
+            ));
+        }
+        let timestamp = nanos(instant.saturating_duration_since(self.started));
+        let direction = match direction {
+            Direction::Sent => 0u8,
+            Direction::Received => 1,
+        };
+
+        self.writer.write_all(&[direction])?;
+        self.writer.write_all(&peer.to_le_bytes())?;
+        self.writer.write_all(&timestamp.to_le_bytes())?;
+        self.writer.write_all(&(bytes.len() as u32).to_le_bytes())?;
+        self.writer.write_all(bytes)
+    }
+
+    /// Flush the underlying writer.
+    pub fn flush(&mut self) -> Result<()> {
+        self.writer.flush()
+    }
+
+    /// Move the underlying writer out.
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+/// Reading end of a capture.
+///
+/// See [`CaptureWriter`].
+#[derive(Debug)]
+pub struct CaptureReader<R> {
+    reader: R,
+    header: Header,
+}
+
+impl<R: Read> CaptureReader<R> {
+    /// Read the header of the capture from `reader`.
+    ///
+    /// This function fails with [`ErrorKind::InvalidData`] if `reader`
+    /// doesn't hold a capture of a supported version.
+    pub fn new(mut reader: R) -> Result<Self> {
+        let mut magic = [0u8; 6];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(Error::new(ErrorKind::InvalidData, "not a gutters capture"));
+        }
+        if read_u16(&mut reader)? != VERSION {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "unsupported capture version",
+            ));
+        }
+        let started = UNIX_EPOCH + Duration::from_nanos(read_u64(&mut reader)?);
+        let count = read_u32(&mut reader)?;
+        let schemas = (0..count)
+            .map(|_| read_u64(&mut reader))
+            .collect::<Result<_>>()?;
+
+        Ok(CaptureReader {
+            reader,
+            header: Header { started, schemas },
+        })
+    }
+
+    /// Get the header of the capture.
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// Read the next frame, or `None` at the end of the capture.
+    ///
+    /// This function fails with [`ErrorKind::UnexpectedEof`] if the
+    /// capture ends in the middle of a frame, and with
+    /// [`ErrorKind::InvalidData`] if the frame is malformed.
+    pub fn read_frame(&mut self) -> Result<Option<Frame>> {
+        let mut direction = [0u8];
+        loop {
+            match self.reader.read(&mut direction) {
+                Ok(0) => return Ok(None),
+                Ok(_) => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        let direction = match direction[0] {
+            0 => Direction::Sent,
+            1 => Direction::Received,
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "invalid frame direction",
+                ))
+            }
+        };
+        let peer = read_u32(&mut self.reader)?;
+        let timestamp = Duration::from_nanos(read_u64(&mut self.reader)?);
+        let len = read_u32(&mut self.reader)? as usize;
+        if len > DEFAULT_MAX_FRAME_LEN {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "frame is too long for a capture",
+            ));
+        }
+        let mut bytes = vec![0u8; len];
+        self.reader.read_exact(&mut bytes)?;
+
+        Ok(Some(Frame {
+            direction,
+            peer,
+            timestamp,
+            bytes,
+        }))
+    }
+
+    /// Write the bytes of the remaining frames going in `direction` to
+    /// `gutter`, and return the number of such frames.
+    ///
+    /// This function is blocking.
+    ///
+    /// Frames are written as fast as possible, regardless of their
+    /// timestamps.
+    pub fn replay<G: Write>(&mut self, gutter: &mut G, direction: Direction) -> Result<usize> {
+        let mut count = 0;
+        while let Some(frame) = self.read_frame()? {
+            if frame.direction == direction {
+                gutter.write_all(&frame.bytes)?;
+                count += 1;
+            }
+        }
+        gutter.flush()?;
+        Ok(count)
+    }
+
+    /// Move the underlying reader out.
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+/// A gutter recording its traffic to a capture.
+///
+/// Several gutters can record to the same capture, each with its own
+/// peer id.
+///
+/// Failing to record never disturbs the traffic itself: the first error
+/// of the capture is kept, and returned by the next
+/// [`flush`](Write::flush). Reads and writes longer than
+/// [`DEFAULT_MAX_FRAME_LEN`] are recorded as several frames.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```
+/// use gutters::capture::{CaptureReader, CaptureWriter, Direction, RecordingGutter};
+/// use gutters::schema::fingerprint;
+/// use gutters::throw;
+/// use std::io::Cursor;
+/// use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+///
+/// let capture = CaptureWriter::new(Vec::new(), &[fingerprint::<u32>()])?;
+/// let capture = Arc::new(Mutex::new(capture));
+///
+/// let mut gutter = RecordingGutter::new(Cursor::new(Vec::new()), capture.clone(), 7);
+/// throw(&mut gutter, &42u32)?;
+/// drop(gutter);
+///
+/// let capture = Arc::into_inner(capture).unwrap().into_inner().unwrap().into_inner();
+/// let mut reader = CaptureReader::new(&capture[..])?;
+/// let mut replayed = Vec::new();
+/// assert_eq!(reader.replay(&mut replayed, Direction::Sent)?, 1);
+/// assert_eq!(replayed, 42u32.to_ne_bytes());
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Debug)]
+pub struct RecordingGutter<G, W> {
+    gutter: G,
+    capture: Arc<Mutex<CaptureWriter<W>>>,
+    peer: u32,
+    error: Option<Error>,
+}
+
+impl<G, W: Write> RecordingGutter<G, W> {
+    /// Wrap `gutter`, recording its traffic with `peer` to `capture`.
+    pub fn new(gutter: G, capture: Arc<Mutex<CaptureWriter<W>>>, peer: u32) -> Self {
+        RecordingGutter {
+            gutter,
+            capture,
+            peer,
+            error: None,
+        }
+    }
+
+    fn record(&mut self, direction: Direction, bytes: &[u8]) {
+        if self.error.is_some() {
+            return;
+        }
+        let mut capture = self.capture.lock().unwrap_or_else(PoisonError::into_inner);
+        for chunk in bytes.chunks(DEFAULT_MAX_FRAME_LEN) {
+            if let Err(e) = capture.write_frame(direction, self.peer, chunk) {
+                self.error = Some(e);
+                return;
+            }
+        }
+    }
+
+    fn capture(&self) -> MutexGuard<'_, CaptureWriter<W>> {
+        self.capture.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Get a reference to the underlying gutter.
+    pub fn get_ref(&self) -> &G {
+        &self.gutter
+    }
+
+    /// Move the underlying gutter out.
+    pub fn into_inner(self) -> G {
+        self.gutter
+    }
+}
+
+impl<G: Read, W: Write> Read for RecordingGutter<G, W> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let len = self.gutter.read(buf)?;
+        self.record(Direction::Received, &buf[..len]);
+        Ok(len)
+    }
+}
+
+impl<G: Write, W: Write> Write for RecordingGutter<G, W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let len = self.gutter.write(buf)?;
+        self.record(Direction::Sent, &buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.gutter.flush()?;
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        self.capture().flush()
+    }
+}
+
+fn nanos(duration: Duration) -> u64 {
+    u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX)
+}
+
+fn read_u16<R: Read>(reader: &mut R) -> Result<u16> {
+    let mut bytes = [0u8; 2];
+    reader.read_exact(&mut bytes)?;
+    Ok(u16::from_le_bytes(bytes))
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> Result<u32> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> Result<u64> {
+    let mut bytes = [0u8; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
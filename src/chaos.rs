@@ -0,0 +1,279 @@
+//! Fault injection for soak tests.
+//!
+//! A [`ChaosGutter`] wraps a gutter, and injects the faults of a
+//! [`Scenario`] into the reads and writes going through it: lost
+//! connections, corrupted bytes, duplicated writes and stalls. Faults
+//! are either scheduled at a given operation, or drawn at random from a
+//! seed, so that failing runs can be replayed. This helps checking that
+//! reliability layers, such as [checksums](crate::checksum) and
+//! [deduplication](crate::dedup), hold up together.
+
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::thread;
+use std::time::Duration;
+
+use crate::Rng;
+
+/// A fault injected by a [`ChaosGutter`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// Fail this and every later operation with
+    /// [`ErrorKind::ConnectionReset`].
+    Disconnect,
+    /// Flip a random bit of the bytes read or written.
+    Corrupt,
+    /// Write the bytes twice. This has no effect on reads.
+    Duplicate,
+    /// Sleep for the given duration before the operation.
+    Stall(Duration),
+}
+
+/// Faults to inject, for a [`ChaosGutter`].
+///
+/// Operations are the calls to [`Read::read`] and [`Write::write`] of the
+/// gutter, counted together from 0.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Scenario {
+    seed: u64,
+    disconnect: f64,
+    corrupt: f64,
+    duplicate: f64,
+    stall: (f64, Duration),
+    script: Vec<(u64, Fault)>,
+}
+
+impl Scenario {
+    /// Create a scenario with no faults.
+    pub fn new() -> Self {
+        Scenario {
+            seed: 0x2545_f491_4f6c_dd1d,
+            disconnect: 0.0,
+            corrupt: 0.0,
+            duplicate: 0.0,
+            stall: (0.0, Duration::ZERO),
+            script: Vec::new(),
+        }
+    }
+
+    /// Seed the random faults, for reproducible runs.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Disconnect at each operation with the given `probability`.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `probability` is not between 0 and 1.
+    pub fn disconnect(mut self, probability: f64) -> Self {
+        self.disconnect = checked(probability);
+        self
+    }
+
+    /// Corrupt each operation with the given `probability`.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `probability` is not between 0 and 1.
+    pub fn corrupt(mut self, probability: f64) -> Self {
+        self.corrupt = checked(probability);
+        self
+    }
+
+    /// Duplicate each write with the given `probability`.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `probability` is not between 0 and 1.
+    pub fn duplicate(mut self, probability: f64) -> Self {
+        self.duplicate = checked(probability);
+        self
+    }
+
+    /// Stall each operation for `duration` with the given `probability`.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `probability` is not between 0 and 1.
+    pub fn stall(mut self, probability: f64, duration: Duration) -> Self {
+        self.stall = (checked(probability), duration);
+        self
+    }
+
+    /// Inject `fault` at the given `operation`, besides random faults.
+    pub fn at(mut self, operation: u64, fault: Fault) -> Self {
+        self.script.push((operation, fault));
+        self
+    }
+}
+
+impl Default for Scenario {
+    fn default() -> Self {
+        Scenario::new()
+    }
+}
+
+fn checked(probability: f64) -> f64 {
+    assert!(
+        (0.0..=1.0).contains(&probability),
+        "fault probability is not between 0 and 1"
+    );
+    probability
+}
+
+/// A gutter injecting faults into another.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```
+/// use gutters::chaos::{ChaosGutter, Fault, Scenario};
+/// use gutters::checksum::{self, ChecksumMismatch};
+/// use std::io::Cursor;
+///
+/// let scenario = Scenario::new().at(1, Fault::Corrupt);
+/// let mut gutter = ChaosGutter::new(Cursor::new(Vec::new()), scenario);
+/// checksum::throw(&mut gutter, &64.0f64)?;
+/// assert_eq!(gutter.injected(), [(1, Fault::Corrupt)]);
+///
+/// let mut gutter = gutter.into_inner();
+/// gutter.set_position(0);
+/// let mut data = 0.0f64;
+/// let error = checksum::pick_up(&mut gutter, &mut data).unwrap_err();
+/// assert!(error.get_ref().unwrap().is::<ChecksumMismatch>());
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Debug)]
+pub struct ChaosGutter<G> {
+    gutter: G,
+    scenario: Scenario,
+    rng: Rng,
+    operations: u64,
+    disconnected: bool,
+    injected: Vec<(u64, Fault)>,
+}
+
+impl<G> ChaosGutter<G> {
+    /// Wrap `gutter`, injecting the faults of `scenario`.
+    pub fn new(gutter: G, scenario: Scenario) -> Self {
+        ChaosGutter {
+            gutter,
+            rng: Rng::new(scenario.seed),
+            scenario,
+            operations: 0,
+            disconnected: false,
+            injected: Vec::new(),
+        }
+    }
+
+    /// Return the faults injected so far, along with their operation.
+    pub fn injected(&self) -> &[(u64, Fault)] {
+        &self.injected
+    }
+
+    /// Move the gutter out.
+    pub fn into_inner(self) -> G {
+        self.gutter
+    }
+
+    /// Draw the faults of the next operation, and apply disconnections
+    /// and stalls.
+    fn next_faults(&mut self) -> Result<(bool, bool)> {
+        let operation = self.operations;
+        self.operations += 1;
+
+        let scenario = &self.scenario;
+        let rng = &mut self.rng;
+        let mut faults: Vec<Fault> = scenario
+            .script
+            .iter()
+            .filter(|(at, _)| *at == operation)
+            .map(|&(_, fault)| fault)
+            .collect();
+        // Draw every number whatever the outcome, so that a run only
+        // depends on the seed and the sequence of operations.
+        let draws = [
+            rng.next_f64(),
+            rng.next_f64(),
+            rng.next_f64(),
+            rng.next_f64(),
+        ];
+        if draws[0] < scenario.disconnect {
+            faults.push(Fault::Disconnect);
+        }
+        if draws[1] < scenario.stall.0 {
+            faults.push(Fault::Stall(scenario.stall.1));
+        }
+        if draws[2] < scenario.corrupt {
+            faults.push(Fault::Corrupt);
+        }
+        if draws[3] < scenario.duplicate {
+            faults.push(Fault::Duplicate);
+        }
+
+        let (mut corrupt, mut duplicate) = (false, false);
+        for fault in faults {
+            if !self.disconnected {
+                self.injected.push((operation, fault));
+            }
+            match fault {
+                Fault::Disconnect => self.disconnected = true,
+                Fault::Corrupt => corrupt = true,
+                Fault::Duplicate => duplicate = true,
+                Fault::Stall(duration) => thread::sleep(duration),
+            }
+        }
+        if self.disconnected {
+            return Err(Error::new(
+                ErrorKind::ConnectionReset,
+                "chaos disconnected the gutter",
+            ));
+        }
+        Ok((corrupt, duplicate))
+    }
+
+    fn flip_bit(&mut self, bytes: &mut [u8]) {
+        if !bytes.is_empty() {
+            let bit = self.rng.next_u64() % (bytes.len() as u64 * 8);
+            bytes[(bit / 8) as usize] ^= 1 << (bit % 8);
+        }
+    }
+}
+
+impl<G: Read> Read for ChaosGutter<G> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let (corrupt, _) = self.next_faults()?;
+        let n = self.gutter.read(buf)?;
+        if corrupt {
+            self.flip_bit(&mut buf[..n]);
+        }
+        Ok(n)
+    }
+}
+
+impl<G: Write> Write for ChaosGutter<G> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let (corrupt, duplicate) = self.next_faults()?;
+        let mut bytes = buf.to_vec();
+        if corrupt {
+            self.flip_bit(&mut bytes);
+        }
+        self.gutter.write_all(&bytes)?;
+        if duplicate {
+            self.gutter.write_all(&bytes)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if self.disconnected {
+            return Err(Error::new(
+                ErrorKind::ConnectionReset,
+                "chaos disconnected the gutter",
+            ));
+        }
+        self.gutter.flush()
+    }
+}
@@ -0,0 +1,244 @@
+//! Checksums over raw bytes.
+//!
+//! [`crc32c`] computes the CRC-32C (Castagnoli) of a buffer. On x86_64
+//! processors supporting SSE4.2, the dedicated `crc32` instruction is
+//! used, which is detected at runtime. Other targets fall back to a
+//! table-driven implementation.
+//!
+//! [`throw`] and [`pick_up`] append it to each log, so that corrupted
+//! logs are detected on receipt rather than picked up as garbage.
+
+use std::error;
+use std::fmt;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+
+use crate::{as_u8_slice, as_u8_slice_mut, read_exact_or_closed, Log};
+
+/// Send a message of type `T` down the `gutter`, followed by its
+/// CRC-32C.
+///
+/// This function is blocking.
+///
+/// This function doesn't change endianness, so it must be the
+/// same between the peers.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use gutters::checksum;
+/// use std::net::TcpStream;
+/// let mut stream = TcpStream::connect("127.0.0.1:34567")?;
+///
+/// checksum::throw(&mut stream, &64.0)?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn throw<G: Write, T: Log>(gutter: &mut G, buffer: &T) -> Result<()> {
+    let bytes = as_u8_slice(buffer);
+    gutter.write_all(bytes)?;
+    gutter.write_all(&crc32c(bytes).to_ne_bytes())
+}
+
+/// Read a message of type `T` from the `gutter`, and check its CRC-32C.
+///
+/// This function is blocking.
+///
+/// This function fails with [`ErrorKind::InvalidData`] if the checksum
+/// doesn't match, in which case the content of `buffer` must not be
+/// used. The inner error is then a [`ChecksumMismatch`].
+///
+/// This function fails with a [`Closed`](crate::Closed) error if the
+/// peer closed the gutter before sending the message.
+///
+/// This function doesn't change endianness, so it must be the
+/// same between the peers.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```
+/// use gutters::checksum::{self, ChecksumMismatch};
+/// use gutters::Closed;
+/// use std::io::Cursor;
+///
+/// let mut gutter = Cursor::new(Vec::new());
+/// checksum::throw(&mut gutter, &64.0f64)?;
+///
+/// gutter.set_position(0);
+/// let mut data = 0.0f64;
+/// checksum::pick_up(&mut gutter, &mut data)?;
+/// assert_eq!(data, 64.0);
+/// assert!(Closed::is(&checksum::pick_up(&mut gutter, &mut data).unwrap_err()));
+///
+/// // Flip a bit.
+/// gutter.get_mut()[0] ^= 1;
+/// gutter.set_position(0);
+/// let error = checksum::pick_up(&mut gutter, &mut data).unwrap_err();
+/// assert!(error.get_ref().unwrap().is::<ChecksumMismatch>());
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn pick_up<G: Read, T: Log>(gutter: &mut G, buffer: &mut T) -> Result<()> {
+    let bytes = as_u8_slice_mut(buffer);
+    read_exact_or_closed(gutter, bytes)?;
+    let mut expected = [0u8; 4];
+    gutter.read_exact(&mut expected)?;
+    let expected = u32::from_ne_bytes(expected);
+    let actual = crc32c(bytes);
+    if actual != expected {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            ChecksumMismatch { expected, actual },
+        ));
+    }
+    Ok(())
+}
+
+/// Error of a log whose checksum doesn't match its content.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChecksumMismatch {
+    /// Checksum received along the log.
+    pub expected: u32,
+    /// Checksum of the log as received.
+    pub actual: u32,
+}
+
+impl fmt::Display for ChecksumMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "log checksum mismatch: expected {:#010x}, got {:#010x}",
+            self.expected, self.actual
+        )
+    }
+}
+
+impl error::Error for ChecksumMismatch {}
+
+/// Compute the CRC-32C of `data`.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```
+/// use gutters::checksum::crc32c;
+///
+/// assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+/// ```
+pub fn crc32c(data: &[u8]) -> u32 {
+    crc32c_append(0, data)
+}
+
+/// Extend the CRC-32C `crc` of some previous bytes with `data`.
+///
+/// This allows computing the checksum of a message produced in several
+/// segments.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```
+/// use gutters::checksum::{crc32c, crc32c_append};
+///
+/// let crc = crc32c(b"1234");
+/// assert_eq!(crc32c_append(crc, b"56789"), crc32c(b"123456789"));
+/// ```
+pub fn crc32c_append(crc: u32, data: &[u8]) -> u32 {
+    #[cfg(target_arch = "x86_64")]
+    {
+        if std::is_x86_feature_detected!("sse4.2") {
+            // SAFETY: the required target feature was just detected.
+            return !unsafe { crc32c_sse42(!crc, data) };
+        }
+    }
+    !crc32c_table(!crc, data)
+}
+
+const POLYNOMIAL: u32 = 0x82f6_3b78;
+
+const TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ POLYNOMIAL
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+fn crc32c_table(mut crc: u32, data: &[u8]) -> u32 {
+    for &byte in data {
+        crc = TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "sse4.2")]
+unsafe fn crc32c_sse42(crc: u32, data: &[u8]) -> u32 {
+    use std::arch::x86_64::{_mm_crc32_u64, _mm_crc32_u8};
+
+    let mut crc = crc as u64;
+    let mut words = data.chunks_exact(8);
+    for word in &mut words {
+        crc = _mm_crc32_u64(crc, u64::from_le_bytes(word.try_into().unwrap()));
+    }
+    let mut crc = crc as u32;
+    for &byte in words.remainder() {
+        crc = _mm_crc32_u8(crc, byte);
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_value() {
+        assert_eq!(!crc32c_table(!0, b"123456789"), 0xe306_9283);
+        assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+    }
+
+    // Doctests only go through whichever implementation the machine
+    // dispatches to, so both are compared here.
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn sse42_matches_table() {
+        if !std::is_x86_feature_detected!("sse4.2") {
+            return;
+        }
+        let data: Vec<u8> = (0..128u32).map(|i| (i * 167 + 13) as u8).collect();
+        for offset in 0..8 {
+            for len in 0..=64 {
+                let data = &data[offset..offset + len];
+                for crc in [0, 0xe306_9283, u32::MAX] {
+                    // SAFETY: the required target feature was just detected.
+                    let sse42 = unsafe { crc32c_sse42(crc, data) };
+                    assert_eq!(
+                        sse42,
+                        crc32c_table(crc, data),
+                        "{} bytes at {}",
+                        len,
+                        offset
+                    );
+                }
+            }
+        }
+        // SAFETY: the required target feature was just detected.
+        assert_eq!(!unsafe { crc32c_sse42(!0, b"123456789") }, 0xe306_9283);
+    }
+}
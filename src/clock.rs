@@ -0,0 +1,96 @@
+//! Time sources.
+//!
+//! Time-dependent features, such as the [time to live](crate::outbox::Builder::ttl)
+//! of outbox logs, read the time from a [`Clock`], and features waiting
+//! for some time, such as the [backoff](crate::resilient::ResilientGutter::backoff)
+//! of reconnections, [sleep](Clock::sleep) on it. They use the
+//! [`SystemClock`] by default, but a [`ManualClock`] can be swapped in so
+//! that tests of expiry and backoff don't have to sleep.
+//!
+//! Read timeouts of sockets, as used by the [timeout](crate::timeout)
+//! module, are enforced by the operating system and always follow the
+//! system clock.
+
+use std::fmt;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A source of the current time.
+pub trait Clock: Send + Sync + fmt::Debug {
+    /// Return the current instant.
+    fn now(&self) -> Instant;
+
+    /// Block the current thread until `duration` has elapsed on the
+    /// clock.
+    ///
+    /// The default implementation puts the thread to sleep.
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
+
+/// The monotonic clock of the system, as given by [`Instant::now`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock that only moves when told to.
+///
+/// Clones of a `ManualClock` share the same time, so a test can keep one
+/// to [`advance`](ManualClock::advance) while another is in use.
+/// [Sleeping](Clock::sleep) on it advances it by the duration of the
+/// sleep, and returns immediately.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```
+/// use gutters::clock::{Clock, ManualClock};
+/// use std::time::Duration;
+///
+/// let clock = ManualClock::new();
+/// let start = clock.now();
+/// clock.advance(Duration::from_secs(60));
+/// assert_eq!(clock.now() - start, Duration::from_secs(60));
+/// ```
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    now: Arc<Mutex<Instant>>,
+}
+
+impl ManualClock {
+    /// Create a clock stopped at the current instant.
+    pub fn new() -> Self {
+        ManualClock {
+            now: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    /// Move the clock forward by `duration`.
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap_or_else(PoisonError::into_inner) += duration;
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        ManualClock::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration);
+    }
+}
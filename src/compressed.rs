@@ -0,0 +1,342 @@
+//! Compression of large framed logs.
+//!
+//! A [`CompressedGutter`] compresses each frame at least as long as a
+//! threshold before throwing it, and decompresses it on pick-up. Frames
+//! that are short, or that compression doesn't make any shorter, are
+//! thrown as they are, so small logs cost a single byte more.
+//!
+//! The compression algorithm is a [`Codec`], which wraps any compression
+//! library, e.g. `flate2` or `zstd`. Both peers
+//! [negotiate](CompressedGutter::negotiate) which one to use out of the
+//! codecs each of them supports, and fall back to no compression when
+//! they have none in common.
+//!
+//! On the wire, each frame starts as usual with its length prefix,
+//! followed by a flag byte: 0 for a frame thrown as is, 1 for a
+//! compressed one, in which case the `u32` length of the decompressed
+//! payload comes next.
+
+use std::fmt;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::mem::size_of;
+
+use crate::{
+    pick_up_frame_len, pick_up_framed, slice_as_u8_slice, slice_as_u8_slice_mut, throw_framed, Log,
+    DEFAULT_MAX_FRAME_LEN, HEARTBEAT,
+};
+
+const RAW: u8 = 0;
+const COMPRESSED: u8 = 1;
+
+/// A compression algorithm.
+///
+/// # Examples
+///
+/// Basic usage, with a run-length encoding standing in for a real
+/// compression library:
+///
+/// ```
+/// use gutters::compressed::Codec;
+/// use std::io::{Error, ErrorKind, Result};
+///
+/// #[derive(Debug)]
+/// pub struct RunLength;
+///
+/// impl Codec for RunLength {
+///     fn name(&self) -> &str {
+///         "rle"
+///     }
+///
+///     fn compress(&mut self, input: &[u8], output: &mut Vec<u8>) -> Result<()> {
+///         for run in input.chunk_by(|a, b| a == b) {
+///             for chunk in run.chunks(255) {
+///                 output.extend_from_slice(&[chunk.len() as u8, chunk[0]]);
+///             }
+///         }
+///         Ok(())
+///     }
+///
+///     fn decompress(&mut self, input: &[u8], max_len: usize, output: &mut Vec<u8>) -> Result<()> {
+///         for pair in input.chunks(2) {
+///             let [count, byte] = pair else {
+///                 return Err(Error::new(ErrorKind::InvalidData, "truncated run"));
+///             };
+///             if output.len() + *count as usize > max_len {
+///                 return Err(Error::new(ErrorKind::InvalidData, "too many runs"));
+///             }
+///             output.extend(std::iter::repeat_n(*byte, *count as usize));
+///         }
+///         Ok(())
+///     }
+/// }
+/// ```
+pub trait Codec: Send + fmt::Debug {
+    /// Return the name identifying the algorithm during negotiation.
+    ///
+    /// Names must not contain newlines.
+    fn name(&self) -> &str;
+
+    /// Append the compressed `input` to `output`.
+    fn compress(&mut self, input: &[u8], output: &mut Vec<u8>) -> Result<()>;
+
+    /// Append the decompressed `input` to the empty `output`.
+    ///
+    /// `max_len` is the decompressed length announced by the peer, for
+    /// which `output` has room. Implementations must fail rather than
+    /// decompress more than `max_len` bytes, so that a small malicious
+    /// frame can't exhaust memory. A result of another length is
+    /// rejected.
+    fn decompress(&mut self, input: &[u8], max_len: usize, output: &mut Vec<u8>) -> Result<()>;
+}
+
+/// A gutter compressing large frames.
+///
+/// # Examples
+///
+/// Basic usage, with the `RunLength` codec of [`Codec`]:
+///
+/// ```
+/// # use gutters::compressed::Codec;
+/// # use std::io::{Error, ErrorKind, Result};
+/// # #[derive(Debug)]
+/// # pub struct RunLength;
+/// # impl Codec for RunLength {
+/// #     fn name(&self) -> &str { "rle" }
+/// #     fn compress(&mut self, input: &[u8], output: &mut Vec<u8>) -> Result<()> {
+/// #         for run in input.chunk_by(|a, b| a == b) {
+/// #             for chunk in run.chunks(255) { output.extend_from_slice(&[chunk.len() as u8, chunk[0]]); }
+/// #         }
+/// #         Ok(())
+/// #     }
+/// #     fn decompress(&mut self, input: &[u8], max_len: usize, output: &mut Vec<u8>) -> Result<()> {
+/// #         for pair in input.chunks(2) {
+/// #             let [count, byte] = pair else { return Err(Error::new(ErrorKind::InvalidData, "truncated run")) };
+/// #             if output.len() + *count as usize > max_len { return Err(Error::new(ErrorKind::InvalidData, "too many runs")) }
+/// #             output.extend(std::iter::repeat_n(*byte, *count as usize));
+/// #         }
+/// #         Ok(())
+/// #     }
+/// # }
+/// use gutters::compressed::CompressedGutter;
+/// use std::net::{TcpListener, TcpStream};
+/// use std::thread;
+///
+/// let listener = TcpListener::bind("127.0.0.1:0")?;
+/// let stream = TcpStream::connect(listener.local_addr()?)?;
+/// let server = thread::spawn(move || -> std::io::Result<Vec<f32>> {
+///     let mut gutter = CompressedGutter::negotiate(listener.accept()?.0, vec![Box::new(RunLength)], 1024)?;
+///     gutter.pick_up_vec()
+/// });
+///
+/// let mut gutter = CompressedGutter::negotiate(stream, vec![Box::new(RunLength)], 1024)?;
+/// assert_eq!(gutter.codec(), Some("rle"));
+/// let samples = vec![0.0f32; 1_000_000];
+/// gutter.throw_framed(&samples)?;
+/// assert_eq!(server.join().unwrap()?, samples);
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Debug)]
+pub struct CompressedGutter<G> {
+    gutter: G,
+    codec: Option<Box<dyn Codec>>,
+    threshold: usize,
+    max_len: usize,
+    buffer: Vec<u8>,
+}
+
+impl<G: Read + Write> CompressedGutter<G> {
+    /// Agree with the peer on a codec out of `codecs`, listed in order of
+    /// preference, and compress frames of at least `threshold` bytes
+    /// with it.
+    ///
+    /// This function is blocking.
+    ///
+    /// Both peers pick the codec they both support with the best
+    /// combined rank in their lists, so they always agree. Without any
+    /// codec in common, frames are never compressed.
+    pub fn negotiate(mut gutter: G, codecs: Vec<Box<dyn Codec>>, threshold: usize) -> Result<Self> {
+        let names: Vec<&str> = codecs.iter().map(|codec| codec.name()).collect();
+        throw_framed(&mut gutter, names.join("\n").as_bytes())?;
+        gutter.flush()?;
+        let theirs = String::from_utf8(pick_up_framed(&mut gutter)?)
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "codec names are not UTF-8"))?;
+        let theirs: Vec<&str> = theirs.split('\n').collect();
+
+        let chosen = names
+            .iter()
+            .enumerate()
+            .filter_map(|(rank, name)| {
+                let their_rank = theirs.iter().position(|theirs| theirs == name)?;
+                Some((rank + their_rank, *name))
+            })
+            .min()
+            .map(|(_, name)| name.to_owned());
+        let codec =
+            chosen.and_then(|chosen| codecs.into_iter().find(|codec| codec.name() == chosen));
+
+        Ok(CompressedGutter {
+            gutter,
+            codec,
+            threshold,
+            max_len: DEFAULT_MAX_FRAME_LEN,
+            buffer: Vec::new(),
+        })
+    }
+
+    /// Return the name of the codec agreed upon, if any.
+    pub fn codec(&self) -> Option<&str> {
+        self.codec.as_ref().map(|codec| codec.name())
+    }
+
+    /// Reject frames whose decompressed payload is longer than `max_len`
+    /// bytes, rather than [`DEFAULT_MAX_FRAME_LEN`].
+    pub fn set_max_len(&mut self, max_len: usize) {
+        self.max_len = max_len;
+    }
+
+    /// Send variable-sized messages of type `T`, compressed if they are
+    /// long enough.
+    ///
+    /// This function is blocking.
+    ///
+    /// This function doesn't change endianness, so it must be the
+    /// same between the peers.
+    pub fn throw_framed<T: Log>(&mut self, payload: &[T]) -> Result<()> {
+        let payload = slice_as_u8_slice(payload);
+        if let Some(codec) = self
+            .codec
+            .as_mut()
+            .filter(|_| payload.len() >= self.threshold)
+        {
+            self.buffer.clear();
+            codec.compress(payload, &mut self.buffer)?;
+            if self.buffer.len() + size_of::<u32>() < payload.len() {
+                let original = u32::try_from(payload.len())
+                    .map_err(|_| Error::new(ErrorKind::InvalidInput, "frame is too long"))?;
+                let compressed = std::mem::take(&mut self.buffer);
+                let result = write_frame(
+                    &mut self.gutter,
+                    &[&[COMPRESSED], &original.to_ne_bytes(), &compressed],
+                );
+                self.buffer = compressed;
+                return result;
+            }
+        }
+        write_frame(&mut self.gutter, &[&[RAW], payload])
+    }
+
+    /// Read variable-sized messages of type `T` thrown by
+    /// [`throw_framed`](CompressedGutter::throw_framed), decompressing
+    /// them if needed.
+    ///
+    /// This function is blocking.
+    ///
+    /// This function fails with [`ErrorKind::InvalidData`] if the frame
+    /// is malformed, too long, or if its length is not a multiple of the
+    /// size of `T`.
+    ///
+    /// This function doesn't change endianness, so it must be the
+    /// same between the peers.
+    pub fn pick_up_vec<T: Log>(&mut self) -> Result<Vec<T>> {
+        let bytes = self.pick_up_framed()?;
+        let size = size_of::<T>();
+        if !bytes.is_empty() && (size == 0 || !bytes.len().is_multiple_of(size)) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "frame of {} bytes doesn't hold logs of {} bytes",
+                    bytes.len(),
+                    size
+                ),
+            ));
+        }
+        let count = if bytes.is_empty() {
+            0
+        } else {
+            bytes.len() / size
+        };
+        // SAFETY: logs are valid for any bytes, including zeroes.
+        let mut logs: Vec<T> = (0..count).map(|_| unsafe { std::mem::zeroed() }).collect();
+        slice_as_u8_slice_mut(&mut logs).copy_from_slice(&bytes);
+        Ok(logs)
+    }
+
+    /// Read a variable-sized payload thrown by
+    /// [`throw_framed`](CompressedGutter::throw_framed), decompressing it
+    /// if needed.
+    ///
+    /// This function is blocking.
+    ///
+    /// This function fails with [`ErrorKind::InvalidData`] if the frame
+    /// is malformed or too long.
+    pub fn pick_up_framed(&mut self) -> Result<Vec<u8>> {
+        let invalid = |message| Error::new(ErrorKind::InvalidData, message);
+        let len = pick_up_frame_len(&mut self.gutter, self.max_len.saturating_add(1))?;
+        if len == 0 {
+            return Err(invalid("frame without a compression flag"));
+        }
+        let mut flag = 0u8;
+        self.gutter.read_exact(std::slice::from_mut(&mut flag))?;
+        match flag {
+            RAW => read_body(&mut self.gutter, len - 1),
+            COMPRESSED => {
+                let codec = self
+                    .codec
+                    .as_mut()
+                    .ok_or_else(|| invalid("compressed frame without a codec"))?;
+                if len < 1 + size_of::<u32>() {
+                    return Err(invalid("truncated compressed frame"));
+                }
+                let mut original = [0u8; size_of::<u32>()];
+                self.gutter.read_exact(&mut original)?;
+                self.buffer = read_body(&mut self.gutter, len - 1 - size_of::<u32>())?;
+                let original = u32::from_ne_bytes(original) as usize;
+                if original > self.max_len {
+                    return Err(invalid("decompressed frame is too long"));
+                }
+                let mut payload = Vec::with_capacity(original);
+                codec.decompress(&self.buffer, original, &mut payload)?;
+                if payload.len() != original {
+                    return Err(invalid("decompressed frame has the wrong length"));
+                }
+                Ok(payload)
+            }
+            _ => Err(invalid("invalid compression flag")),
+        }
+    }
+
+    /// Get a reference to the underlying gutter.
+    pub fn get_ref(&self) -> &G {
+        &self.gutter
+    }
+
+    /// Move the underlying gutter out.
+    pub fn into_inner(self) -> G {
+        self.gutter
+    }
+}
+
+/// Read the `len` bytes left of a frame, growing the buffer as they
+/// arrive rather than trusting the announced length up front.
+fn read_body<G: Read>(gutter: &mut G, len: usize) -> Result<Vec<u8>> {
+    let mut body = Vec::new();
+    gutter.take(len as u64).read_to_end(&mut body)?;
+    if body.len() != len {
+        return Err(ErrorKind::UnexpectedEof.into());
+    }
+    Ok(body)
+}
+
+/// Write a frame made of `parts`, without copying them together.
+fn write_frame<G: Write>(gutter: &mut G, parts: &[&[u8]]) -> Result<()> {
+    let len = parts.iter().map(|part| part.len()).sum::<usize>();
+    let len = u32::try_from(len)
+        .ok()
+        .filter(|&len| len < HEARTBEAT)
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "frame is too long"))?;
+    gutter.write_all(&len.to_ne_bytes())?;
+    for part in parts {
+        gutter.write_all(part)?;
+    }
+    Ok(())
+}
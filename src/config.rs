@@ -0,0 +1,177 @@
+//! Settings that can be swapped at runtime.
+//!
+//! A [`ConfigHandle`] holds the current settings of a server, e.g. its
+//! limits, timeouts or [keys](crate::signed::Key), and lets any clone of
+//! it [store](ConfigHandle::store) new ones atomically, without
+//! restarting. Readers take a [snapshot](ConfigHandle::load) when they
+//! need one: connections accepted from then on see the new settings
+//! right away, while established ones keep theirs until they choose to
+//! pick the new ones up, through a [`ConfigView`], at a point where it
+//! is safe to do so.
+//!
+//! A [`Drain`](crate::drain::Drain) can also take its
+//! [connection limit](crate::drain::Drain::max_connections_from) from a
+//! handle.
+
+use std::ops::Deref;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, PoisonError, RwLock};
+
+/// A shared, swappable configuration.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```
+/// use gutters::config::ConfigHandle;
+/// use gutters::signed::{self, Key};
+/// use std::io::Cursor;
+///
+/// struct Settings {
+///     key: Key,
+/// }
+///
+/// let config = ConfigHandle::new(Settings { key: Key::new(b"old secret") });
+/// let mut view = config.view();
+/// let mut gutter = Cursor::new(Vec::new());
+/// signed::throw(&mut gutter, &view.key, &1u32)?;
+///
+/// // From an admin command, somewhere else in the process.
+/// config.store(Settings { key: Key::new(b"new secret") });
+///
+/// // The connection picks up the new key between two logs.
+/// view.refresh();
+/// signed::throw(&mut gutter, &view.key, &2u32)?;
+///
+/// gutter.set_position(0);
+/// let mut data = 0u32;
+/// signed::pick_up(&mut gutter, &Key::new(b"old secret"), &mut data)?;
+/// signed::pick_up(&mut gutter, &Key::new(b"new secret"), &mut data)?;
+/// assert_eq!(data, 2);
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Debug)]
+pub struct ConfigHandle<C> {
+    shared: Arc<Shared<C>>,
+}
+
+#[derive(Debug)]
+struct Shared<C> {
+    current: RwLock<Arc<C>>,
+    version: AtomicU64,
+}
+
+impl<C> ConfigHandle<C> {
+    /// Create a handle holding `config`.
+    pub fn new(config: C) -> Self {
+        ConfigHandle {
+            shared: Arc::new(Shared {
+                current: RwLock::new(Arc::new(config)),
+                version: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// Return a snapshot of the current configuration.
+    ///
+    /// The snapshot is unaffected by later changes.
+    pub fn load(&self) -> Arc<C> {
+        let current = self
+            .shared
+            .current
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+        Arc::clone(&current)
+    }
+
+    /// Replace the configuration with `config`, and return the previous
+    /// one.
+    pub fn store(&self, config: C) -> Arc<C> {
+        self.update(|_| config)
+    }
+
+    /// Replace the configuration with the result of `change`, called on
+    /// the current one, and return the previous one.
+    ///
+    /// Concurrent updates are applied one after another, so none of
+    /// them is lost.
+    pub fn update<F: FnOnce(&C) -> C>(&self, change: F) -> Arc<C> {
+        let mut current = self
+            .shared
+            .current
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        let config = Arc::new(change(&current));
+        let previous = std::mem::replace(&mut *current, config);
+        self.shared.version.fetch_add(1, Ordering::Release);
+        previous
+    }
+
+    /// Return the number of times the configuration was replaced.
+    pub fn version(&self) -> u64 {
+        self.shared.version.load(Ordering::Acquire)
+    }
+
+    /// Return a view of the current configuration, which only changes
+    /// when [refreshed](ConfigView::refresh).
+    pub fn view(&self) -> ConfigView<C> {
+        let version = self.version();
+        ConfigView {
+            handle: self.clone(),
+            config: self.load(),
+            version,
+        }
+    }
+}
+
+impl<C> Clone for ConfigHandle<C> {
+    fn clone(&self) -> Self {
+        ConfigHandle {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+}
+
+/// A connection's own copy of a configuration.
+///
+/// A view dereferences to the configuration it was last refreshed with,
+/// so that the settings of a connection don't change under its feet.
+#[derive(Debug)]
+pub struct ConfigView<C> {
+    handle: ConfigHandle<C>,
+    config: Arc<C>,
+    version: u64,
+}
+
+impl<C> ConfigView<C> {
+    /// Pick up the current configuration of the handle, and return
+    /// whether it changed.
+    ///
+    /// This only costs an atomic load when it didn't, so it can be
+    /// called before each log.
+    pub fn refresh(&mut self) -> bool {
+        let version = self.handle.version();
+        if version == self.version {
+            return false;
+        }
+        // The version is read before the configuration, so a change in
+        // between is picked up on the next refresh at worst.
+        self.config = self.handle.load();
+        self.version = version;
+        true
+    }
+
+    /// Return the handle the view was taken from.
+    pub fn handle(&self) -> &ConfigHandle<C> {
+        &self.handle
+    }
+}
+
+impl<C> Deref for ConfigView<C> {
+    type Target = C;
+
+    fn deref(&self) -> &C {
+        &self.config
+    }
+}
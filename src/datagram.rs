@@ -0,0 +1,325 @@
+//! Logs over UDP.
+//!
+//! The functions of the crate root assume a byte stream, and reading a
+//! log across datagram boundaries would silently mangle it. A
+//! [`DatagramGutter`] throws each log in a datagram of its own instead,
+//! and checks that each datagram picked up has the size of the log.
+//!
+//! Datagrams may be lost, duplicated or reordered on the way. A
+//! [sequenced](DatagramGutter::sequenced) gutter numbers the logs it
+//! throws, so that the receiving end can skip duplicates and logs older
+//! than the latest one, and keep count of them. Each sequenced gutter
+//! also draws a random session identifier, sent along with the numbers:
+//! when it changes, the sender restarted, and the receiving end starts
+//! over. Both ends must agree on whether the gutter is sequenced.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{Error, ErrorKind, Result};
+use std::mem::size_of;
+use std::net::UdpSocket;
+use std::time::SystemTime;
+
+use crate::{as_u8_slice, as_u8_slice_mut, Log};
+
+/// Largest log, in bytes, that fits in a single UDP datagram over IPv4.
+pub const MAX_DATAGRAM_LEN: usize = 65507;
+
+/// Number of sequence numbers, below the latest, remembered to tell
+/// duplicates from reordered datagrams.
+const WINDOW: u64 = 64;
+
+/// A gutter throwing each log in its own datagram.
+///
+/// The socket must be [connected](UdpSocket::connect) to the peer.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```
+/// use gutters::datagram::DatagramGutter;
+/// use std::net::UdpSocket;
+///
+/// let left = UdpSocket::bind("127.0.0.1:0")?;
+/// let right = UdpSocket::bind("127.0.0.1:0")?;
+/// left.connect(right.local_addr()?)?;
+/// right.connect(left.local_addr()?)?;
+/// let mut sender = DatagramGutter::sequenced(left);
+/// let mut receiver = DatagramGutter::sequenced(right);
+///
+/// sender.throw(&[1.0f32, 2.0, 3.0])?;
+/// let mut position = [0.0f32; 3];
+/// receiver.pick_up(&mut position)?;
+/// assert_eq!(position, [1.0, 2.0, 3.0]);
+/// assert_eq!(receiver.lost(), 0);
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Debug)]
+pub struct DatagramGutter {
+    socket: UdpSocket,
+    sequenced: bool,
+    session: u64,
+    next: u64,
+    peer_session: Option<u64>,
+    previous_session: Option<u64>,
+    first: u64,
+    latest: Option<u64>,
+    seen: u64,
+    buffer: Vec<u8>,
+    duplicates: u64,
+    reordered: u64,
+    lost: u64,
+    restarts: u64,
+}
+
+impl DatagramGutter {
+    /// Wrap the connected `socket`, throwing logs as they are.
+    pub fn new(socket: UdpSocket) -> Self {
+        Self::with_sequence(socket, false)
+    }
+
+    /// Wrap the connected `socket`, numbering the logs thrown and
+    /// checking the numbers of the logs picked up.
+    ///
+    /// The gutter draws a random session identifier, which tells the
+    /// peer when the sender restarted.
+    pub fn sequenced(socket: UdpSocket) -> Self {
+        let mut gutter = Self::with_sequence(socket, true);
+        // Each `RandomState` is keyed differently, and the time tells
+        // apart processes that happen to draw the same keys.
+        let mut hasher = RandomState::new().build_hasher();
+        if let Ok(now) = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+            hasher.write_u128(now.as_nanos());
+        }
+        hasher.write_u32(std::process::id());
+        gutter.session = hasher.finish();
+        gutter
+    }
+
+    fn with_sequence(socket: UdpSocket, sequenced: bool) -> Self {
+        DatagramGutter {
+            socket,
+            sequenced,
+            session: 0,
+            next: 0,
+            peer_session: None,
+            previous_session: None,
+            first: 0,
+            latest: None,
+            seen: 0,
+            buffer: Vec::new(),
+            duplicates: 0,
+            reordered: 0,
+            lost: 0,
+            restarts: 0,
+        }
+    }
+
+    /// Send a message of type `T` in a datagram of its own.
+    ///
+    /// This function is blocking.
+    ///
+    /// This function fails with [`ErrorKind::InvalidInput`] if the log
+    /// doesn't fit in a datagram, see [`MAX_DATAGRAM_LEN`].
+    ///
+    /// This function doesn't change endianness, so it must be the
+    /// same between the peers.
+    pub fn throw<T: Log>(&mut self, buffer: &T) -> Result<()> {
+        let header = self.header_len();
+        if header + size_of::<T>() > MAX_DATAGRAM_LEN {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "log is too long for a datagram",
+            ));
+        }
+
+        self.buffer.clear();
+        if self.sequenced {
+            self.buffer.extend_from_slice(as_u8_slice(&self.session));
+            self.buffer.extend_from_slice(as_u8_slice(&self.next));
+        }
+        self.buffer.extend_from_slice(as_u8_slice(buffer));
+        let sent = self.socket.send(&self.buffer)?;
+        if sent != self.buffer.len() {
+            return Err(Error::new(
+                ErrorKind::WriteZero,
+                "datagram was truncated on send",
+            ));
+        }
+        self.next += 1;
+        Ok(())
+    }
+
+    /// Read a message of type `T` from the next datagram.
+    ///
+    /// This function is blocking.
+    ///
+    /// This function fails with [`ErrorKind::InvalidData`] if the
+    /// datagram doesn't have the size of the log. When the gutter is
+    /// sequenced, duplicates and datagrams older than the latest one
+    /// picked up are skipped.
+    ///
+    /// This function doesn't change endianness, so it must be the
+    /// same between the peers.
+    pub fn pick_up<T: Log>(&mut self, buffer: &mut T) -> Result<()> {
+        let header = self.header_len();
+        let len = header + size_of::<T>();
+        // One spare byte tells a longer datagram apart, as the socket
+        // truncates it to the buffer.
+        self.buffer.resize(len + 1, 0);
+        loop {
+            let received = self.socket.recv(&mut self.buffer)?;
+            if received != len {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "datagram size doesn't match the log",
+                ));
+            }
+            if self.sequenced {
+                let (mut session, mut sequence) = (0u64, 0u64);
+                as_u8_slice_mut(&mut session).copy_from_slice(&self.buffer[..header / 2]);
+                as_u8_slice_mut(&mut sequence).copy_from_slice(&self.buffer[header / 2..header]);
+                if !self.accept(session, sequence) {
+                    continue;
+                }
+            }
+            as_u8_slice_mut(buffer).copy_from_slice(&self.buffer[header..len]);
+            return Ok(());
+        }
+    }
+
+    /// Record `sequence` of `session` as received, returning whether it
+    /// is newer than any picked up so far, or starts a new session.
+    fn accept(&mut self, session: u64, sequence: u64) -> bool {
+        if self.peer_session != Some(session) {
+            if self.previous_session == Some(session) {
+                // Delayed from before the restart.
+                self.reordered += 1;
+                return false;
+            }
+            if self.peer_session.is_some() {
+                self.restarts += 1;
+            }
+            self.previous_session = self.peer_session.replace(session);
+            self.latest = None;
+        }
+        let Some(latest) = self.latest else {
+            self.first = sequence;
+            self.latest = Some(sequence);
+            self.seen = 1;
+            return true;
+        };
+
+        if sequence > latest {
+            let shift = sequence - latest;
+            self.lost += shift - 1;
+            self.seen = if shift < WINDOW {
+                self.seen << shift
+            } else {
+                0
+            };
+            self.seen |= 1;
+            self.latest = Some(sequence);
+            return true;
+        }
+
+        let age = latest - sequence;
+        if age < WINDOW && self.seen & (1 << age) != 0 {
+            self.duplicates += 1;
+        } else {
+            // Only the gaps after the first datagram were counted as lost.
+            if age < WINDOW && sequence > self.first {
+                self.seen |= 1 << age;
+                self.lost -= 1;
+            }
+            self.reordered += 1;
+        }
+        false
+    }
+
+    fn header_len(&self) -> usize {
+        if self.sequenced {
+            2 * size_of::<u64>()
+        } else {
+            0
+        }
+    }
+
+    /// Return the number of duplicate datagrams skipped.
+    ///
+    /// This is always 0 if the gutter isn't sequenced.
+    pub fn duplicates(&self) -> u64 {
+        self.duplicates
+    }
+
+    /// Return the number of datagrams skipped because a later one had
+    /// already been picked up.
+    ///
+    /// This is always 0 if the gutter isn't sequenced.
+    pub fn reordered(&self) -> u64 {
+        self.reordered
+    }
+
+    /// Return the number of datagrams that never arrived, as far as
+    /// the sequence numbers tell.
+    ///
+    /// Datagrams arriving late still count as arrived, unless they are
+    /// more than 64 behind the latest one. This is always 0 if the
+    /// gutter isn't sequenced.
+    pub fn lost(&self) -> u64 {
+        self.lost
+    }
+
+    /// Return the number of times the sender restarted, as told by a
+    /// datagram from a new session.
+    ///
+    /// Such a datagram is picked up, and the sequence starts over from
+    /// it. Datagrams of the session before it, arriving late, are skipped
+    /// as reordered. This is always 0 if the gutter isn't sequenced.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```
+    /// use gutters::datagram::DatagramGutter;
+    /// use std::net::UdpSocket;
+    ///
+    /// let left = UdpSocket::bind("127.0.0.1:0")?;
+    /// let right = UdpSocket::bind("127.0.0.1:0")?;
+    /// left.connect(right.local_addr()?)?;
+    /// right.connect(left.local_addr()?)?;
+    /// let mut receiver = DatagramGutter::sequenced(right);
+    /// let mut data = 0u32;
+    ///
+    /// let mut sender = DatagramGutter::sequenced(left.try_clone()?);
+    /// for i in 0..10u32 {
+    ///     sender.throw(&i)?;
+    ///     receiver.pick_up(&mut data)?;
+    /// }
+    ///
+    /// // The sending process restarts.
+    /// let mut sender = DatagramGutter::sequenced(left);
+    /// sender.throw(&1000u32)?;
+    /// receiver.pick_up(&mut data)?;
+    /// assert_eq!((data, receiver.restarts()), (1000, 1));
+    /// sender.throw(&1001u32)?;
+    /// receiver.pick_up(&mut data)?;
+    /// assert_eq!((data, receiver.restarts(), receiver.lost()), (1001, 1, 0));
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    pub fn restarts(&self) -> u64 {
+        self.restarts
+    }
+
+    /// Get a reference to the socket.
+    pub fn get_ref(&self) -> &UdpSocket {
+        &self.socket
+    }
+
+    /// Move the socket out.
+    pub fn into_inner(self) -> UdpSocket {
+        self.socket
+    }
+}
@@ -0,0 +1,145 @@
+//! Suppression of repeated logs.
+//!
+//! A [`DedupGutter`] doesn't throw a log identical to one of the last
+//! few it has thrown, which saves bandwidth for publishers repeating an
+//! unchanging state. Each log that is thrown is preceded by the number
+//! of repeats suppressed since the previous one, so that the receiving
+//! end can keep count of them.
+//!
+//! Recent logs are kept along with their CRC-32C, which makes comparing
+//! them cheap, but only logs whose bytes are identical are suppressed.
+//! Both ends must use a `DedupGutter`.
+
+use std::collections::VecDeque;
+use std::io::{Read, Result, Write};
+
+use crate::checksum::crc32c;
+use crate::{as_u8_slice, as_u8_slice_mut, read_exact_or_closed, Log};
+
+/// A gutter suppressing repeated logs.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```
+/// use gutters::dedup::DedupGutter;
+/// use std::io::Cursor;
+///
+/// let mut sender = DedupGutter::new(Cursor::new(Vec::new()), 1);
+/// for status in [1u32, 1, 1, 2, 2, 1] {
+///     sender.throw(&status)?;
+/// }
+/// assert_eq!(sender.suppressed(), 3);
+///
+/// let mut gutter = sender.into_inner();
+/// gutter.set_position(0);
+/// let mut receiver = DedupGutter::new(gutter, 1);
+/// let mut status = 0u32;
+/// for expected in [1, 2, 1] {
+///     receiver.pick_up(&mut status)?;
+///     assert_eq!(status, expected);
+/// }
+/// assert_eq!(receiver.suppressed(), 3);
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Debug)]
+pub struct DedupGutter<G> {
+    gutter: G,
+    window: usize,
+    recent: VecDeque<(u32, Vec<u8>)>,
+    pending: u64,
+    suppressed: u64,
+}
+
+impl<G: Read + Write> DedupGutter<G> {
+    /// Wrap `gutter`, suppressing logs identical to one of the last
+    /// `window` thrown.
+    ///
+    /// A `window` of 1 only suppresses consecutive repeats, and a
+    /// `window` of 0 none at all.
+    pub fn new(gutter: G, window: usize) -> Self {
+        DedupGutter {
+            gutter,
+            window,
+            recent: VecDeque::with_capacity(window),
+            pending: 0,
+            suppressed: 0,
+        }
+    }
+
+    /// Send a message of type `T` down the gutter, unless it was
+    /// recently thrown.
+    ///
+    /// This function is blocking.
+    ///
+    /// This returns whether the log was actually thrown.
+    ///
+    /// This function doesn't change endianness, so it must be the
+    /// same between the peers.
+    pub fn throw<T: Log>(&mut self, buffer: &T) -> Result<bool> {
+        let bytes = as_u8_slice(buffer);
+        let hash = crc32c(bytes);
+        if self
+            .recent
+            .iter()
+            .any(|(recent, recent_bytes)| *recent == hash && recent_bytes == bytes)
+        {
+            self.pending += 1;
+            self.suppressed += 1;
+            return Ok(false);
+        }
+
+        self.gutter.write_all(as_u8_slice(&self.pending))?;
+        self.gutter.write_all(bytes)?;
+        self.pending = 0;
+        if self.window > 0 {
+            let mut copy = if self.recent.len() == self.window {
+                self.recent.pop_front().map(|(_, bytes)| bytes).unwrap()
+            } else {
+                Vec::with_capacity(bytes.len())
+            };
+            copy.clear();
+            copy.extend_from_slice(bytes);
+            self.recent.push_back((hash, copy));
+        }
+        Ok(true)
+    }
+
+    /// Read the next message of type `T` thrown by the peer.
+    ///
+    /// This function is blocking.
+    ///
+    /// This function fails with a [`Closed`](crate::Closed) error if the
+    /// peer closed the gutter before sending the message.
+    ///
+    /// This function doesn't change endianness, so it must be the
+    /// same between the peers.
+    pub fn pick_up<T: Log>(&mut self, buffer: &mut T) -> Result<()> {
+        let mut repeats = 0u64;
+        read_exact_or_closed(&mut self.gutter, as_u8_slice_mut(&mut repeats))?;
+        self.gutter.read_exact(as_u8_slice_mut(buffer))?;
+        self.suppressed += repeats;
+        Ok(())
+    }
+
+    /// Forget the logs thrown so far, so that the next log is thrown
+    /// even if it is a repeat.
+    pub fn reset(&mut self) {
+        self.recent.clear();
+    }
+
+    /// Return the number of repeats suppressed.
+    ///
+    /// On the throwing end, this counts the logs that weren't thrown. On
+    /// the picking up end, this counts those the peer reported, which
+    /// excludes repeats suppressed since the last log picked up.
+    pub fn suppressed(&self) -> u64 {
+        self.suppressed
+    }
+
+    /// Move the gutter out.
+    pub fn into_inner(self) -> G {
+        self.gutter
+    }
+}
@@ -0,0 +1,401 @@
+//! Accepting many peers on a listener.
+//!
+//! A [`Drain`] runs the accept loop every gutter server needs: it
+//! accepts connections on a listener, optionally runs a handshake on
+//! each of them, then hands them to a handler as a buffered
+//! [`Gutter`]. Each connection is served on a thread of its own, or by
+//! a fixed pool of [workers](Drain::workers), and the number of
+//! connections served at once can be [limited](Drain::max_connections).
+
+use std::fmt;
+use std::io::{ErrorKind, Read, Result, Write};
+use std::net::{TcpListener, TcpStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc;
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+use std::thread;
+use std::time::Duration;
+
+use crate::config::ConfigHandle;
+use crate::timeout::ReadTimeout;
+use crate::Gutter;
+
+type Handshake<S> = Box<dyn Fn(&mut Gutter<S>) -> Result<()> + Send + Sync>;
+type Limit = Box<dyn Fn() -> usize + Send>;
+type SetTimeout<S> = fn(&S, Option<Duration>) -> Result<()>;
+
+/// Interval at which a drain waiting at its connection limit checks
+/// whether the limit changed.
+const LIMIT_POLL: Duration = Duration::from_millis(100);
+
+/// Listeners a [`Drain`] can accept connections on.
+pub trait Listener {
+    /// Connections accepted by the listener.
+    type Stream: Read + Write + Send + 'static;
+
+    /// Accept a new connection.
+    ///
+    /// This function is blocking.
+    fn accept_stream(&self) -> Result<Self::Stream>;
+}
+
+impl Listener for TcpListener {
+    type Stream = TcpStream;
+
+    fn accept_stream(&self) -> Result<TcpStream> {
+        self.accept().map(|(stream, _)| stream)
+    }
+}
+
+#[cfg(unix)]
+impl Listener for std::os::unix::net::UnixListener {
+    type Stream = std::os::unix::net::UnixStream;
+
+    fn accept_stream(&self) -> Result<Self::Stream> {
+        self.accept().map(|(stream, _)| stream)
+    }
+}
+
+#[cfg(any(unix, windows))]
+impl Listener for crate::local::LocalListener {
+    type Stream = crate::local::LocalStream;
+
+    fn accept_stream(&self) -> Result<Self::Stream> {
+        self.accept()
+    }
+}
+
+/// An accept loop handing connections to a handler.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```
+/// use gutters::drain::Drain;
+/// use gutters::{pick_up, throw};
+/// use std::net::{TcpListener, TcpStream};
+/// use std::thread;
+///
+/// let listener = TcpListener::bind("127.0.0.1:0")?;
+/// let address = listener.local_addr()?;
+/// thread::spawn(move || {
+///     Drain::new(listener)
+///         .max_connections(16)
+///         .handshake(|gutter| {
+///             throw(gutter, b"HELLO")?;
+///             gutter.flush()
+///         })
+///         .run(|mut gutter| {
+///             let mut data = 0.0f64;
+///             gutter.pick_up(&mut data)?;
+///             gutter.throw(&(data * 2.0))?;
+///             gutter.flush()
+///         })
+/// });
+///
+/// let mut stream = TcpStream::connect(address)?;
+/// let mut hello = [0u8; 5];
+/// pick_up(&mut stream, &mut hello)?;
+/// assert_eq!(&hello, b"HELLO");
+/// throw(&mut stream, &21.0f64)?;
+/// let mut answer = 0.0f64;
+/// pick_up(&mut stream, &mut answer)?;
+/// assert_eq!(answer, 42.0);
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub struct Drain<L: Listener> {
+    listener: L,
+    handshake: Option<Handshake<L::Stream>>,
+    handshake_timeout: Option<(Duration, SetTimeout<L::Stream>)>,
+    max_connections: Option<Limit>,
+    workers: usize,
+}
+
+impl<L: Listener> Drain<L> {
+    /// Accept connections on `listener`, each served on a thread of its
+    /// own, without any handshake or limit.
+    pub fn new(listener: L) -> Self {
+        Drain {
+            listener,
+            handshake: None,
+            handshake_timeout: None,
+            max_connections: None,
+            workers: 0,
+        }
+    }
+
+    /// Run `handshake` on each connection before handing it to the
+    /// handler.
+    ///
+    /// The handshake runs on the thread serving the connection, so with
+    /// a thread per connection, a slow peer doesn't hold up the others.
+    /// With a pool of [workers](Drain::workers), or a
+    /// [connection limit](Drain::max_connections), it still holds up a
+    /// worker or a place until it is done, so peers that never finish
+    /// the handshake can stall the drain, unless it is given a
+    /// [timeout](Drain::handshake_timeout). Connections whose handshake
+    /// fails are dropped.
+    pub fn handshake<F>(mut self, handshake: F) -> Self
+    where
+        F: Fn(&mut Gutter<L::Stream>) -> Result<()> + Send + Sync + 'static,
+    {
+        self.handshake = Some(Box::new(handshake));
+        self
+    }
+
+    /// Drop connections whose handshake doesn't receive anything for
+    /// `timeout`.
+    ///
+    /// The read timeout of the connection is set for the handshake, and
+    /// cleared before handing the connection to the handler.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `timeout` is zero.
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self
+    where
+        L::Stream: ReadTimeout,
+    {
+        assert!(!timeout.is_zero(), "handshake timeout must not be zero");
+        self.handshake_timeout = Some((timeout, <L::Stream as ReadTimeout>::set_read_timeout));
+        self
+    }
+
+    /// Serve at most `max` connections at once.
+    ///
+    /// Once the limit is reached, new connections wait in the backlog
+    /// of the listener until one of the served connections ends.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `max` is 0.
+    pub fn max_connections(mut self, max: usize) -> Self {
+        assert!(max > 0, "a drain must serve at least one connection");
+        self.max_connections = Some(Box::new(move || max));
+        self
+    }
+
+    /// Serve at most as many connections at once as `limit` returns for
+    /// the current configuration of `config`.
+    ///
+    /// The limit is checked before accepting each connection, so
+    /// lowering it doesn't end connections already being served. A
+    /// limit of 0 pauses accepting until it is raised again.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```no_run
+    /// use gutters::config::ConfigHandle;
+    /// use gutters::drain::Drain;
+    /// use std::net::TcpListener;
+    ///
+    /// struct Settings {
+    ///     max_peers: usize,
+    /// }
+    ///
+    /// let config = ConfigHandle::new(Settings { max_peers: 64 });
+    /// let listener = TcpListener::bind("0.0.0.0:34567")?;
+    /// let drain = Drain::new(listener).max_connections_from(config.clone(), |s| s.max_peers);
+    /// std::thread::spawn(move || drain.run(|_gutter| Ok(())));
+    ///
+    /// // Later, without restarting.
+    /// config.store(Settings { max_peers: 128 });
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    pub fn max_connections_from<C, F>(mut self, config: ConfigHandle<C>, limit: F) -> Self
+    where
+        C: Send + Sync + 'static,
+        F: Fn(&C) -> usize + Send + 'static,
+    {
+        self.max_connections = Some(Box::new(move || limit(&config.load())));
+        self
+    }
+
+    /// Serve connections on a pool of `workers` threads, rather than on
+    /// a thread per connection.
+    ///
+    /// Connections accepted while all the workers are busy wait for one
+    /// of them to be done.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `workers` is 0.
+    pub fn workers(mut self, workers: usize) -> Self {
+        assert!(workers > 0, "a drain needs at least one worker");
+        self.workers = workers;
+        self
+    }
+
+    /// Accept connections forever, handing each of them to `handler`.
+    ///
+    /// Errors returned by the handshake or the handler, and panics in
+    /// them, only end their own connection.
+    ///
+    /// This function is blocking, and only returns if accepting a
+    /// connection fails, other than because the peer gave up first.
+    pub fn run<F>(self, handler: F) -> Result<()>
+    where
+        F: Fn(Gutter<L::Stream>) -> Result<()> + Send + Sync + 'static,
+    {
+        let active = Arc::new(Active {
+            count: Mutex::new(0),
+            freed: Condvar::new(),
+        });
+        let serve = Arc::new(Serve {
+            handshake: self.handshake,
+            handshake_timeout: self.handshake_timeout,
+            handler,
+        });
+
+        let pool = if self.workers > 0 {
+            let (sender, receiver) = mpsc::channel::<Slot<L::Stream>>();
+            let receiver = Arc::new(Mutex::new(receiver));
+            for _ in 0..self.workers {
+                let receiver = Arc::clone(&receiver);
+                let serve = Arc::clone(&serve);
+                thread::spawn(move || loop {
+                    let slot = receiver
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .recv();
+                    match slot {
+                        // A panicking handler must not take its worker
+                        // down with it.
+                        Ok(slot) => {
+                            let _ = panic::catch_unwind(AssertUnwindSafe(|| serve.serve(slot)));
+                        }
+                        Err(_) => break,
+                    }
+                });
+            }
+            Some(sender)
+        } else {
+            None
+        };
+
+        loop {
+            if let Some(max) = &self.max_connections {
+                active.wait_below(max);
+            }
+            let stream = match self.listener.accept_stream() {
+                Ok(stream) => stream,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        ErrorKind::ConnectionAborted | ErrorKind::Interrupted
+                    ) =>
+                {
+                    continue
+                }
+                Err(e) => return Err(e),
+            };
+
+            let slot = Slot {
+                stream,
+                _permit: active.acquire(),
+            };
+            match &pool {
+                Some(sender) => {
+                    // The workers only stop once the sender is dropped.
+                    let _ = sender.send(slot);
+                }
+                None => {
+                    let serve = Arc::clone(&serve);
+                    thread::spawn(move || serve.serve(slot));
+                }
+            }
+        }
+    }
+}
+
+impl<L: Listener + fmt::Debug> fmt::Debug for Drain<L> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Drain")
+            .field("listener", &self.listener)
+            .field("handshake", &self.handshake.is_some())
+            .field(
+                "handshake_timeout",
+                &self.handshake_timeout.map(|(timeout, _)| timeout),
+            )
+            .field(
+                "max_connections",
+                &self.max_connections.as_ref().map(|max| max()),
+            )
+            .field("workers", &self.workers)
+            .finish()
+    }
+}
+
+struct Serve<S: Write, F> {
+    handshake: Option<Handshake<S>>,
+    handshake_timeout: Option<(Duration, SetTimeout<S>)>,
+    handler: F,
+}
+
+impl<S: Read + Write, F: Fn(Gutter<S>) -> Result<()>> Serve<S, F> {
+    fn serve(&self, slot: Slot<S>) {
+        let mut gutter = Gutter::new(slot.stream);
+        if let Some(handshake) = &self.handshake {
+            if let Some((timeout, set_timeout)) = self.handshake_timeout {
+                if set_timeout(gutter.get_ref(), Some(timeout)).is_err() {
+                    return;
+                }
+            }
+            if handshake(&mut gutter).is_err() {
+                return;
+            }
+            if let Some((_, set_timeout)) = self.handshake_timeout {
+                if set_timeout(gutter.get_ref(), None).is_err() {
+                    return;
+                }
+            }
+        }
+        let _ = (self.handler)(gutter);
+        // The connection stops counting as `slot._permit` is dropped,
+        // even if the handler panics.
+    }
+}
+
+/// Number of connections being served.
+struct Active {
+    count: Mutex<usize>,
+    freed: Condvar,
+}
+
+impl Active {
+    fn wait_below(&self, max: &Limit) {
+        let mut count = self.count.lock().unwrap_or_else(PoisonError::into_inner);
+        // The limit may be raised without any connection ending, so it
+        // is checked again every now and then.
+        while *count >= max() {
+            count = self
+                .freed
+                .wait_timeout(count, LIMIT_POLL)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+    }
+
+    fn acquire(self: &Arc<Self>) -> Permit {
+        *self.count.lock().unwrap_or_else(PoisonError::into_inner) += 1;
+        Permit(Arc::clone(self))
+    }
+}
+
+/// A connection counted in [`Active`] until dropped.
+struct Permit(Arc<Active>);
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        *self.0.count.lock().unwrap_or_else(PoisonError::into_inner) -= 1;
+        self.0.freed.notify_one();
+    }
+}
+
+struct Slot<S> {
+    stream: S,
+    _permit: Permit,
+}
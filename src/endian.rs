@@ -0,0 +1,455 @@
+//! Byte order conversion.
+//!
+//! [`throw_be`] and [`pick_up_be`], or their little-endian counterparts,
+//! exchange logs in a fixed byte order, so that peers of different
+//! endianness can talk. Logs must implement [`SwapBytes`], which can be
+//! derived field by field with the `derive` feature.
+//!
+//! The `swap_bytes_*` functions reverse the byte order of every element
+//! of a slice in place. On x86_64, they shuffle the bytes of 32 or 16 at
+//! a time with the widest vector instructions available at runtime (AVX2
+//! or SSSE3). Arrays and slices of primitives, whether thrown as a log
+//! or with [`throw_slice_be`], go through them.
+
+use std::io::{Read, Result, Write};
+use std::slice;
+
+use crate::{as_u8_slice, as_u8_slice_mut, pick_up_slice, read_exact_or_closed, throw_slice, Log};
+
+#[cfg(feature = "derive")]
+pub use gutters_derive::SwapBytes;
+
+/// Logs whose byte order can be reversed.
+///
+/// This is implemented for integer and floating point primitives, and
+/// for arrays of them. Structs reverse each of their fields.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```
+/// use gutters::endian::SwapBytes;
+///
+/// #[derive(Clone)]
+/// #[repr(C)]
+/// struct Sample {
+///     timestamp: u64,
+///     channels: [f32; 4],
+/// }
+///
+/// impl SwapBytes for Sample {
+///     fn swap_bytes_in_place(&mut self) {
+///         self.timestamp.swap_bytes_in_place();
+///         self.channels.swap_bytes_in_place();
+///     }
+/// }
+/// ```
+pub trait SwapBytes {
+    /// Reverse the byte order of every primitive in `self`.
+    fn swap_bytes_in_place(&mut self);
+
+    /// Reverse the byte order of every primitive in `values`.
+    ///
+    /// The default implementation goes through `values` one by one.
+    /// Primitives of 2, 4 or 8 bytes use the `swap_bytes_*` functions
+    /// instead.
+    fn swap_bytes_slice(values: &mut [Self])
+    where
+        Self: Sized,
+    {
+        for value in values {
+            value.swap_bytes_in_place();
+        }
+    }
+}
+
+macro_rules! impl_swap_bytes {
+    ($($t:ty),*) => {
+        $(
+            impl SwapBytes for $t {
+                fn swap_bytes_in_place(&mut self) {
+                    *self = self.swap_bytes();
+                }
+            }
+        )*
+    };
+}
+
+impl_swap_bytes!(u8, u128, usize, i8, i128, isize);
+
+macro_rules! impl_swap_bytes_vectorized {
+    ($swap:ident, $u:ty, $($t:ty),*) => {
+        $(
+            impl SwapBytes for $t {
+                fn swap_bytes_in_place(&mut self) {
+                    let mut bytes = self.to_ne_bytes();
+                    bytes.reverse();
+                    *self = <$t>::from_ne_bytes(bytes);
+                }
+
+                fn swap_bytes_slice(values: &mut [Self]) {
+                    // SAFETY: both types have the same size and alignment,
+                    // and any bit pattern is valid for either.
+                    let values = unsafe {
+                        slice::from_raw_parts_mut(values.as_mut_ptr().cast::<$u>(), values.len())
+                    };
+                    $swap(values);
+                }
+            }
+        )*
+    };
+}
+
+impl_swap_bytes_vectorized!(swap_bytes_u16, u16, u16, i16);
+impl_swap_bytes_vectorized!(swap_bytes_u32, u32, u32, i32, f32);
+impl_swap_bytes_vectorized!(swap_bytes_u64, u64, u64, i64, f64);
+
+impl<T: SwapBytes, const N: usize> SwapBytes for [T; N] {
+    fn swap_bytes_in_place(&mut self) {
+        T::swap_bytes_slice(self);
+    }
+}
+
+/// Send a message of type `T` to the `gutter`, in big-endian byte order.
+///
+/// This function is blocking.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use gutters::endian;
+/// use std::net::TcpStream;
+/// let mut stream = TcpStream::connect("127.0.0.1:34567")?;
+///
+/// endian::throw_be(&mut stream, &[1u32, 2, 3])?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn throw_be<G: Write, T: Log + SwapBytes + Clone>(gutter: &mut G, buffer: &T) -> Result<()> {
+    throw_ordered(gutter, buffer, cfg!(target_endian = "little"))
+}
+
+/// Read a message of type `T`, sent in big-endian byte order, from the
+/// `gutter`.
+///
+/// This function is blocking.
+///
+/// This function fails with a [`Closed`](crate::Closed) error if the
+/// peer closed the gutter before sending the message.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```
+/// use gutters::endian;
+/// use std::io::Cursor;
+/// let mut gutter = Cursor::new(Vec::new());
+/// endian::throw_be(&mut gutter, &0x0102u16)?;
+/// assert_eq!(gutter.get_ref(), &[1, 2]);
+///
+/// gutter.set_position(0);
+/// let mut data = 0u16;
+/// endian::pick_up_be(&mut gutter, &mut data)?;
+/// assert_eq!(data, 0x0102);
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn pick_up_be<G: Read, T: Log + SwapBytes>(gutter: &mut G, buffer: &mut T) -> Result<()> {
+    pick_up_ordered(gutter, buffer, cfg!(target_endian = "little"))
+}
+
+/// Send a message of type `T` to the `gutter`, in little-endian byte
+/// order.
+///
+/// This function is blocking.
+pub fn throw_le<G: Write, T: Log + SwapBytes + Clone>(gutter: &mut G, buffer: &T) -> Result<()> {
+    throw_ordered(gutter, buffer, cfg!(target_endian = "big"))
+}
+
+/// Read a message of type `T`, sent in little-endian byte order, from
+/// the `gutter`.
+///
+/// This function is blocking.
+///
+/// This function fails with a [`Closed`](crate::Closed) error if the
+/// peer closed the gutter before sending the message.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```
+/// use gutters::{endian, Closed};
+/// use std::io::Cursor;
+/// let mut gutter = Cursor::new(vec![2, 1]);
+///
+/// let mut data = 0u16;
+/// endian::pick_up_le(&mut gutter, &mut data)?;
+/// assert_eq!(data, 0x0102);
+/// assert!(Closed::is(&endian::pick_up_le(&mut gutter, &mut data).unwrap_err()));
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn pick_up_le<G: Read, T: Log + SwapBytes>(gutter: &mut G, buffer: &mut T) -> Result<()> {
+    pick_up_ordered(gutter, buffer, cfg!(target_endian = "big"))
+}
+
+fn throw_ordered<G: Write, T: Log + SwapBytes + Clone>(
+    gutter: &mut G,
+    buffer: &T,
+    swap: bool,
+) -> Result<()> {
+    if swap {
+        let mut swapped = buffer.clone();
+        swapped.swap_bytes_in_place();
+        gutter.write_all(as_u8_slice(&swapped))
+    } else {
+        gutter.write_all(as_u8_slice(buffer))
+    }
+}
+
+fn pick_up_ordered<G: Read, T: Log + SwapBytes>(
+    gutter: &mut G,
+    buffer: &mut T,
+    swap: bool,
+) -> Result<()> {
+    read_exact_or_closed(gutter, as_u8_slice_mut(buffer))?;
+    if swap {
+        buffer.swap_bytes_in_place();
+    }
+    Ok(())
+}
+
+/// Send all the messages of type `T` in `logs` to the `gutter`, in
+/// big-endian byte order, in a single write.
+///
+/// This function is blocking.
+///
+/// See [`throw_slice`].
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```
+/// use gutters::endian;
+/// use std::io::Cursor;
+/// let mut gutter = Cursor::new(Vec::new());
+/// endian::throw_slice_be(&mut gutter, &[0x0102u16, 0x0304])?;
+/// assert_eq!(gutter.get_ref(), &[1, 2, 3, 4]);
+///
+/// gutter.set_position(0);
+/// let mut data = [0u16; 2];
+/// endian::pick_up_slice_be(&mut gutter, &mut data)?;
+/// assert_eq!(data, [0x0102, 0x0304]);
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn throw_slice_be<G: Write, T: Log + SwapBytes + Clone>(
+    gutter: &mut G,
+    logs: &[T],
+) -> Result<()> {
+    throw_slice_ordered(gutter, logs, cfg!(target_endian = "little"))
+}
+
+/// Read `logs.len()` messages of type `T`, sent in big-endian byte
+/// order, from the `gutter` into `logs`.
+///
+/// This function is blocking.
+///
+/// See [`pick_up_slice`].
+pub fn pick_up_slice_be<G: Read, T: Log + SwapBytes>(gutter: &mut G, logs: &mut [T]) -> Result<()> {
+    pick_up_slice_ordered(gutter, logs, cfg!(target_endian = "little"))
+}
+
+/// Send all the messages of type `T` in `logs` to the `gutter`, in
+/// little-endian byte order, in a single write.
+///
+/// This function is blocking.
+///
+/// See [`throw_slice`].
+pub fn throw_slice_le<G: Write, T: Log + SwapBytes + Clone>(
+    gutter: &mut G,
+    logs: &[T],
+) -> Result<()> {
+    throw_slice_ordered(gutter, logs, cfg!(target_endian = "big"))
+}
+
+/// Read `logs.len()` messages of type `T`, sent in little-endian byte
+/// order, from the `gutter` into `logs`.
+///
+/// This function is blocking.
+///
+/// See [`pick_up_slice`].
+pub fn pick_up_slice_le<G: Read, T: Log + SwapBytes>(gutter: &mut G, logs: &mut [T]) -> Result<()> {
+    pick_up_slice_ordered(gutter, logs, cfg!(target_endian = "big"))
+}
+
+fn throw_slice_ordered<G: Write, T: Log + SwapBytes + Clone>(
+    gutter: &mut G,
+    logs: &[T],
+    swap: bool,
+) -> Result<()> {
+    if swap {
+        let mut swapped = logs.to_vec();
+        T::swap_bytes_slice(&mut swapped);
+        throw_slice(gutter, &swapped)
+    } else {
+        throw_slice(gutter, logs)
+    }
+}
+
+fn pick_up_slice_ordered<G: Read, T: Log + SwapBytes>(
+    gutter: &mut G,
+    logs: &mut [T],
+    swap: bool,
+) -> Result<()> {
+    pick_up_slice(gutter, logs)?;
+    if swap {
+        T::swap_bytes_slice(logs);
+    }
+    Ok(())
+}
+
+/// Shuffle indices reversing each group of `width` bytes of a 16-byte
+/// lane, repeated for both lanes of an AVX2 register.
+#[cfg(target_arch = "x86_64")]
+const fn shuffle_mask(width: usize) -> [u8; 32] {
+    let mut mask = [0; 32];
+    let mut i = 0;
+    while i < 32 {
+        let lane = i % 16;
+        mask[i] = (lane - lane % width + width - 1 - lane % width) as u8;
+        i += 1;
+    }
+    mask
+}
+
+macro_rules! swap_bytes_slice {
+    ($name:ident, $avx2:ident, $ssse3:ident, $t:ty) => {
+        #[doc = concat!("Reverse the byte order of every `", stringify!($t), "` of `values`.")]
+        ///
+        /// # Examples
+        ///
+        /// Basic usage:
+        ///
+        /// ```
+        #[doc = concat!("use gutters::endian::", stringify!($name), ";")]
+        ///
+        #[doc = concat!("let mut values = [1", stringify!($t), ", 2, 3];")]
+        #[doc = concat!(stringify!($name), "(&mut values);")]
+        #[doc = concat!("assert_eq!(values, [1, 2, 3].map(", stringify!($t), "::swap_bytes));")]
+        /// ```
+        pub fn $name(values: &mut [$t]) {
+            #[cfg(target_arch = "x86_64")]
+            {
+                if std::is_x86_feature_detected!("avx2") {
+                    // SAFETY: the required target feature was just detected.
+                    return unsafe { $avx2(values) };
+                }
+                if std::is_x86_feature_detected!("ssse3") {
+                    // SAFETY: the required target feature was just detected.
+                    return unsafe { $ssse3(values) };
+                }
+            }
+            for value in values {
+                *value = value.swap_bytes();
+            }
+        }
+
+        #[cfg(target_arch = "x86_64")]
+        #[target_feature(enable = "avx2")]
+        unsafe fn $avx2(values: &mut [$t]) {
+            use std::arch::x86_64::{
+                __m256i, _mm256_loadu_si256, _mm256_shuffle_epi8, _mm256_storeu_si256,
+            };
+
+            const MASK: [u8; 32] = shuffle_mask(std::mem::size_of::<$t>());
+            let mask = _mm256_loadu_si256(MASK.as_ptr().cast());
+            let mut chunks = values.chunks_exact_mut(32 / std::mem::size_of::<$t>());
+            for chunk in &mut chunks {
+                // Each chunk is exactly 32 bytes long, and unaligned
+                // loads and stores accept any address.
+                let chunk = chunk.as_mut_ptr().cast::<__m256i>();
+                _mm256_storeu_si256(chunk, _mm256_shuffle_epi8(_mm256_loadu_si256(chunk), mask));
+            }
+            for value in chunks.into_remainder() {
+                *value = value.swap_bytes();
+            }
+        }
+
+        #[cfg(target_arch = "x86_64")]
+        #[target_feature(enable = "ssse3")]
+        unsafe fn $ssse3(values: &mut [$t]) {
+            use std::arch::x86_64::{__m128i, _mm_loadu_si128, _mm_shuffle_epi8, _mm_storeu_si128};
+
+            const MASK: [u8; 32] = shuffle_mask(std::mem::size_of::<$t>());
+            let mask = _mm_loadu_si128(MASK.as_ptr().cast());
+            let mut chunks = values.chunks_exact_mut(16 / std::mem::size_of::<$t>());
+            for chunk in &mut chunks {
+                // Each chunk is exactly 16 bytes long, and unaligned
+                // loads and stores accept any address.
+                let chunk = chunk.as_mut_ptr().cast::<__m128i>();
+                _mm_storeu_si128(chunk, _mm_shuffle_epi8(_mm_loadu_si128(chunk), mask));
+            }
+            for value in chunks.into_remainder() {
+                *value = value.swap_bytes();
+            }
+        }
+    };
+}
+swap_bytes_slice!(
+    swap_bytes_u16,
+    swap_bytes_u16_avx2,
+    swap_bytes_u16_ssse3,
+    u16
+);
+swap_bytes_slice!(
+    swap_bytes_u32,
+    swap_bytes_u32_avx2,
+    swap_bytes_u32_ssse3,
+    u32
+);
+swap_bytes_slice!(
+    swap_bytes_u64,
+    swap_bytes_u64_avx2,
+    swap_bytes_u64_ssse3,
+    u64
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Doctests only go through whichever kernel the machine dispatches
+    // to, so all of them are compared with the scalar code here.
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn kernels_match_scalar() {
+        macro_rules! check {
+            ($t:ty, $avx2:ident, $ssse3:ident) => {
+                for len in 0..=40 {
+                    let values: Vec<$t> = (0..len as $t)
+                        .map(|i| i.wrapping_mul(0x0123_4567_89ab_cdef_u64 as $t))
+                        .collect();
+                    let expected: Vec<$t> = values.iter().map(|value| value.swap_bytes()).collect();
+                    if std::is_x86_feature_detected!("avx2") {
+                        let mut swapped = values.clone();
+                        // SAFETY: the required target feature was just detected.
+                        unsafe { $avx2(&mut swapped) };
+                        assert_eq!(swapped, expected);
+                    }
+                    if std::is_x86_feature_detected!("ssse3") {
+                        let mut swapped = values.clone();
+                        // SAFETY: the required target feature was just detected.
+                        unsafe { $ssse3(&mut swapped) };
+                        assert_eq!(swapped, expected);
+                    }
+                }
+            };
+        }
+        check!(u16, swap_bytes_u16_avx2, swap_bytes_u16_ssse3);
+        check!(u32, swap_bytes_u32_avx2, swap_bytes_u32_ssse3);
+        check!(u64, swap_bytes_u64_avx2, swap_bytes_u64_ssse3);
+    }
+}
@@ -0,0 +1,340 @@
+//! Failover between a primary and a backup gutter.
+//!
+//! A [`FailoverGutter`] throws down its primary gutter until it fails,
+//! then switches to the backup one, resending the log that failed. Each
+//! log is preceded by a sequence number, which lets the receiving end
+//! drop the duplicates this may produce.
+//!
+//! Once a new primary gutter is established, the throwing end calls
+//! [`restore_primary`](FailoverGutter::restore_primary), which tells the
+//! receiving end to switch back through the backup gutter. The receiving
+//! end must have been given its new primary with
+//! [`expect_primary`](FailoverGutter::expect_primary).
+//!
+//! Failover avoids duplicates, but can't guarantee delivery: logs
+//! accepted by the primary gutter right before it broke may never reach
+//! the peer.
+
+use std::io::{Error, ErrorKind, Read, Result, Write};
+
+use crate::{as_u8_slice, as_u8_slice_mut, Log};
+
+/// Sequence number announcing a switch back to the primary gutter.
+const SWITCH: u64 = u64::MAX;
+
+/// A gutter backed up by a second one.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```
+/// use gutters::failover::FailoverGutter;
+/// use gutters::testing::pair;
+/// use std::io::{self, ErrorKind, Read, Write};
+///
+/// /// A link delivering every log, but reporting the second one as lost.
+/// struct Flaky<G> {
+///     gutter: G,
+///     flushes: u32,
+/// }
+///
+/// impl<G: Read> Read for Flaky<G> {
+///     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+///         self.gutter.read(buf)
+///     }
+/// }
+///
+/// impl<G: Write> Write for Flaky<G> {
+///     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+///         self.gutter.write(buf)
+///     }
+///
+///     fn flush(&mut self) -> io::Result<()> {
+///         self.gutter.flush()?;
+///         self.flushes += 1;
+///         if self.flushes == 2 {
+///             return Err(ErrorKind::BrokenPipe.into());
+///         }
+///         Ok(())
+///     }
+/// }
+///
+/// let (ethernet, ethernet_peer) = pair();
+/// let (cellular, cellular_peer) = pair();
+/// let mut sender = FailoverGutter::new(Flaky { gutter: ethernet, flushes: 0 }, cellular);
+/// let mut receiver = FailoverGutter::new(ethernet_peer, cellular_peer);
+///
+/// for i in 1..=3u32 {
+///     sender.throw(&i)?;
+/// }
+/// assert!(sender.is_on_backup());
+///
+/// // The second log went down both gutters, but is only picked up once.
+/// let mut data = 0u32;
+/// for i in 1..=3 {
+///     receiver.pick_up(&mut data)?;
+///     assert_eq!(data, i);
+/// }
+/// assert!(receiver.is_on_backup());
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Debug)]
+pub struct FailoverGutter<P, B> {
+    primary: Option<P>,
+    standby: Option<P>,
+    backup: B,
+    switch_pending: bool,
+    thrown: u64,
+    picked_up: u64,
+}
+
+impl<P: Read + Write, B: Read + Write> FailoverGutter<P, B> {
+    /// Create a gutter that throws down `primary` and fails over to
+    /// `backup`.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```no_run
+    /// use gutters::failover::FailoverGutter;
+    /// use std::net::TcpStream;
+    ///
+    /// let ethernet = TcpStream::connect("10.0.0.1:34567")?;
+    /// let cellular = TcpStream::connect("100.64.0.1:34567")?;
+    /// let mut gutter = FailoverGutter::new(ethernet, cellular);
+    ///
+    /// gutter.throw(&64.0)?;
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    pub fn new(primary: P, backup: B) -> Self {
+        FailoverGutter {
+            primary: Some(primary),
+            standby: None,
+            backup,
+            switch_pending: false,
+            thrown: 0,
+            picked_up: 0,
+        }
+    }
+
+    /// Check whether the gutter has failed over to its backup.
+    pub fn is_on_backup(&self) -> bool {
+        self.primary.is_none()
+    }
+
+    /// Send a message of type `T`, preceded by its sequence number.
+    ///
+    /// This function is blocking.
+    ///
+    /// If throwing down the primary gutter fails, the message is thrown
+    /// again down the backup one, which is used from then on. An error
+    /// is only returned if the backup gutter fails too.
+    ///
+    /// This function doesn't change endianness, so it must be the
+    /// same between the peers.
+    pub fn throw<T: Log>(&mut self, buffer: &T) -> Result<()> {
+        self.thrown += 1;
+        if let Some(primary) = &mut self.primary {
+            if send(primary, self.thrown, as_u8_slice(buffer)).is_ok() {
+                return Ok(());
+            }
+            self.primary = None;
+        }
+        send(&mut self.backup, self.thrown, as_u8_slice(buffer))
+    }
+
+    /// Read a message of type `T`, dropping any duplicate.
+    ///
+    /// This function is blocking.
+    ///
+    /// If reading from the primary gutter fails, the message is read
+    /// from the backup one, which is used from then on. An error is only
+    /// returned if the backup gutter fails too, or if the peer switched
+    /// back to a primary gutter which wasn't given to
+    /// [`expect_primary`](FailoverGutter::expect_primary) yet.
+    ///
+    /// Only errors make the gutter fail over: a primary gutter that
+    /// stalls silently blocks this function forever. Give the primary
+    /// gutter a read timeout, e.g. with
+    /// [`TcpStream::set_read_timeout`](std::net::TcpStream::set_read_timeout),
+    /// for a stall to count as a failure.
+    ///
+    /// This function doesn't change endianness, so it must be the
+    /// same between the peers.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```no_run
+    /// use gutters::failover::FailoverGutter;
+    /// use std::net::TcpListener;
+    ///
+    /// let ethernet = TcpListener::bind("10.0.0.1:34567")?;
+    /// let cellular = TcpListener::bind("100.64.0.1:34567")?;
+    /// let mut gutter = FailoverGutter::new(ethernet.accept()?.0, cellular.accept()?.0);
+    ///
+    /// let mut data = 0.0f64;
+    /// loop {
+    ///     gutter.pick_up(&mut data)?;
+    ///     println!("{}", data);
+    /// }
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    pub fn pick_up<T: Log>(&mut self, buffer: &mut T) -> Result<()> {
+        loop {
+            let sequence = match &mut self.primary {
+                Some(primary) => match receive(primary, as_u8_slice_mut(buffer)) {
+                    Ok(sequence) => sequence,
+                    Err(_) => {
+                        self.primary = None;
+                        continue;
+                    }
+                },
+                None => receive(&mut self.backup, as_u8_slice_mut(buffer))?,
+            };
+
+            if sequence == SWITCH {
+                match self.standby.take() {
+                    Some(primary) => self.primary = Some(primary),
+                    None => {
+                        self.switch_pending = true;
+                        return Err(Error::new(
+                            ErrorKind::NotConnected,
+                            "peer switched back to a primary gutter that isn't expected yet",
+                        ));
+                    }
+                }
+            } else if sequence > self.picked_up {
+                self.picked_up = sequence;
+                return Ok(());
+            }
+        }
+    }
+
+    /// Switch back to a newly established `primary` gutter, and tell
+    /// the peer to do the same.
+    ///
+    /// This is meant for the throwing end, once it
+    /// [is on its backup](FailoverGutter::is_on_backup).
+    ///
+    /// This function fails with [`ErrorKind::InvalidInput`] if the
+    /// gutter is still on its primary, as the peer would keep reading
+    /// from it. `primary` is dropped then.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```
+    /// use gutters::failover::FailoverGutter;
+    /// use gutters::testing::{pair, FaultyGutter};
+    ///
+    /// let (ethernet, ethernet_peer) = pair();
+    /// let (cellular, cellular_peer) = pair();
+    /// let ethernet = FaultyGutter::new(ethernet).fail_after(0);
+    /// let mut sender = FailoverGutter::new(ethernet, cellular);
+    /// let mut receiver = FailoverGutter::new(ethernet_peer, cellular_peer);
+    ///
+    /// sender.throw(&1u32)?; // The ethernet link is down.
+    /// assert!(sender.is_on_backup());
+    ///
+    /// let (ethernet, ethernet_peer) = pair();
+    /// receiver.expect_primary(ethernet_peer);
+    /// sender.restore_primary(FaultyGutter::new(ethernet))?;
+    /// sender.throw(&2u32)?;
+    ///
+    /// let mut data = 0u32;
+    /// receiver.pick_up(&mut data)?;
+    /// assert_eq!(data, 1);
+    /// receiver.pick_up(&mut data)?;
+    /// assert_eq!(data, 2);
+    /// assert!(!receiver.is_on_backup());
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    pub fn restore_primary(&mut self, primary: P) -> Result<()> {
+        if self.primary.is_some() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "gutter is still on its primary",
+            ));
+        }
+        self.backup.write_all(&SWITCH.to_ne_bytes())?;
+        self.backup.flush()?;
+        self.primary = Some(primary);
+        Ok(())
+    }
+
+    /// Give a newly established `primary` gutter to switch back to, once
+    /// the peer calls [`restore_primary`](FailoverGutter::restore_primary).
+    ///
+    /// This is meant for the receiving end. If the peer switched back
+    /// first, [`pick_up`](FailoverGutter::pick_up) fails until then, and
+    /// reads from `primary` afterwards.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```
+    /// use gutters::failover::FailoverGutter;
+    /// use gutters::testing::{pair, FaultyGutter};
+    /// use std::io::ErrorKind;
+    ///
+    /// let (ethernet, ethernet_peer) = pair();
+    /// let (cellular, cellular_peer) = pair();
+    /// let ethernet = FaultyGutter::new(ethernet).fail_after(0);
+    /// let mut sender = FailoverGutter::new(ethernet, cellular);
+    /// let mut receiver = FailoverGutter::new(ethernet_peer, cellular_peer);
+    ///
+    /// sender.throw(&1u32)?;
+    /// let (ethernet, ethernet_peer) = pair();
+    /// sender.restore_primary(FaultyGutter::new(ethernet))?;
+    /// sender.throw(&2u32)?;
+    ///
+    /// let mut data = 0u32;
+    /// receiver.pick_up(&mut data)?;
+    /// assert_eq!(data, 1);
+    /// // The new primary gutter wasn't handed over yet.
+    /// let error = receiver.pick_up(&mut data).unwrap_err();
+    /// assert_eq!(error.kind(), ErrorKind::NotConnected);
+    ///
+    /// receiver.expect_primary(ethernet_peer);
+    /// receiver.pick_up(&mut data)?;
+    /// assert_eq!(data, 2);
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    pub fn expect_primary(&mut self, primary: P) {
+        if self.switch_pending {
+            self.switch_pending = false;
+            self.primary = Some(primary);
+        } else {
+            self.standby = Some(primary);
+        }
+    }
+
+    /// Move the gutters out, as `(primary, backup)`.
+    ///
+    /// The primary gutter is `None` if the gutter failed over.
+    pub fn into_inner(self) -> (Option<P>, B) {
+        (self.primary, self.backup)
+    }
+}
+
+fn send<G: Write>(gutter: &mut G, sequence: u64, bytes: &[u8]) -> Result<()> {
+    gutter.write_all(&sequence.to_ne_bytes())?;
+    gutter.write_all(bytes)?;
+    gutter.flush()
+}
+
+fn receive<G: Read>(gutter: &mut G, bytes: &mut [u8]) -> Result<u64> {
+    let mut sequence = [0u8; 8];
+    gutter.read_exact(&mut sequence)?;
+    let sequence = u64::from_ne_bytes(sequence);
+    if sequence != SWITCH {
+        gutter.read_exact(bytes)?;
+    }
+    Ok(sequence)
+}
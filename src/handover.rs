@@ -0,0 +1,287 @@
+//! Handing live gutters over to another process.
+//!
+//! During a graceful restart, a daemon can pass its connections to its
+//! successor rather than dropping them: [`hand_over`] sends the file
+//! descriptor of a gutter over a Unix domain socket with `SCM_RIGHTS`,
+//! along with the state of the protocol running on it, e.g. sequence
+//! numbers or credits, and [`take_over`] rebuilds the gutter on the other
+//! end. Peers don't notice anything, except for a pause.
+//!
+//! Data already read from the gutter but not picked up yet is handed
+//! over too, so no log is lost or split. This module is only available
+//! on Linux.
+
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::mem::size_of;
+use std::os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::net::UnixStream;
+
+use crate::{as_u8_slice, as_u8_slice_mut, Gutter, Log, DEFAULT_MAX_FRAME_LEN};
+
+#[repr(C)]
+struct IoVec {
+    base: *mut u8,
+    len: usize,
+}
+
+#[repr(C)]
+struct MsgHdr {
+    name: *mut u8,
+    name_len: u32,
+    iov: *mut IoVec,
+    iov_len: usize,
+    control: *mut u8,
+    control_len: usize,
+    flags: i32,
+}
+
+#[repr(C)]
+struct CmsgHdr {
+    len: usize,
+    level: i32,
+    kind: i32,
+}
+
+extern "C" {
+    fn sendmsg(fd: i32, msg: *const MsgHdr, flags: i32) -> isize;
+    fn recvmsg(fd: i32, msg: *mut MsgHdr, flags: i32) -> isize;
+}
+
+const SOL_SOCKET: i32 = 1;
+const SCM_RIGHTS: i32 = 1;
+const MSG_CTRUNC: i32 = 0x8;
+const MSG_NOSIGNAL: i32 = 0x4000;
+const MSG_CMSG_CLOEXEC: i32 = 0x4000_0000;
+
+const fn cmsg_align(len: usize) -> usize {
+    (len + size_of::<usize>() - 1) & !(size_of::<usize>() - 1)
+}
+
+/// Offset of the data of a control message.
+const CMSG_HEADER: usize = cmsg_align(size_of::<CmsgHdr>());
+/// Length of a control message holding a single file descriptor.
+const CMSG_LEN: usize = CMSG_HEADER + size_of::<i32>();
+/// Room taken by a control message holding a single file descriptor.
+const CMSG_SPACE: usize = CMSG_HEADER + cmsg_align(size_of::<i32>());
+
+/// Buffer for a control message, aligned as the kernel expects.
+#[repr(C)]
+struct Control([usize; CMSG_SPACE.div_ceil(size_of::<usize>())]);
+
+/// Send the file descriptor `fd` over `channel`, along with `payload`.
+///
+/// This function is blocking.
+///
+/// Prefer [`hand_over`] for buffered gutters.
+pub fn send_fd<F: AsFd>(channel: &UnixStream, fd: F, payload: &[u8]) -> Result<()> {
+    let len = u32::try_from(payload.len())
+        .ok()
+        .filter(|&len| len as usize <= DEFAULT_MAX_FRAME_LEN)
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "handover payload is too long"))?;
+    let mut len = len.to_ne_bytes();
+    let mut control = Control([0; CMSG_SPACE.div_ceil(size_of::<usize>())]);
+    let mut iov = IoVec {
+        base: len.as_mut_ptr(),
+        len: len.len(),
+    };
+    let header = CmsgHdr {
+        len: CMSG_LEN,
+        level: SOL_SOCKET,
+        kind: SCM_RIGHTS,
+    };
+    let raw_fd = fd.as_fd().as_raw_fd();
+
+    // SAFETY: the header and the descriptor fit in the control buffer,
+    // at the offsets given by the kernel's CMSG macros.
+    unsafe {
+        let base = control.0.as_mut_ptr() as *mut u8;
+        std::ptr::write_unaligned(base as *mut CmsgHdr, header);
+        std::ptr::write_unaligned(base.add(CMSG_HEADER) as *mut i32, raw_fd);
+    }
+    let msg = MsgHdr {
+        name: std::ptr::null_mut(),
+        name_len: 0,
+        iov: &mut iov,
+        iov_len: 1,
+        control: control.0.as_mut_ptr() as *mut u8,
+        control_len: CMSG_SPACE,
+        flags: 0,
+    };
+
+    // The descriptor travels with the first byte of the length prefix,
+    // the rest is written normally.
+    let sent = loop {
+        // SAFETY: `msg` and everything it points to outlive the call.
+        let sent = unsafe { sendmsg(channel.as_raw_fd(), &msg, MSG_NOSIGNAL) };
+        if sent >= 0 {
+            break sent as usize;
+        }
+        let error = Error::last_os_error();
+        if error.kind() != ErrorKind::Interrupted {
+            return Err(error);
+        }
+    };
+    let mut channel = channel;
+    channel.write_all(&len[sent..])?;
+    channel.write_all(payload)?;
+    channel.flush()
+}
+
+/// Receive a file descriptor sent by [`send_fd`] over `channel`, along
+/// with its payload.
+///
+/// This function is blocking.
+///
+/// This function fails with [`ErrorKind::InvalidData`] if no file
+/// descriptor came along.
+pub fn receive_fd(channel: &UnixStream) -> Result<(OwnedFd, Vec<u8>)> {
+    let mut len = [0u8; 4];
+    let mut control = Control([0; CMSG_SPACE.div_ceil(size_of::<usize>())]);
+    let mut iov = IoVec {
+        base: len.as_mut_ptr(),
+        len: len.len(),
+    };
+    let mut msg = MsgHdr {
+        name: std::ptr::null_mut(),
+        name_len: 0,
+        iov: &mut iov,
+        iov_len: 1,
+        control: control.0.as_mut_ptr() as *mut u8,
+        control_len: CMSG_SPACE,
+        flags: 0,
+    };
+
+    let received = loop {
+        // SAFETY: `msg` and everything it points to outlive the call.
+        let received = unsafe { recvmsg(channel.as_raw_fd(), &mut msg, MSG_CMSG_CLOEXEC) };
+        if received >= 0 {
+            break received as usize;
+        }
+        let error = Error::last_os_error();
+        if error.kind() != ErrorKind::Interrupted {
+            return Err(error);
+        }
+    };
+    if received == 0 {
+        return Err(ErrorKind::UnexpectedEof.into());
+    }
+
+    // SAFETY: the kernel wrote at most `msg.control_len` bytes of
+    // control messages to the buffer, and a descriptor it passed is
+    // owned by this process from now on.
+    let fd = unsafe {
+        let base = control.0.as_ptr() as *const u8;
+        let header = std::ptr::read_unaligned(base as *const CmsgHdr);
+        if msg.control_len < CMSG_LEN
+            || header.level != SOL_SOCKET
+            || header.kind != SCM_RIGHTS
+            || header.len < CMSG_LEN
+        {
+            None
+        } else {
+            let fd = std::ptr::read_unaligned(base.add(CMSG_HEADER) as *const i32);
+            Some(OwnedFd::from_raw_fd(fd))
+        }
+    };
+    if msg.flags & MSG_CTRUNC != 0 {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "more than one file descriptor was handed over",
+        ));
+    }
+    let fd =
+        fd.ok_or_else(|| Error::new(ErrorKind::InvalidData, "no file descriptor was handed over"))?;
+
+    let mut channel = channel;
+    channel.read_exact(&mut len[received..])?;
+    let len = u32::from_ne_bytes(len) as usize;
+    if len > DEFAULT_MAX_FRAME_LEN {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "handover payload is too long",
+        ));
+    }
+    let mut payload = vec![0u8; len];
+    channel.read_exact(&mut payload)?;
+    Ok((fd, payload))
+}
+
+/// Hand `gutter` over to the process at the other end of `channel`,
+/// along with the protocol `state`.
+///
+/// This function is blocking.
+///
+/// Pending logs are flushed first. The gutter is closed in this process,
+/// but stays open in the other one, which must call [`take_over`].
+///
+/// This function doesn't change endianness, so it must be the
+/// same between the processes.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```
+/// use gutters::handover::{hand_over, take_over};
+/// use gutters::{throw, Gutter};
+/// use std::net::{TcpListener, TcpStream};
+/// use std::os::unix::net::UnixStream;
+///
+/// let listener = TcpListener::bind("127.0.0.1:0")?;
+/// let mut client = TcpStream::connect(listener.local_addr()?)?;
+/// let mut gutter = Gutter::new(listener.accept()?.0);
+/// throw(&mut client, &1u32)?;
+/// throw(&mut client, &2u32)?;
+///
+/// let mut data = 0u32;
+/// gutter.pick_up(&mut data)?;
+/// let sequence = 1u64;
+///
+/// // Usually, the channel connects the old and the new daemon.
+/// let (old, new) = UnixStream::pair()?;
+/// hand_over(&old, gutter, &sequence)?;
+/// let (mut gutter, sequence) = take_over::<TcpStream, u64>(&new)?;
+///
+/// gutter.pick_up(&mut data)?;
+/// assert_eq!((data, sequence), (2, 1));
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn hand_over<S, T>(channel: &UnixStream, gutter: Gutter<S>, state: &T) -> Result<()>
+where
+    S: Read + Write + AsFd,
+    T: Log,
+{
+    let (stream, unread) = gutter.into_parts()?;
+    let mut payload = as_u8_slice(state).to_vec();
+    payload.extend_from_slice(&unread);
+    send_fd(channel, &stream, &payload)
+}
+
+/// Take over a gutter handed over by [`hand_over`] through `channel`,
+/// and return it along with the protocol state.
+///
+/// This function is blocking.
+///
+/// This function fails with [`ErrorKind::InvalidData`] if the state
+/// handed over is too short for a `T`.
+///
+/// This function doesn't change endianness, so it must be the
+/// same between the processes.
+pub fn take_over<S, T>(channel: &UnixStream) -> Result<(Gutter<S>, T)>
+where
+    S: Read + Write + From<OwnedFd>,
+    T: Log + Default,
+{
+    let (fd, payload) = receive_fd(channel)?;
+    let mut state = T::default();
+    let state_len = size_of::<T>();
+    if payload.len() < state_len {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "handover state is too short",
+        ));
+    }
+    as_u8_slice_mut(&mut state).copy_from_slice(&payload[..state_len]);
+    let unread = payload[state_len..].to_vec();
+    Ok((Gutter::from_parts(S::from(fd), unread), state))
+}
@@ -0,0 +1,71 @@
+//! A status endpoint for gutter servers.
+//!
+//! Orchestrators and readiness probes usually can't speak the protocol
+//! of a gutter server. [`serve`] answers any connection on a separate
+//! socket with a plain text (or JSON, or whatever is convenient)
+//! summary instead, then hangs up, so that a probe is as simple as
+//! `nc localhost 9000`.
+
+use std::io::{ErrorKind, Result, Write};
+use std::net::TcpListener;
+use std::time::Duration;
+
+/// Accept connections on `listener` forever, sending the output of
+/// `status` to each of them before closing it.
+///
+/// `status` is called once per connection, so it should be cheap.
+/// Connections are served one at a time, and a peer that doesn't read
+/// its status within a second is dropped.
+///
+/// This function is blocking, and only returns if accepting a
+/// connection fails, other than because the peer gave up first.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```
+/// use gutters::health;
+/// use gutters::outbox::Outbox;
+/// use std::io::Read;
+/// use std::net::{TcpListener, TcpStream};
+///
+/// let peer = TcpListener::bind("127.0.0.1:0")?;
+/// let stream = TcpStream::connect(peer.local_addr()?)?;
+/// let (outbox, _sender) = Outbox::<f64>::spawn(stream);
+///
+/// let listener = TcpListener::bind("127.0.0.1:0")?;
+/// let address = listener.local_addr()?;
+/// std::thread::spawn(move || {
+///     health::serve(listener, move || {
+///         let stats = outbox.stats();
+///         format!("queued {}\nthrown {}\n", stats.queued, stats.thrown)
+///     })
+/// });
+///
+/// let mut report = String::new();
+/// TcpStream::connect(address)?.read_to_string(&mut report)?;
+/// assert_eq!(report, "queued 0\nthrown 0\n");
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn serve<F: Fn() -> String>(listener: TcpListener, status: F) -> Result<()> {
+    loop {
+        let mut stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    ErrorKind::ConnectionAborted | ErrorKind::Interrupted
+                ) =>
+            {
+                continue
+            }
+            Err(e) => return Err(e),
+        };
+        let report = status();
+        // The probe going away is its own problem, not the server's.
+        let _ = stream
+            .set_write_timeout(Some(Duration::from_secs(1)))
+            .and_then(|_| stream.write_all(report.as_bytes()));
+    }
+}
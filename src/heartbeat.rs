@@ -0,0 +1,346 @@
+//! Detection of silently dead peers.
+//!
+//! A peer whose machine crashed or whose link went down doesn't close
+//! the gutter: it just stops talking, and writes to it may keep
+//! succeeding for minutes. A [`Heartbeat`] prevents this by throwing a
+//! heartbeat from a background thread every so often, even while there
+//! is nothing to say, and a [`Monitor`] on the receiving end keeps track
+//! of when the peer was last heard from.
+//!
+//! A heartbeat is a length prefix no frame can have, so heartbeats can
+//! be interleaved with frames: [`pick_up_framed`](crate::pick_up_framed)
+//! and [`pick_up_vec`](crate::pick_up_vec) skip them. Logs of a fixed
+//! size have no room for heartbeats, so the other functions of the
+//! crate must not be mixed with them.
+
+use std::io::{Error, Read, Result, Write};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::clock::{Clock, SystemClock};
+use crate::receiver::panic_message;
+use crate::HEARTBEAT;
+
+/// Throw a single heartbeat down the `gutter`, and flush it.
+///
+/// This function is blocking.
+pub fn beat<G: Write>(gutter: &mut G) -> Result<()> {
+    gutter.write_all(&HEARTBEAT.to_ne_bytes())?;
+    gutter.flush()
+}
+
+/// A background thread throwing heartbeats.
+///
+/// The gutter is shared with the rest of the program through a
+/// [`Mutex`], which must be held while throwing each frame so that a
+/// heartbeat never lands in the middle of it. The thread stops when the
+/// `Heartbeat` is dropped, or when throwing a heartbeat fails.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```
+/// use gutters::heartbeat::{Heartbeat, Monitor};
+/// use gutters::{pick_up_framed, throw_framed};
+/// use std::net::{TcpListener, TcpStream};
+/// use std::sync::{Arc, Mutex};
+/// use std::time::Duration;
+///
+/// let listener = TcpListener::bind("127.0.0.1:0")?;
+/// let stream = Arc::new(Mutex::new(TcpStream::connect(listener.local_addr()?)?));
+/// let mut monitor = Monitor::new(listener.accept()?.0);
+///
+/// let heartbeat = Heartbeat::spawn(stream.clone(), Duration::from_millis(10));
+/// std::thread::sleep(Duration::from_millis(50));
+/// throw_framed(&mut *stream.lock().unwrap(), b"still here")?;
+///
+/// // The heartbeats are skipped.
+/// assert_eq!(pick_up_framed(&mut monitor)?, b"still here");
+/// assert!(monitor.is_alive(Duration::from_secs(1)));
+/// heartbeat.stop()?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Debug)]
+pub struct Heartbeat {
+    stop: Arc<(Mutex<bool>, Condvar)>,
+    thread: Option<JoinHandle<Result<()>>>,
+}
+
+impl Heartbeat {
+    /// Spawn a thread throwing a heartbeat down `gutter` every
+    /// `interval`.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `interval` is zero.
+    pub fn spawn<G: Write + Send + 'static>(gutter: Arc<Mutex<G>>, interval: Duration) -> Self {
+        Self::spawn_with_clock(gutter, interval, SystemClock)
+    }
+
+    /// Spawn a thread throwing a heartbeat down `gutter` every
+    /// `interval`, as measured by `clock` rather than the system clock.
+    ///
+    /// The thread checks the clock at least once every `interval` of
+    /// real time, so a [`ManualClock`](crate::clock::ManualClock)
+    /// advanced by a test triggers a heartbeat within that time.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `interval` is zero.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```
+    /// use gutters::clock::ManualClock;
+    /// use gutters::heartbeat::Heartbeat;
+    /// use std::io::Cursor;
+    /// use std::sync::{Arc, Mutex};
+    /// use std::thread;
+    /// use std::time::Duration;
+    ///
+    /// let gutter = Arc::new(Mutex::new(Cursor::new(Vec::new())));
+    /// let clock = ManualClock::new();
+    /// let interval = Duration::from_millis(10);
+    /// let heartbeat = Heartbeat::spawn_with_clock(gutter.clone(), interval, clock.clone());
+    ///
+    /// // No time passes on the clock, so no heartbeat is thrown.
+    /// thread::sleep(5 * interval);
+    /// assert!(gutter.lock().unwrap().get_ref().is_empty());
+    ///
+    /// clock.advance(interval);
+    /// while gutter.lock().unwrap().get_ref().is_empty() {
+    ///     thread::sleep(Duration::from_millis(1));
+    /// }
+    /// heartbeat.stop()?;
+    /// assert_eq!(gutter.lock().unwrap().get_ref().len(), 4);
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    pub fn spawn_with_clock<G, C>(gutter: Arc<Mutex<G>>, interval: Duration, clock: C) -> Self
+    where
+        G: Write + Send + 'static,
+        C: Clock + 'static,
+    {
+        assert!(!interval.is_zero(), "heartbeat interval must not be zero");
+        let stop = Arc::new((Mutex::new(false), Condvar::new()));
+        let thread = {
+            let stop = Arc::clone(&stop);
+            thread::spawn(move || {
+                let (stopped, signal) = &*stop;
+                let mut next = clock.now() + interval;
+                loop {
+                    let now = clock.now();
+                    if now >= next {
+                        beat(&mut *gutter.lock().unwrap_or_else(PoisonError::into_inner))?;
+                        next = now + interval;
+                        continue;
+                    }
+                    let stopped = stopped.lock().unwrap_or_else(PoisonError::into_inner);
+                    let (stopped, _) = signal
+                        .wait_timeout_while(stopped, next - now, |stopped| !*stopped)
+                        .unwrap_or_else(PoisonError::into_inner);
+                    if *stopped {
+                        return Ok(());
+                    }
+                }
+            })
+        };
+        Heartbeat {
+            stop,
+            thread: Some(thread),
+        }
+    }
+
+    /// Check whether the thread is still throwing heartbeats.
+    pub fn is_running(&self) -> bool {
+        self.thread
+            .as_ref()
+            .is_some_and(|thread| !thread.is_finished())
+    }
+
+    /// Stop throwing heartbeats, and return the error that stopped the
+    /// thread beforehand, if any.
+    ///
+    /// A panic of the thread, e.g. while writing to the gutter, is
+    /// turned into an error of kind [`ErrorKind::Other`](std::io::ErrorKind::Other),
+    /// carrying the panic message.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```
+    /// use gutters::heartbeat::Heartbeat;
+    /// use std::io::{self, ErrorKind, Write};
+    /// use std::sync::{Arc, Mutex};
+    /// use std::time::Duration;
+    ///
+    /// struct Broken;
+    ///
+    /// impl Write for Broken {
+    ///     fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+    ///         panic!("out of ink");
+    ///     }
+    ///
+    ///     fn flush(&mut self) -> io::Result<()> {
+    ///         Ok(())
+    ///     }
+    /// }
+    ///
+    /// let heartbeat = Heartbeat::spawn(Arc::new(Mutex::new(Broken)), Duration::from_millis(1));
+    /// while heartbeat.is_running() {
+    ///     std::thread::sleep(Duration::from_millis(1));
+    /// }
+    /// let error = heartbeat.stop().unwrap_err();
+    /// assert_eq!(error.kind(), ErrorKind::Other);
+    /// assert!(error.to_string().contains("out of ink"));
+    /// ```
+    pub fn stop(mut self) -> Result<()> {
+        self.join()
+    }
+
+    fn join(&mut self) -> Result<()> {
+        let (stopped, signal) = &*self.stop;
+        *stopped.lock().unwrap_or_else(PoisonError::into_inner) = true;
+        signal.notify_one();
+        match self.thread.take() {
+            Some(thread) => thread.join().unwrap_or_else(|panic| {
+                Err(Error::other(format!(
+                    "heartbeat thread panicked: {}",
+                    panic_message(&*panic)
+                )))
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Heartbeat {
+    fn drop(&mut self) {
+        let _ = self.join();
+    }
+}
+
+/// A gutter keeping track of when the peer was last heard from.
+///
+/// Any byte read counts, be it a heartbeat or data. See [`Heartbeat`].
+#[derive(Debug)]
+pub struct Monitor<G> {
+    gutter: G,
+    liveness: Liveness,
+}
+
+impl<G> Monitor<G> {
+    /// Wrap `gutter`, considering the peer heard from now.
+    pub fn new(gutter: G) -> Self {
+        Self::with_clock(gutter, SystemClock)
+    }
+
+    /// Wrap `gutter`, reading the time from `clock` rather than the
+    /// system clock.
+    pub fn with_clock<C: Clock + 'static>(gutter: G, clock: C) -> Self {
+        let clock: Arc<dyn Clock> = Arc::new(clock);
+        Monitor {
+            gutter,
+            liveness: Liveness {
+                last_seen: Arc::new(Mutex::new(clock.now())),
+                clock,
+            },
+        }
+    }
+
+    /// Return a handle telling whether the peer is alive, for use from
+    /// another thread, e.g. while this one is blocked reading.
+    pub fn liveness(&self) -> Liveness {
+        self.liveness.clone()
+    }
+
+    /// Return when the peer was last heard from.
+    pub fn last_seen(&self) -> Instant {
+        self.liveness.last_seen()
+    }
+
+    /// Check whether the peer was heard from within the last `timeout`.
+    pub fn is_alive(&self, timeout: Duration) -> bool {
+        self.liveness.is_alive(timeout)
+    }
+
+    /// Get a reference to the underlying gutter.
+    pub fn get_ref(&self) -> &G {
+        &self.gutter
+    }
+
+    /// Move the underlying gutter out.
+    pub fn into_inner(self) -> G {
+        self.gutter
+    }
+}
+
+impl<G: Read> Read for Monitor<G> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let len = self.gutter.read(buf)?;
+        if len > 0 {
+            let now = self.liveness.clock.now();
+            *self.liveness.lock() = now;
+        }
+        Ok(len)
+    }
+}
+
+impl<G: Write> Write for Monitor<G> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.gutter.write(buf)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.gutter.flush()
+    }
+}
+
+/// Shared view of when the peer of a [`Monitor`] was last heard from.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```
+/// use gutters::clock::ManualClock;
+/// use gutters::heartbeat::Monitor;
+/// use std::io::Cursor;
+/// use std::time::Duration;
+///
+/// let clock = ManualClock::new();
+/// let monitor = Monitor::with_clock(Cursor::new(Vec::<u8>::new()), clock.clone());
+/// let liveness = monitor.liveness();
+///
+/// clock.advance(Duration::from_secs(5));
+/// assert!(liveness.is_alive(Duration::from_secs(10)));
+/// clock.advance(Duration::from_secs(10));
+/// assert!(!liveness.is_alive(Duration::from_secs(10)));
+/// ```
+#[derive(Debug, Clone)]
+pub struct Liveness {
+    last_seen: Arc<Mutex<Instant>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Liveness {
+    /// Return when the peer was last heard from.
+    pub fn last_seen(&self) -> Instant {
+        *self.lock()
+    }
+
+    /// Check whether the peer was heard from within the last `timeout`.
+    pub fn is_alive(&self, timeout: Duration) -> bool {
+        self.clock.now().saturating_duration_since(self.last_seen()) <= timeout
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Instant> {
+        self.last_seen
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
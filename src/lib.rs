@@ -4,33 +4,33 @@
 //!
 //! # Usage
 //!
-//! ```rust
+//! ```no_run
 //! use gutters::{pick_up, throw, hail, wait, throw_and_wait, pick_up_and_hail};
 //!
 //! // Here we use a TcpStream as a "gutter", but anything implementing
 //! // Read and Write will do.
 //! use std::net::TcpStream;
-//! let mut stream = TcpStream::connect("127.0.0.1:34254");
+//! let mut stream = TcpStream::connect("127.0.0.1:34254")?;
 //! // Of course, here you need a server on the other side.
 //!
 //! // You can "throw" data down the "gutter" with the corresponding
 //! // function. The other side will have to "pick-up" the corresponding
 //! // message (or "log", if you want to pursue the metaphor).
-//! let log = 123.4f64
+//! let log = 123.4f64;
 //! throw(&mut stream, &log)?;
 //!
 //! // You can "throw" as many "logs" as you want, while the other end
 //! // "picks them up".
-//! let log = 567.8f64
+//! let log = 567.8f64;
 //! throw(&mut stream, &log)?;
 //! throw(&mut stream, &log)?;
 //!
 //! // You can also "pick-up logs".
 //! let mut log = 0.0f64;
 //! pick_up(&mut stream, &mut log)?;
-//! println!("{}", log)?;
+//! println!("{}", log);
 //! pick_up(&mut stream, &mut log)?;
-//! println!("{}", log)?;
+//! println!("{}", log);
 //!
 //! // If you need to synchronize with the other end, you can "hail" to
 //! // them. They will have to "wait" for you.
@@ -41,44 +41,472 @@
 //!
 //! // If you want to be synchronized with the other end at all time,
 //! // you may use the `pick_up_and_hail` and `throw_and_wait` variants.
-//! let log = 567.8f64
+//! let log = 567.8f64;
 //! throw_and_wait(&mut stream, &log)?;
 //!
 //! let mut log = 0.0f64;
 //! pick_up_and_hail(&mut stream, &mut log)?;
-//! println!("{}", log)?;
+//! println!("{}", log);
+//! # Ok::<(), std::io::Error>(())
 //! ```
+//!
+//! # Allocations
+//!
+//! [`throw`], [`pick_up`], [`hail`], [`wait`] and their combined variants
+//! never allocate: logs are copied straight between their own memory and
+//! the gutter. They are therefore safe to call from real-time threads,
+//! as long as the gutter itself doesn't allocate or block. The same goes
+//! for [`throw_framed`] and [`pick_up_framed_into`].
+//!
+//! [`pick_up_framed`] and [`pick_up_vec`], on the other hand, allocate
+//! the vector they return, as the length of a frame is only known once
+//! it arrives.
+//!
+//! ```
+//! use gutters::{hail, pick_up, pick_up_framed_into, throw, throw_and_wait, throw_framed, wait};
+//! use std::alloc::{GlobalAlloc, Layout, System};
+//! use std::io::Cursor;
+//! use std::sync::atomic::{AtomicUsize, Ordering};
+//!
+//! struct Counting;
+//! static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+//!
+//! unsafe impl GlobalAlloc for Counting {
+//!     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+//!         ALLOCATIONS.fetch_add(1, Ordering::SeqCst);
+//!         System.alloc(layout)
+//!     }
+//!     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+//!         System.dealloc(ptr, layout)
+//!     }
+//! }
+//!
+//! #[global_allocator]
+//! static GLOBAL: Counting = Counting;
+//!
+//! fn main() -> std::io::Result<()> {
+//!     let mut memory = [0u8; 1024];
+//!     let mut log = [1.5f64; 16];
+//!
+//!     let mut frame = [0.0f64; 32];
+//!
+//!     let before = ALLOCATIONS.load(Ordering::SeqCst);
+//!     let mut gutter = Cursor::new(&mut memory[..]);
+//!     throw(&mut gutter, &log)?;
+//!     hail(&mut gutter)?;
+//!     throw_framed(&mut gutter, &log)?;
+//!     // The zeroed memory after the log stands in for the hail.
+//!     throw_and_wait(&mut gutter, &log)?;
+//!     gutter.set_position(0);
+//!     pick_up(&mut gutter, &mut log)?;
+//!     wait(&mut gutter)?;
+//!     let count = pick_up_framed_into(&mut gutter, &mut frame)?;
+//!     assert_eq!(ALLOCATIONS.load(Ordering::SeqCst), before);
+//!     assert_eq!(frame[..count], log);
+//!     Ok(())
+//! }
+//! ```
+
+#[cfg(unix)]
+pub mod activation;
+pub mod bench;
+pub mod bonded;
+pub mod buffered;
+pub mod capture;
+pub mod chaos;
+pub mod checksum;
+pub mod clock;
+pub mod compressed;
+pub mod config;
+pub mod datagram;
+pub mod dedup;
+pub mod drain;
+pub mod endian;
+pub mod failover;
+#[cfg(target_os = "linux")]
+pub mod handover;
+pub mod health;
+pub mod heartbeat;
+#[cfg(any(unix, windows))]
+pub mod local;
+pub mod manifold;
+pub mod outbox;
+pub mod proxy;
+pub mod receiver;
+pub mod registry;
+pub mod resilient;
+pub mod schema;
+pub mod shaped;
+pub mod signed;
+pub mod spin;
+pub mod split;
+pub mod stateful;
+pub mod tap;
+pub mod testing;
+pub mod timeout;
+pub mod trace;
+pub mod upgrade;
+
+use std::io::{BufReader, Error, ErrorKind, Read, Result, Write};
 
-use std::io::{Read, Result, Write};
+pub use buffered::Gutter;
 
-fn as_u8_slice_mut<T>(v: &mut T) -> &mut [u8] {
+/// Derive [`Log`] for a plain old data struct, checking that it is
+/// `#[repr(C)]`, has no padding and only contains logs.
+///
+/// This requires the `derive` feature.
+#[cfg(feature = "derive")]
+pub use gutters_derive::GutterLog;
+
+/// Types that can be thrown down a gutter as their raw bytes.
+///
+/// This is implemented for integer and floating point primitives, and
+/// for arrays of logs. Plain old data structs can implement it too.
+///
+/// # Safety
+///
+/// Implementing this trait asserts that:
+///
+/// - the type has no padding bytes, so that throwing it doesn't leak
+///   uninitialized memory;
+/// - any `size_of::<Self>()` bytes make a valid value, so that picking
+///   it up can't produce an invalid one. This excludes `bool`, `char`,
+///   enums, references and pointers;
+/// - the type has a defined layout, e.g. `#[repr(C)]`, so that both
+///   peers agree on it.
+///
+/// In practice, this means a `#[repr(C)]` struct, without padding, whose
+/// fields are all logs themselves. With the `derive` feature,
+/// `#[derive(GutterLog)]` checks all of this and implements the trait.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```
+/// use gutters::Log;
+///
+/// #[derive(Default)]
+/// #[repr(C)]
+/// struct Sample {
+///     timestamp: u64,
+///     channels: [f32; 4],
+/// }
+///
+/// // SAFETY: `Sample` is `repr(C)`, its fields are logs, and 8 + 16
+/// // bytes leave no room for padding.
+/// unsafe impl Log for Sample {}
+///
+/// let sample = gutters::testing::roundtrip(&Sample { timestamp: 7, ..Sample::default() })?;
+/// assert_eq!(sample.timestamp, 7);
+/// # Ok::<(), std::io::Error>(())
+/// ```
+///
+/// Types that may hold invalid bit patterns can't be picked up:
+///
+/// ```compile_fail
+/// let mut flag = false;
+/// gutters::pick_up(&mut std::io::empty(), &mut flag)?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub unsafe trait Log {}
+
+macro_rules! impl_log {
+    ($($t:ty),*) => {
+        $(
+            unsafe impl Log for $t {}
+        )*
+    };
+}
+
+impl_log!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64);
+
+unsafe impl<T: Log, const N: usize> Log for [T; N] {}
+
+fn as_u8_slice_mut<T: Log>(v: &mut T) -> &mut [u8] {
     unsafe { std::slice::from_raw_parts_mut((v as *mut T) as *mut u8, std::mem::size_of::<T>()) }
 }
 
-fn as_u8_slice<T>(v: &T) -> &[u8] {
+fn as_u8_slice<T: Log>(v: &T) -> &[u8] {
     unsafe { std::slice::from_raw_parts((v as *const T) as *const u8, std::mem::size_of::<T>()) }
 }
 
+fn slice_as_u8_slice_mut<T: Log>(v: &mut [T]) -> &mut [u8] {
+    unsafe { std::slice::from_raw_parts_mut(v.as_mut_ptr() as *mut u8, std::mem::size_of_val(v)) }
+}
+
+fn slice_as_u8_slice<T: Log>(v: &[T]) -> &[u8] {
+    unsafe { std::slice::from_raw_parts(v.as_ptr() as *const u8, std::mem::size_of_val(v)) }
+}
+
+/// Seeded pseudo-random numbers for the simulation wrappers, from an
+/// xorshift generator.
+#[derive(Debug, Clone)]
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        // Xorshift gets stuck on zero.
+        Rng(seed | 1)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// Return a random number between 0 and 1.
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
 /// Read a message of type `T` from the `gutter`.
 ///
 /// This function is blocking.
 ///
+/// This function fails with a [`Closed`] error if the peer closed the
+/// gutter before sending the message.
+///
 /// This function doesn't change endianness, so it must be the
 /// same between the peers.
 ///
+/// Each call reads from the `gutter` directly. When picking up many
+/// small logs, wrap it in a [`BufReader`] so that several of them are
+/// served from a single read, see [`buffered_frames`].
+///
 /// # Examples
 ///
 /// Basic usage:
 ///
 /// ```no_run
+/// # use gutters::pick_up;
 /// use std::net::TcpStream;
-/// let stream = TcpStream::connect("127.0.0.1:34567")
+/// let mut stream = TcpStream::connect("127.0.0.1:34567")?;
 ///
-/// let mut data = 0.0f64
+/// let mut data = 0.0f64;
 /// pick_up(&mut stream, &mut data)?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn pick_up<G: Read, T: Log>(gutter: &mut G, buffer: &mut T) -> Result<()> {
+    read_exact_or_closed(gutter, as_u8_slice_mut(buffer))
+}
+
+/// Iterate over the messages of type `T` picked up from the `gutter`,
+/// until the peer closes it.
+///
+/// Each item is picked up with [`pick_up`]. The iterator ends when the
+/// peer closes the gutter in between messages, or after yielding any
+/// other error.
+///
+/// This function doesn't change endianness, so it must be the
+/// same between the peers.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```
+/// use gutters::{logs, throw};
+/// use std::io::Cursor;
+///
+/// let mut gutter = Cursor::new(Vec::new());
+/// for i in 0..3 {
+///     throw(&mut gutter, &(i as f64))?;
+/// }
+///
+/// gutter.set_position(0);
+/// let mut sum = 0.0;
+/// for log in logs::<f64, _>(&mut gutter) {
+///     sum += log?;
+/// }
+/// assert_eq!(sum, 3.0);
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn logs<T: Log + Default, G: Read>(gutter: G) -> Logs<T, G> {
+    Logs {
+        gutter: Some(gutter),
+        _log: std::marker::PhantomData,
+    }
+}
+
+/// Iterator over the messages picked up from a gutter.
+///
+/// This is created by [`logs`].
+#[derive(Debug)]
+pub struct Logs<T, G> {
+    gutter: Option<G>,
+    _log: std::marker::PhantomData<fn() -> T>,
+}
+
+impl<T, G> Logs<T, G> {
+    /// Move the gutter out, unless the iterator has ended.
+    pub fn into_inner(self) -> Option<G> {
+        self.gutter
+    }
+}
+
+impl<T: Log + Default, G: Read> Iterator for Logs<T, G> {
+    type Item = Result<T>;
+
+    fn next(&mut self) -> Option<Result<T>> {
+        let gutter = self.gutter.as_mut()?;
+        let mut log = T::default();
+        match pick_up(gutter, &mut log) {
+            Ok(()) => Some(Ok(log)),
+            Err(error) => {
+                self.gutter = None;
+                if Closed::is(&error) {
+                    None
+                } else {
+                    Some(Err(error))
+                }
+            }
+        }
+    }
+}
+
+impl<T: Log + Default, G: Read> std::iter::FusedIterator for Logs<T, G> {}
+
+/// Like [`Read::read_exact`], but fail with a [`Closed`] error if the
+/// gutter is closed before the first byte.
+pub(crate) fn read_exact_or_closed<G: Read>(gutter: &mut G, buf: &mut [u8]) -> Result<()> {
+    if buf.is_empty() {
+        return Ok(());
+    }
+    loop {
+        match gutter.read(buf) {
+            Ok(0) => return Err(Error::new(ErrorKind::UnexpectedEof, Closed)),
+            Ok(n) => return gutter.read_exact(&mut buf[n..]),
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Count the messages of type `T` that `reader` can serve without
+/// reading from the underlying gutter.
+///
+/// Hot loops can keep calling [`pick_up`] while this is non-zero, and
+/// only then go back to other work. Use [`BufReader::with_capacity`] to
+/// configure how many bytes are pulled from the gutter at once.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// # use gutters::{buffered_frames, pick_up};
+/// use std::io::BufReader;
+/// use std::net::TcpStream;
+/// let stream = TcpStream::connect("127.0.0.1:34567")?;
+/// let mut reader = BufReader::with_capacity(64 * 1024, stream);
+///
+/// let mut data = 0.0f64;
+/// loop {
+///     pick_up(&mut reader, &mut data)?;
+///     while buffered_frames::<f64, _>(&reader) > 0 {
+///         pick_up(&mut reader, &mut data)?;
+///     }
+/// }
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn buffered_frames<T, R>(reader: &BufReader<R>) -> usize {
+    match std::mem::size_of::<T>() {
+        0 => usize::MAX,
+        size => reader.buffer().len() / size,
+    }
+}
+
+/// Read up to `max` messages of type `T` from `reader`, and append
+/// them to `logs`.
+///
+/// This function blocks until at least one message is available, then
+/// also picks up every complete message already buffered by `reader`
+/// (see [`buffered_frames`]), without reading from the gutter again.
+/// It returns the number of messages appended.
+///
+/// This function doesn't change endianness, so it must be the
+/// same between the peers.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// # use gutters::pick_up_many_into;
+/// use std::io::BufReader;
+/// use std::net::TcpStream;
+/// let stream = TcpStream::connect("127.0.0.1:34567")?;
+/// let mut reader = BufReader::new(stream);
+///
+/// let mut batch: Vec<f64> = Vec::with_capacity(256);
+/// loop {
+///     batch.clear();
+///     pick_up_many_into(&mut reader, &mut batch, 256)?;
+///     println!("got {} logs", batch.len());
+/// }
+/// # Ok::<(), std::io::Error>(())
 /// ```
-pub fn pick_up<G: Read + Write, T>(gutter: &mut G, buffer: &mut T) -> Result<()> {
-    gutter.read_exact(as_u8_slice_mut(buffer))
+pub fn pick_up_many_into<T: Log + Default, R: Read>(
+    reader: &mut BufReader<R>,
+    logs: &mut Vec<T>,
+    max: usize,
+) -> Result<usize> {
+    let mut count = 0;
+    while count < max && (count == 0 || buffered_frames::<T, _>(reader) > 0) {
+        let mut log = T::default();
+        pick_up(reader, &mut log)?;
+        logs.push(log);
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Read exactly `buffer.len()` bytes from the `gutter` into `buffer`,
+/// which must start at a multiple of `alignment`.
+///
+/// This function is blocking.
+///
+/// The bytes land directly in `buffer`, without any intermediate copy,
+/// so it can be memory registered for DMA or pinned for GPU transfers.
+/// This function fails with [`ErrorKind::InvalidInput`],
+/// without reading anything, if `alignment` is not a power of two or if
+/// `buffer` is not aligned to it.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// # use gutters::pick_up_into_aligned;
+/// use std::net::TcpStream;
+/// let mut stream = TcpStream::connect("127.0.0.1:34567")?;
+///
+/// #[repr(C, align(4096))]
+/// struct Page([u8; 4096]);
+///
+/// let mut page = Box::new(Page([0; 4096]));
+/// pick_up_into_aligned(&mut stream, &mut page.0, 4096)?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn pick_up_into_aligned<G: Read>(
+    gutter: &mut G,
+    buffer: &mut [u8],
+    alignment: usize,
+) -> Result<()> {
+    if !alignment.is_power_of_two() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "alignment is not a power of two",
+        ));
+    }
+    if !(buffer.as_ptr() as usize).is_multiple_of(alignment) {
+        return Err(Error::new(ErrorKind::InvalidInput, "buffer is not aligned"));
+    }
+    gutter.read_exact(buffer)
 }
 
 /// Send a message of type `T` to the `gutter`.
@@ -93,12 +521,14 @@ pub fn pick_up<G: Read + Write, T>(gutter: &mut G, buffer: &mut T) -> Result<()>
 /// Basic usage:
 ///
 /// ```no_run
+/// # use gutters::throw;
 /// use std::net::TcpStream;
-/// let stream = TcpStream::connect("127.0.0.1:34567")
+/// let mut stream = TcpStream::connect("127.0.0.1:34567")?;
 ///
 /// throw(&mut stream, &64.0)?;
+/// # Ok::<(), std::io::Error>(())
 /// ```
-pub fn throw<G: Read + Write, T>(gutter: &mut G, buffer: &T) -> Result<()> {
+pub fn throw<G: Read + Write, T: Log>(gutter: &mut G, buffer: &T) -> Result<()> {
     gutter.write_all(as_u8_slice(buffer))
 }
 
@@ -113,10 +543,12 @@ pub fn throw<G: Read + Write, T>(gutter: &mut G, buffer: &T) -> Result<()> {
 /// Basic usage:
 ///
 /// ```no_run
+/// # use gutters::hail;
 /// use std::net::TcpStream;
-/// let stream = TcpStream::connect("127.0.0.1:34567")
+/// let mut stream = TcpStream::connect("127.0.0.1:34567")?;
 ///
 /// hail(&mut stream)?;
+/// # Ok::<(), std::io::Error>(())
 /// ```
 pub fn hail<G: Write>(gutter: &mut G) -> Result<()> {
     gutter.write_all(b"\n")
@@ -133,15 +565,136 @@ pub fn hail<G: Write>(gutter: &mut G) -> Result<()> {
 /// Basic usage:
 ///
 /// ```no_run
+/// # use gutters::wait;
 /// use std::net::TcpStream;
-/// let stream = TcpStream::connect("127.0.0.1:34567")
+/// let mut stream = TcpStream::connect("127.0.0.1:34567")?;
 ///
 /// wait(&mut stream)?;
+/// # Ok::<(), std::io::Error>(())
 /// ```
 pub fn wait<G: Read>(gutter: &mut G) -> Result<()> {
     gutter.read_exact(&mut [0u8])
 }
 
+/// Acknowledgments with a meaning, for [`hail_with`] and [`wait_for`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum Signal {
+    /// Plain acknowledgment, as sent by [`hail`].
+    Ack = b'\n',
+    /// The peer is ready to proceed.
+    Ready = b'R',
+    /// The peer is done.
+    Done = b'D',
+    /// The peer hit an error.
+    Error = b'E',
+}
+
+impl From<Signal> for u8 {
+    fn from(signal: Signal) -> u8 {
+        signal as u8
+    }
+}
+
+impl TryFrom<u8> for Signal {
+    type Error = u8;
+
+    fn try_from(token: u8) -> std::result::Result<Signal, u8> {
+        match token {
+            b'\n' => Ok(Signal::Ack),
+            b'R' => Ok(Signal::Ready),
+            b'D' => Ok(Signal::Done),
+            b'E' => Ok(Signal::Error),
+            _ => Err(token),
+        }
+    }
+}
+
+/// Error of an acknowledgment that isn't the one waited for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnexpectedSignal {
+    /// Token waited for.
+    pub expected: u8,
+    /// Token received instead.
+    pub received: u8,
+}
+
+impl std::fmt::Display for UnexpectedSignal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "expected signal {:?}, received {:?}",
+            self.expected as char, self.received as char
+        )
+    }
+}
+
+impl std::error::Error for UnexpectedSignal {}
+
+/// Send an acknowledgment carrying `token` to the `gutter`.
+///
+/// This function is blocking.
+///
+/// `token` is either a [`Signal`] or any byte the peers agree on.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use gutters::{hail_with, Signal};
+/// use std::net::TcpStream;
+/// let mut stream = TcpStream::connect("127.0.0.1:34567")?;
+///
+/// hail_with(&mut stream, Signal::Done)?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn hail_with<G: Write>(gutter: &mut G, token: impl Into<u8>) -> Result<()> {
+    gutter.write_all(&[token.into()])
+}
+
+/// Wait for an acknowledgment carrying `token` from the `gutter`.
+///
+/// This function is blocking.
+///
+/// This function fails with [`ErrorKind::InvalidData`] if another token
+/// is received. The inner error is then an [`UnexpectedSignal`].
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```
+/// use gutters::{hail_with, wait_for, Signal, UnexpectedSignal};
+/// use std::io::Cursor;
+///
+/// let mut gutter = Cursor::new(Vec::new());
+/// hail_with(&mut gutter, Signal::Ready)?;
+/// hail_with(&mut gutter, Signal::Error)?;
+///
+/// gutter.set_position(0);
+/// wait_for(&mut gutter, Signal::Ready)?;
+/// let error = wait_for(&mut gutter, Signal::Done).unwrap_err();
+/// let signal = error.get_ref().unwrap().downcast_ref::<UnexpectedSignal>().unwrap();
+/// assert_eq!(Signal::try_from(signal.received), Ok(Signal::Error));
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn wait_for<G: Read>(gutter: &mut G, token: impl Into<u8>) -> Result<()> {
+    let expected = token.into();
+    let mut received = [0u8];
+    gutter.read_exact(&mut received)?;
+    if received[0] != expected {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            UnexpectedSignal {
+                expected,
+                received: received[0],
+            },
+        ));
+    }
+    Ok(())
+}
+
 /// Read a message of type `T` from the `gutter`, and send an
 /// acknowledgement.
 ///
@@ -157,13 +710,15 @@ pub fn wait<G: Read>(gutter: &mut G) -> Result<()> {
 /// Basic usage:
 ///
 /// ```no_run
+/// # use gutters::pick_up_and_hail;
 /// use std::net::TcpStream;
-/// let stream = TcpStream::connect("127.0.0.1:34567")
+/// let mut stream = TcpStream::connect("127.0.0.1:34567")?;
 ///
-/// let mut data = 0.0f64
+/// let mut data = 0.0f64;
 /// pick_up_and_hail(&mut stream, &mut data)?;
+/// # Ok::<(), std::io::Error>(())
 /// ```
-pub fn pick_up_and_hail<G: Read + Write, T>(gutter: &mut G, buffer: &mut T) -> Result<()> {
+pub fn pick_up_and_hail<G: Read + Write, T: Log>(gutter: &mut G, buffer: &mut T) -> Result<()> {
     pick_up(gutter, buffer)?;
     hail(gutter)
 }
@@ -183,12 +738,471 @@ pub fn pick_up_and_hail<G: Read + Write, T>(gutter: &mut G, buffer: &mut T) -> R
 /// Basic usage:
 ///
 /// ```no_run
+/// # use gutters::throw;
 /// use std::net::TcpStream;
-/// let stream = TcpStream::connect("127.0.0.1:34567")
+/// let mut stream = TcpStream::connect("127.0.0.1:34567")?;
 ///
 /// throw(&mut stream, &64.0)?;
+/// # Ok::<(), std::io::Error>(())
 /// ```
-pub fn throw_and_wait<G: Read + Write, T>(gutter: &mut G, buffer: &T) -> Result<()> {
+pub fn throw_and_wait<G: Read + Write, T: Log>(gutter: &mut G, buffer: &T) -> Result<()> {
     throw(gutter, buffer)?;
     wait(gutter)
 }
+
+/// Send a `request` of type `Req` to the `gutter`, and read the reply of
+/// type `Resp` into `response`.
+///
+/// The peer must reply with [`answer`].
+///
+/// This function is blocking.
+///
+/// This function doesn't change endianness, so it must be the
+/// same between the peers.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```
+/// use gutters::{answer, ask};
+/// use std::net::{TcpListener, TcpStream};
+/// use std::thread;
+///
+/// let listener = TcpListener::bind("127.0.0.1:0")?;
+/// let mut stream = TcpStream::connect(listener.local_addr()?)?;
+/// let server = thread::spawn(move || -> std::io::Result<()> {
+///     let (mut stream, _) = listener.accept()?;
+///     for _ in 0..2 {
+///         answer(&mut stream, |operands: &[f64; 2]| operands[0] + operands[1])?;
+///     }
+///     Ok(())
+/// });
+///
+/// let mut sum = 0.0f64;
+/// ask(&mut stream, &[1.0f64, 2.0], &mut sum)?;
+/// assert_eq!(sum, 3.0);
+/// ask(&mut stream, &[4.0f64, 8.0], &mut sum)?;
+/// assert_eq!(sum, 12.0);
+/// server.join().unwrap()?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn ask<G: Read + Write, Req: Log, Resp: Log>(
+    gutter: &mut G,
+    request: &Req,
+    response: &mut Resp,
+) -> Result<()> {
+    gutter.write_all(as_u8_slice(request))?;
+    gutter.flush()?;
+    pick_up(gutter, response)
+}
+
+/// Read a request of type `Req` from the `gutter`, and send back the
+/// reply of type `Resp` computed by `f`.
+///
+/// This answers a single request sent with [`ask`], so servers
+/// typically call it in a loop.
+///
+/// This function is blocking.
+///
+/// This function doesn't change endianness, so it must be the
+/// same between the peers.
+pub fn answer<G, Req, Resp, F>(gutter: &mut G, f: F) -> Result<()>
+where
+    G: Read + Write,
+    Req: Log + Default,
+    Resp: Log,
+    F: FnOnce(&Req) -> Resp,
+{
+    let mut request = Req::default();
+    pick_up(gutter, &mut request)?;
+    gutter.write_all(as_u8_slice(&f(&request)))?;
+    gutter.flush()
+}
+
+/// Send all the messages of type `T` in `logs` to the `gutter`, in a
+/// single write.
+///
+/// This function is blocking.
+///
+/// The peer must know how many messages to expect, and pick them up
+/// with [`pick_up_slice`], or one by one with [`pick_up`]. Use
+/// [`throw_framed`] to send the count along.
+///
+/// This function doesn't change endianness, so it must be the
+/// same between the peers.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// # use gutters::throw_slice;
+/// use std::net::TcpStream;
+/// let mut stream = TcpStream::connect("127.0.0.1:34567")?;
+///
+/// let samples = vec![0.0f64; 100_000];
+/// throw_slice(&mut stream, &samples)?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn throw_slice<G: Write, T: Log>(gutter: &mut G, logs: &[T]) -> Result<()> {
+    gutter.write_all(slice_as_u8_slice(logs))
+}
+
+/// Read `logs.len()` messages of type `T` from the `gutter` into `logs`,
+/// in a single read where possible.
+///
+/// This function is blocking.
+///
+/// Like [`pick_up`], this function fails with a [`Closed`] error if the
+/// peer closed the gutter before the first log.
+///
+/// This function doesn't change endianness, so it must be the
+/// same between the peers.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```
+/// # use gutters::{pick_up_slice, throw_slice, Closed};
+/// use std::io::Cursor;
+/// let mut gutter = Cursor::new(Vec::new());
+/// throw_slice(&mut gutter, &[1.0f64, 2.0, 3.0])?;
+///
+/// gutter.set_position(0);
+/// let mut samples = [0.0f64; 3];
+/// pick_up_slice(&mut gutter, &mut samples)?;
+/// assert_eq!(samples, [1.0, 2.0, 3.0]);
+/// assert!(Closed::is(&pick_up_slice(&mut gutter, &mut samples).unwrap_err()));
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn pick_up_slice<G: Read, T: Log>(gutter: &mut G, logs: &mut [T]) -> Result<()> {
+    read_exact_or_closed(gutter, slice_as_u8_slice_mut(logs))
+}
+
+/// Error of a peer that closed the gutter in between logs, or said
+/// [`farewell`].
+///
+/// Picking up functions fail with [`ErrorKind::UnexpectedEof`] either
+/// way, and this error inside, so that receiving loops can tell a clean
+/// shutdown from a log cut short.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```
+/// use gutters::{pick_up, throw, Closed};
+/// use std::io::Cursor;
+///
+/// let mut gutter = Cursor::new(Vec::new());
+/// for i in 0..3u32 {
+///     throw(&mut gutter, &i)?;
+/// }
+///
+/// gutter.set_position(0);
+/// let mut log = 0u32;
+/// loop {
+///     match pick_up(&mut gutter, &mut log) {
+///         Ok(()) => println!("{}", log),
+///         Err(error) if Closed::is(&error) => break,
+///         Err(error) => return Err(error),
+///     }
+/// }
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Closed;
+
+impl Closed {
+    /// Check whether `error` reports a closed gutter.
+    pub fn is(error: &Error) -> bool {
+        error.get_ref().is_some_and(|inner| inner.is::<Closed>())
+    }
+}
+
+impl std::fmt::Display for Closed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("peer closed the gutter")
+    }
+}
+
+impl std::error::Error for Closed {}
+
+/// Length prefix of a [`farewell`], which no frame can have.
+const FAREWELL: u32 = u32::MAX;
+
+/// Length prefix of a [heartbeat](heartbeat::beat), which no frame can
+/// have either.
+pub(crate) const HEARTBEAT: u32 = u32::MAX - 1;
+
+/// Tell the peer that no more frames are coming.
+///
+/// The peer's next [`pick_up_framed`] or [`pick_up_vec`] then fails with
+/// a [`Closed`] error. For logs of a fixed size, which have no room for a
+/// farewell, shut the gutter down for writing instead, e.g. with
+/// [`TcpStream::shutdown`](std::net::TcpStream::shutdown).
+///
+/// This function is blocking.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```
+/// use gutters::{farewell, pick_up_framed, throw_framed, Closed};
+/// use std::io::Cursor;
+///
+/// let mut gutter = Cursor::new(Vec::new());
+/// throw_framed(&mut gutter, b"last words")?;
+/// farewell(&mut gutter)?;
+///
+/// gutter.set_position(0);
+/// assert_eq!(pick_up_framed(&mut gutter)?, b"last words");
+/// assert!(Closed::is(&pick_up_framed(&mut gutter).unwrap_err()));
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn farewell<G: Write>(gutter: &mut G) -> Result<()> {
+    gutter.write_all(&FAREWELL.to_ne_bytes())?;
+    gutter.flush()
+}
+
+/// Default maximum payload size accepted by [`pick_up_framed`] and
+/// [`pick_up_vec`], 16 MiB.
+pub const DEFAULT_MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+
+/// Send a variable-sized `payload` to the `gutter`, preceded by its
+/// length in bytes as a `u32`.
+///
+/// This function is blocking.
+///
+/// Payloads of bytes are picked up with [`pick_up_framed`], and other
+/// logs with [`pick_up_vec`].
+///
+/// This function fails with [`ErrorKind::InvalidInput`], without
+/// writing anything, if `payload` is `u32::MAX - 1` bytes or longer.
+///
+/// This function doesn't change endianness, so it must be the
+/// same between the peers.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// # use gutters::throw_framed;
+/// use std::net::TcpStream;
+/// let mut stream = TcpStream::connect("127.0.0.1:34567")?;
+///
+/// throw_framed(&mut stream, "hello, sewer".as_bytes())?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn throw_framed<G: Write, T: Log>(gutter: &mut G, payload: &[T]) -> Result<()> {
+    let payload = slice_as_u8_slice(payload);
+    let len = u32::try_from(payload.len())
+        .ok()
+        .filter(|&len| len < HEARTBEAT)
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "frame is too long"))?;
+    gutter.write_all(&len.to_ne_bytes())?;
+    gutter.write_all(payload)
+}
+
+/// Read a variable-sized payload sent by [`throw_framed`] from the
+/// `gutter`.
+///
+/// This function is blocking.
+///
+/// This function fails with [`ErrorKind::InvalidData`] if the payload
+/// is longer than [`DEFAULT_MAX_FRAME_LEN`], see
+/// [`pick_up_framed_with_max`], and with a [`Closed`] error if the peer
+/// said [`farewell`] or closed the gutter. [Heartbeats](heartbeat) are
+/// skipped.
+///
+/// This function doesn't change endianness, so it must be the
+/// same between the peers.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```
+/// # use gutters::{pick_up_framed, throw_framed};
+/// use std::io::Cursor;
+/// let mut gutter = Cursor::new(Vec::new());
+/// throw_framed(&mut gutter, b"hello, sewer")?;
+///
+/// gutter.set_position(0);
+/// assert_eq!(pick_up_framed(&mut gutter)?, b"hello, sewer");
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn pick_up_framed<G: Read>(gutter: &mut G) -> Result<Vec<u8>> {
+    pick_up_framed_with_max(gutter, DEFAULT_MAX_FRAME_LEN)
+}
+
+/// Read a variable-sized payload sent by [`throw_framed`] from the
+/// `gutter`, accepting at most `max_len` bytes.
+///
+/// This function is blocking.
+///
+/// This function fails with [`ErrorKind::InvalidData`] if the payload
+/// is longer than `max_len`. The payload is then left unread, so the
+/// `gutter` can't be used any further. Memory is allocated as the
+/// payload arrives, so a peer can't make this function allocate much
+/// more than it actually sends.
+///
+/// This function doesn't change endianness, so it must be the
+/// same between the peers.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```
+/// # use gutters::{pick_up_framed_with_max, throw_framed};
+/// use std::io::{Cursor, ErrorKind};
+/// let mut gutter = Cursor::new(Vec::new());
+/// throw_framed(&mut gutter, &[0; 100])?;
+///
+/// gutter.set_position(0);
+/// let error = pick_up_framed_with_max(&mut gutter, 64).unwrap_err();
+/// assert_eq!(error.kind(), ErrorKind::InvalidData);
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn pick_up_framed_with_max<G: Read>(gutter: &mut G, max_len: usize) -> Result<Vec<u8>> {
+    pick_up_vec_with_max(gutter, max_len)
+}
+
+/// Read variable-sized messages of type `T` sent by [`throw_framed`]
+/// from the `gutter` into `logs`, and return how many were read.
+///
+/// This function is blocking.
+///
+/// Unlike [`pick_up_vec`], this function never allocates. It fails with
+/// [`ErrorKind::InvalidData`] if the payload doesn't fit in `logs`, in
+/// which case it is left unread, so the `gutter` can't be used any
+/// further, or if its length is not a multiple of the size of `T`.
+/// [Heartbeats](heartbeat) are skipped.
+///
+/// This function doesn't change endianness, so it must be the
+/// same between the peers.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```
+/// # use gutters::{pick_up_framed_into, throw_framed};
+/// use std::io::Cursor;
+/// let mut gutter = Cursor::new(Vec::new());
+/// throw_framed(&mut gutter, &[1.0f64, 2.0, 3.0])?;
+///
+/// gutter.set_position(0);
+/// let mut samples = [0.0f64; 8];
+/// let count = pick_up_framed_into(&mut gutter, &mut samples)?;
+/// assert_eq!(samples[..count], [1.0, 2.0, 3.0]);
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn pick_up_framed_into<G: Read, T: Log>(gutter: &mut G, logs: &mut [T]) -> Result<usize> {
+    let bytes = slice_as_u8_slice_mut(logs);
+    let len = pick_up_frame_len(gutter, bytes.len())?;
+    let size = std::mem::size_of::<T>();
+    if len == 0 {
+        return Ok(0);
+    }
+    if size == 0 || !len.is_multiple_of(size) {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "frame doesn't hold a whole number of logs",
+        ));
+    }
+    gutter.read_exact(&mut bytes[..len])?;
+    Ok(len / size)
+}
+
+/// Read variable-sized messages of type `T` sent by [`throw_framed`]
+/// from the `gutter`.
+///
+/// This function is blocking.
+///
+/// This function fails with [`ErrorKind::InvalidData`] if the payload
+/// is longer than [`DEFAULT_MAX_FRAME_LEN`] bytes, see
+/// [`pick_up_vec_with_max`], or if its length is not a multiple of the
+/// size of `T`. [Heartbeats](heartbeat) are skipped.
+///
+/// This function doesn't change endianness, so it must be the
+/// same between the peers.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```
+/// # use gutters::{pick_up_vec, throw_framed};
+/// use std::io::Cursor;
+/// let mut gutter = Cursor::new(Vec::new());
+/// throw_framed(&mut gutter, &[1.0f64, 2.0, 3.0])?;
+///
+/// gutter.set_position(0);
+/// let samples: Vec<f64> = pick_up_vec(&mut gutter)?;
+/// assert_eq!(samples, [1.0, 2.0, 3.0]);
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn pick_up_vec<G: Read, T: Log>(gutter: &mut G) -> Result<Vec<T>> {
+    pick_up_vec_with_max(gutter, DEFAULT_MAX_FRAME_LEN)
+}
+
+/// Read variable-sized messages of type `T` sent by [`throw_framed`]
+/// from the `gutter`, accepting at most `max_len` bytes.
+///
+/// This function is blocking.
+///
+/// See [`pick_up_framed_with_max`] and [`pick_up_vec`].
+pub fn pick_up_vec_with_max<G: Read, T: Log>(gutter: &mut G, max_len: usize) -> Result<Vec<T>> {
+    let len = pick_up_frame_len(gutter, max_len)?;
+    let size = std::mem::size_of::<T>();
+    if len != 0 && (size == 0 || !len.is_multiple_of(size)) {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("frame of {} bytes doesn't hold logs of {} bytes", len, size),
+        ));
+    }
+    let count = if len == 0 { 0 } else { len / size };
+
+    // Grow the buffer as the payload arrives, rather than trusting the
+    // announced length up front.
+    const CHUNK_LEN: usize = 64 * 1024;
+    let mut logs = Vec::new();
+    while logs.len() < count {
+        let start = logs.len();
+        let end = count.min(start + (CHUNK_LEN / size).max(1));
+        // SAFETY: logs are valid for any bytes, including zeroes.
+        logs.resize_with(end, || unsafe { std::mem::zeroed() });
+        gutter.read_exact(slice_as_u8_slice_mut(&mut logs[start..]))?;
+    }
+    Ok(logs)
+}
+
+/// Read the length prefix of the next frame, skipping heartbeats, and
+/// check that it is at most `max_len` bytes.
+pub(crate) fn pick_up_frame_len<G: Read>(gutter: &mut G, max_len: usize) -> Result<usize> {
+    let mut len = [0u8; 4];
+    let len = loop {
+        read_exact_or_closed(gutter, &mut len)?;
+        match u32::from_ne_bytes(len) {
+            HEARTBEAT => continue,
+            len => break len,
+        }
+    };
+    if len == FAREWELL {
+        return Err(Error::new(ErrorKind::UnexpectedEof, Closed));
+    }
+    let len = len as usize;
+    if len > max_len {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("frame of {} bytes exceeds the {} bytes limit", len, max_len),
+        ));
+    }
+    Ok(len)
+}
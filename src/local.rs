@@ -0,0 +1,312 @@
+//! Gutters between processes of the same machine.
+//!
+//! [`connect`] and [`listen`] hide the platform-specific boilerplate of
+//! local interprocess communication: they use Unix domain sockets on
+//! Unix, and named pipes on Windows, behind the same API.
+//!
+//! On Unix, the path is that of the socket file. [`listen`] removes a
+//! stale socket file left by a listener that didn't exit cleanly, and
+//! the [`LocalListener`] removes its socket file when dropped. On
+//! Windows, the path is the name of the pipe, prefixed with `\\.\pipe\`
+//! unless it already is.
+//!
+//! On Unix, a listener can also be made out of a [`UnixListener`], e.g.
+//! one passed by [socket activation](crate::activation), in which case
+//! its socket file is left alone.
+
+use std::io::{Read, Result, Write};
+use std::path::Path;
+
+use crate::split::TryClone;
+
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::PathBuf;
+
+#[cfg(windows)]
+use std::ffi::OsString;
+#[cfg(windows)]
+use std::fs::{File, OpenOptions};
+#[cfg(windows)]
+use std::io::Error;
+#[cfg(windows)]
+use std::sync::{Mutex, PoisonError};
+
+/// A connection between two local processes.
+#[derive(Debug)]
+pub struct LocalStream {
+    #[cfg(unix)]
+    inner: UnixStream,
+    #[cfg(windows)]
+    inner: File,
+}
+
+/// A listener for local connections.
+#[derive(Debug)]
+pub struct LocalListener {
+    #[cfg(unix)]
+    inner: UnixListener,
+    #[cfg(unix)]
+    path: Option<PathBuf>,
+    #[cfg(windows)]
+    name: Vec<u16>,
+    #[cfg(windows)]
+    pending: Mutex<File>,
+}
+
+/// Connect to the local listener at `path`.
+///
+/// This function is blocking.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```
+/// use gutters::{local, pick_up, throw};
+/// use std::thread;
+///
+/// let path = std::env::temp_dir().join("gutters-local-doctest");
+/// let listener = local::listen(&path)?;
+/// let client = thread::spawn(move || -> std::io::Result<f64> {
+///     let mut stream = local::connect(&path)?;
+///     throw(&mut stream, &64.0f64)?;
+///     let mut answer = 0.0f64;
+///     pick_up(&mut stream, &mut answer)?;
+///     Ok(answer)
+/// });
+///
+/// let mut stream = listener.accept()?;
+/// let mut data = 0.0f64;
+/// pick_up(&mut stream, &mut data)?;
+/// throw(&mut stream, &(data * 2.0))?;
+/// assert_eq!(client.join().unwrap()?, 128.0);
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn connect<P: AsRef<Path>>(path: P) -> Result<LocalStream> {
+    #[cfg(unix)]
+    {
+        UnixStream::connect(path).map(|inner| LocalStream { inner })
+    }
+    #[cfg(windows)]
+    {
+        windows::connect(path.as_ref())
+    }
+}
+
+/// Listen for local connections at `path`.
+///
+/// See [`connect`].
+pub fn listen<P: AsRef<Path>>(path: P) -> Result<LocalListener> {
+    #[cfg(unix)]
+    {
+        let path = path.as_ref();
+        let inner = match UnixListener::bind(path) {
+            Ok(inner) => inner,
+            // A socket file nobody listens on is left over from a
+            // previous listener.
+            Err(e)
+                if e.kind() == std::io::ErrorKind::AddrInUse
+                    && UnixStream::connect(path).is_err() =>
+            {
+                std::fs::remove_file(path)?;
+                UnixListener::bind(path)?
+            }
+            Err(e) => return Err(e),
+        };
+        Ok(LocalListener {
+            inner,
+            path: Some(path.to_owned()),
+        })
+    }
+    #[cfg(windows)]
+    {
+        windows::listen(path.as_ref())
+    }
+}
+
+impl LocalListener {
+    /// Accept a new local connection.
+    ///
+    /// This function is blocking.
+    pub fn accept(&self) -> Result<LocalStream> {
+        #[cfg(unix)]
+        {
+            self.inner.accept().map(|(inner, _)| LocalStream { inner })
+        }
+        #[cfg(windows)]
+        {
+            windows::accept(self)
+        }
+    }
+
+    /// Return an iterator over the connections being accepted.
+    ///
+    /// The iterator never returns `None`.
+    pub fn incoming(&self) -> impl Iterator<Item = Result<LocalStream>> + '_ {
+        std::iter::repeat_with(move || self.accept())
+    }
+}
+
+#[cfg(unix)]
+impl From<UnixListener> for LocalListener {
+    /// Adopt `inner`, without removing its socket file when dropped.
+    fn from(inner: UnixListener) -> Self {
+        LocalListener { inner, path: None }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for LocalListener {
+    fn drop(&mut self) {
+        if let Some(path) = &self.path {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+impl Read for LocalStream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl Write for LocalStream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl TryClone for LocalStream {
+    fn try_clone(&self) -> Result<Self> {
+        self.inner.try_clone().map(|inner| LocalStream { inner })
+    }
+}
+
+#[cfg(windows)]
+mod windows {
+    use super::*;
+    use std::os::windows::ffi::OsStrExt;
+    use std::os::windows::io::{FromRawHandle, RawHandle};
+
+    const PIPE_PREFIX: &str = r"\\.\pipe\";
+    const PIPE_ACCESS_DUPLEX: u32 = 0x0000_0003;
+    const FILE_FLAG_FIRST_PIPE_INSTANCE: u32 = 0x0008_0000;
+    const PIPE_TYPE_BYTE: u32 = 0x0000_0000;
+    const PIPE_UNLIMITED_INSTANCES: u32 = 255;
+    const BUFFER_SIZE: u32 = 64 * 1024;
+    const ERROR_PIPE_BUSY: i32 = 231;
+    const ERROR_PIPE_CONNECTED: i32 = 535;
+    const NMPWAIT_WAIT_FOREVER: u32 = 0xffff_ffff;
+
+    extern "system" {
+        fn CreateNamedPipeW(
+            name: *const u16,
+            open_mode: u32,
+            pipe_mode: u32,
+            max_instances: u32,
+            out_buffer_size: u32,
+            in_buffer_size: u32,
+            default_timeout: u32,
+            security_attributes: *mut std::ffi::c_void,
+        ) -> RawHandle;
+        fn ConnectNamedPipe(pipe: RawHandle, overlapped: *mut std::ffi::c_void) -> i32;
+        fn WaitNamedPipeW(name: *const u16, timeout: u32) -> i32;
+    }
+
+    fn pipe_name(path: &Path) -> OsString {
+        let path = path.as_os_str();
+        if path.to_string_lossy().starts_with(PIPE_PREFIX) {
+            path.to_owned()
+        } else {
+            let mut name = OsString::from(PIPE_PREFIX);
+            name.push(path);
+            name
+        }
+    }
+
+    fn wide(name: &OsString) -> Vec<u16> {
+        name.encode_wide().chain(Some(0)).collect()
+    }
+
+    fn create_instance(name: &[u16], first: bool) -> Result<File> {
+        let flags = if first {
+            PIPE_ACCESS_DUPLEX | FILE_FLAG_FIRST_PIPE_INSTANCE
+        } else {
+            PIPE_ACCESS_DUPLEX
+        };
+        // SAFETY: `name` is a nul-terminated wide string, and null
+        // security attributes select the defaults.
+        let handle = unsafe {
+            CreateNamedPipeW(
+                name.as_ptr(),
+                flags,
+                PIPE_TYPE_BYTE,
+                PIPE_UNLIMITED_INSTANCES,
+                BUFFER_SIZE,
+                BUFFER_SIZE,
+                0,
+                std::ptr::null_mut(),
+            )
+        };
+        if handle as isize == -1 {
+            return Err(Error::last_os_error());
+        }
+        // SAFETY: `handle` is a valid pipe handle that nothing else owns.
+        Ok(unsafe { File::from_raw_handle(handle) })
+    }
+
+    pub(super) fn listen(path: &Path) -> Result<LocalListener> {
+        let name = wide(&pipe_name(path));
+        let pending = create_instance(&name, true)?;
+        Ok(LocalListener {
+            name,
+            pending: Mutex::new(pending),
+        })
+    }
+
+    pub(super) fn accept(listener: &LocalListener) -> Result<LocalStream> {
+        use std::os::windows::io::AsRawHandle;
+
+        let mut pending = listener
+            .pending
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        // SAFETY: the pending instance is a valid pipe handle, and a
+        // null overlapped structure makes the call blocking.
+        let connected = unsafe { ConnectNamedPipe(pending.as_raw_handle(), std::ptr::null_mut()) };
+        if connected == 0 {
+            let error = Error::last_os_error();
+            if error.raw_os_error() != Some(ERROR_PIPE_CONNECTED) {
+                return Err(error);
+            }
+        }
+
+        // The connected instance is handed out, and a fresh one waits
+        // for the next client.
+        let next = create_instance(&listener.name, false)?;
+        let inner = std::mem::replace(&mut *pending, next);
+        Ok(LocalStream { inner })
+    }
+
+    pub(super) fn connect(path: &Path) -> Result<LocalStream> {
+        let name = pipe_name(path);
+        loop {
+            match OpenOptions::new().read(true).write(true).open(&name) {
+                Ok(inner) => return Ok(LocalStream { inner }),
+                Err(e) if e.raw_os_error() == Some(ERROR_PIPE_BUSY) => {
+                    // SAFETY: the name is a nul-terminated wide string.
+                    if unsafe { WaitNamedPipeW(wide(&name).as_ptr(), NMPWAIT_WAIT_FOREVER) } == 0 {
+                        return Err(Error::last_os_error());
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
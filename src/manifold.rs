@@ -0,0 +1,255 @@
+//! Several channels over a single gutter.
+//!
+//! A [`Manifold`] multiplexes numbered channels over one gutter, so that
+//! e.g. telemetry and control logs can share a connection without
+//! mixing up. Each log is sent as a frame, preceded by its channel and
+//! length. Picking up from a channel keeps frames of other channels
+//! aside until they are picked up in turn, while [`Manifold::demux`]
+//! hands every frame to a routing function as it arrives.
+//!
+//! Both ends must use a `Manifold`. Frames are limited to
+//! [`DEFAULT_MAX_FRAME_LEN`] bytes, and the frames kept aside for each
+//! channel to [`DEFAULT_MAX_PENDING_LEN`] bytes, unless
+//! [configured otherwise](Manifold::set_max_pending_len), so that a peer
+//! flooding a channel nobody reads can't exhaust memory.
+
+use std::collections::{HashMap, VecDeque};
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::mem;
+
+use crate::{as_u8_slice, as_u8_slice_mut, Closed, Log, DEFAULT_MAX_FRAME_LEN};
+
+/// Default maximum size of the frames kept aside for a channel, 16 MiB.
+pub const DEFAULT_MAX_PENDING_LEN: usize = 16 * 1024 * 1024;
+
+/// A gutter carrying several numbered channels.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```
+/// use gutters::manifold::Manifold;
+/// use std::io::Cursor;
+///
+/// const TELEMETRY: u32 = 0;
+/// const CONTROL: u32 = 1;
+///
+/// let mut manifold = Manifold::new(Cursor::new(Vec::new()));
+/// manifold.throw(TELEMETRY, &[20.5f64, 21.0])?;
+/// manifold.throw(CONTROL, &1u8)?;
+///
+/// let mut gutter = manifold.into_inner();
+/// gutter.set_position(0);
+/// let mut manifold = Manifold::new(gutter);
+/// let mut command = 0u8;
+/// manifold.pick_up(CONTROL, &mut command)?;
+/// assert_eq!(command, 1);
+/// let mut temperatures = [0.0f64; 2];
+/// manifold.pick_up(TELEMETRY, &mut temperatures)?;
+/// assert_eq!(temperatures, [20.5, 21.0]);
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Debug)]
+pub struct Manifold<G> {
+    gutter: G,
+    pending: HashMap<u32, Pending>,
+    max_pending_len: usize,
+}
+
+/// Frames of a channel kept aside for later.
+#[derive(Debug, Default)]
+struct Pending {
+    frames: VecDeque<Vec<u8>>,
+    len: usize,
+}
+
+impl Pending {
+    fn pop_front(&mut self) -> Option<Vec<u8>> {
+        let frame = self.frames.pop_front()?;
+        self.len -= frame.len();
+        Some(frame)
+    }
+}
+
+impl<G: Read + Write> Manifold<G> {
+    /// Carry channels over `gutter`.
+    pub fn new(gutter: G) -> Self {
+        Manifold {
+            gutter,
+            pending: HashMap::new(),
+            max_pending_len: DEFAULT_MAX_PENDING_LEN,
+        }
+    }
+
+    /// Keep at most `max_len` bytes of frames aside for each channel,
+    /// rather than [`DEFAULT_MAX_PENDING_LEN`].
+    pub fn set_max_pending_len(&mut self, max_len: usize) {
+        self.max_pending_len = max_len;
+    }
+
+    /// Send a message of type `T` down `channel`.
+    ///
+    /// This function is blocking.
+    ///
+    /// This function fails with [`ErrorKind::InvalidInput`] if the log
+    /// is longer than [`DEFAULT_MAX_FRAME_LEN`], which the peer would
+    /// reject.
+    ///
+    /// This function doesn't change endianness, so it must be the
+    /// same between the peers.
+    pub fn throw<T: Log>(&mut self, channel: u32, buffer: &T) -> Result<()> {
+        let bytes = as_u8_slice(buffer);
+        let len = u32::try_from(bytes.len())
+            .ok()
+            .filter(|&len| len as usize <= DEFAULT_MAX_FRAME_LEN)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "log is too long for a frame"))?;
+        self.gutter.write_all(as_u8_slice(&[channel, len]))?;
+        self.gutter.write_all(bytes)
+    }
+
+    /// Read the next message of type `T` from `channel`.
+    ///
+    /// This function is blocking.
+    ///
+    /// Frames of other channels read in the meantime are kept for
+    /// later. This function fails with [`ErrorKind::InvalidData`] if the
+    /// frame isn't the size of `T`, in which case it is discarded, or if
+    /// a frame of another channel doesn't fit in the room left aside for
+    /// it, in which case that frame is discarded. It fails with a
+    /// [`Closed`] error if the peer closed the gutter in between frames.
+    ///
+    /// This function doesn't change endianness, so it must be the
+    /// same between the peers.
+    pub fn pick_up<T: Log>(&mut self, channel: u32, buffer: &mut T) -> Result<()> {
+        let frame = match self.pending.get_mut(&channel).and_then(Pending::pop_front) {
+            Some(frame) => frame,
+            None => loop {
+                let (id, frame) = self
+                    .read_frame()?
+                    .ok_or_else(|| Error::new(ErrorKind::UnexpectedEof, Closed))?;
+                if id == channel {
+                    break frame;
+                }
+                let pending = self.pending.entry(id).or_default();
+                if pending.len + frame.len() > self.max_pending_len {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!(
+                            "channel {} has more than {} bytes of frames pending",
+                            id, self.max_pending_len
+                        ),
+                    ));
+                }
+                pending.len += frame.len();
+                pending.frames.push_back(frame);
+            },
+        };
+
+        let bytes = as_u8_slice_mut(buffer);
+        if frame.len() != bytes.len() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "frame of channel {} is {} bytes, expected {}",
+                    channel,
+                    frame.len(),
+                    bytes.len()
+                ),
+            ));
+        }
+        bytes.copy_from_slice(&frame);
+        Ok(())
+    }
+
+    /// Hand every frame to `route`, along with its channel, until the
+    /// peer closes the gutter.
+    ///
+    /// This function is blocking.
+    ///
+    /// Frames kept aside by [`pick_up`](Manifold::pick_up) are routed
+    /// first, by increasing channel. This function returns as soon as
+    /// `route` fails, with its error.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```
+    /// use gutters::manifold::Manifold;
+    /// use std::io::Cursor;
+    /// use std::sync::mpsc;
+    ///
+    /// let mut manifold = Manifold::new(Cursor::new(Vec::new()));
+    /// manifold.throw(0, &12.5f64)?;
+    /// manifold.throw(1, &1u8)?;
+    /// manifold.throw(0, &13.0f64)?;
+    ///
+    /// let mut gutter = manifold.into_inner();
+    /// gutter.set_position(0);
+    /// let (telemetry, readings) = mpsc::channel();
+    /// let mut commands = Vec::new();
+    /// Manifold::new(gutter).demux(|channel, frame| {
+    ///     match channel {
+    ///         0 => telemetry.send(f64::from_ne_bytes(frame.try_into().unwrap())).unwrap(),
+    ///         _ => commands.push(frame[0]),
+    ///     }
+    ///     Ok(())
+    /// })?;
+    /// assert_eq!(readings.try_iter().collect::<Vec<_>>(), [12.5, 13.0]);
+    /// assert_eq!(commands, [1]);
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    pub fn demux<F: FnMut(u32, &[u8]) -> Result<()>>(&mut self, mut route: F) -> Result<()> {
+        let mut channels: Vec<u32> = self.pending.keys().copied().collect();
+        channels.sort_unstable();
+        for channel in channels {
+            if let Some(pending) = self.pending.remove(&channel) {
+                for frame in pending.frames {
+                    route(channel, &frame)?;
+                }
+            }
+        }
+        while let Some((channel, frame)) = self.read_frame()? {
+            route(channel, &frame)?;
+        }
+        Ok(())
+    }
+
+    /// Move the gutter out.
+    ///
+    /// Frames kept aside for later are lost.
+    pub fn into_inner(self) -> G {
+        self.gutter
+    }
+
+    /// Read the next frame, or `None` if the gutter was closed in
+    /// between frames.
+    fn read_frame(&mut self) -> Result<Option<(u32, Vec<u8>)>> {
+        let mut header = [0u32; 2];
+        let bytes = as_u8_slice_mut(&mut header);
+        let mut read = 0;
+        while read < mem::size_of::<[u32; 2]>() {
+            match self.gutter.read(&mut bytes[read..]) {
+                Ok(0) if read == 0 => return Ok(None),
+                Ok(0) => return Err(Error::from(ErrorKind::UnexpectedEof)),
+                Ok(n) => read += n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        let [channel, len] = header;
+        if len as usize > DEFAULT_MAX_FRAME_LEN {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "frame of {} bytes exceeds the {} bytes limit",
+                    len, DEFAULT_MAX_FRAME_LEN
+                ),
+            ));
+        }
+        let mut frame = vec![0u8; len as usize];
+        self.gutter.read_exact(&mut frame)?;
+        Ok(Some((channel, frame)))
+    }
+}
@@ -0,0 +1,1039 @@
+//! A queue of outgoing logs, thrown by a dedicated thread.
+//!
+//! When many threads need to throw into the same gutter, wrapping it in
+//! a mutex makes them contend for the socket. An [`Outbox`] instead
+//! hands the gutter over to a single sender thread, which is fed by a
+//! multi-producer queue. Producers only pay for an enqueue.
+//!
+//! The queue is an unbounded [`std::sync::mpsc`] channel, which is
+//! lock-free. To keep it from growing without bounds, producers can
+//! watch its [`depth`](Outbox::depth), or configure watermarks with a
+//! [`Builder`] and slow down while the outbox is not
+//! [ready](Outbox::poll_ready). Alternatively, a
+//! [lossy](Builder::lossy) outbox drops logs according to a
+//! [`DropPolicy`] when too many are queued, favoring freshness over
+//! completeness.
+//!
+//! Logs can also be thrown with a [priority](Outbox::throw_with_priority)
+//! class. Higher classes are always thrown first, and drop policies
+//! apply to each class separately, so that urgent logs get through even
+//! when bulk ones saturate the gutter.
+//!
+//! Logs can be given a [time to live](Outbox::throw_with_ttl), past
+//! which the sender thread drops them instead of throwing stale data.
+//!
+//! Logs thrown with a [key](Outbox::throw_with_key) replace any queued
+//! log with the same key, so that a slow gutter delivers the latest
+//! value for each key rather than a backlog of stale ones.
+//!
+//! Finally, an [acknowledged](Builder::acknowledged) outbox waits for the
+//! peer to hail each log, and lets producers know when a given log got
+//! [through](Outbox::throw_with_ack).
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::clock::{Clock, SystemClock};
+use crate::{throw, wait, Log};
+
+type Callback = Box<dyn Fn() + Send + Sync>;
+type Completion = Box<dyn FnOnce() + Send>;
+
+/// Producer side of an outbox.
+///
+/// Cloning an `Outbox` gives another producer for the same queue. The
+/// sender thread stops once every producer has been dropped and the
+/// queue has been emptied.
+pub struct Outbox<T> {
+    queue: Sender<Queued<T>>,
+    shared: Arc<Shared>,
+}
+
+/// Configuration of an [`Outbox`].
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use gutters::outbox::Builder;
+/// use std::net::TcpStream;
+/// let stream = TcpStream::connect("127.0.0.1:34567")?;
+///
+/// let (outbox, _sender) = Builder::new()
+///     .watermarks(1_000, 10_000)
+///     .on_high(|| eprintln!("outbox is filling up"))
+///     .on_low(|| eprintln!("outbox is draining again"))
+///     .spawn(stream);
+/// outbox.throw(64.0f64)?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub struct Builder {
+    low: usize,
+    high: usize,
+    on_high: Option<Callback>,
+    on_low: Option<Callback>,
+    priorities: usize,
+    lossy: Option<(usize, DropPolicy)>,
+    lossy_classes: Vec<(usize, usize, DropPolicy)>,
+    ttl: Option<Duration>,
+    acknowledged: bool,
+    clock: Arc<dyn Clock>,
+}
+
+/// Which logs a [lossy](Builder::lossy) outbox drops when full.
+///
+/// With several [priority](Builder::priorities) classes, the policy
+/// only ever drops logs of the class being throttled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropPolicy {
+    /// Drop the oldest queued logs, so that the freshest ones are thrown.
+    ///
+    /// Logs are dropped by the sender thread, so the queue may go over
+    /// capacity while it is busy throwing.
+    Oldest,
+    /// Drop the logs being thrown, keeping the queued ones.
+    Newest,
+    /// Keep only one in every `n` logs being thrown.
+    Sample(u32),
+}
+
+/// Counters of an [`Outbox`], or of one of its
+/// [priority](Builder::priorities) classes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Stats {
+    /// Number of logs queued but not yet thrown.
+    pub queued: usize,
+    /// Number of logs thrown down the gutter.
+    pub thrown: u64,
+    /// Number of logs dropped by the [`DropPolicy`].
+    pub dropped: u64,
+    /// Number of logs dropped because their time to live ran out.
+    pub expired: u64,
+    /// Number of logs replaced by a newer one with the same
+    /// [key](Outbox::throw_with_key).
+    pub compacted: u64,
+    /// Time since the oldest log not thrown yet was queued, if any.
+    pub oldest_unsent: Option<Duration>,
+    /// Time since the log waiting for the peer's hail was queued, if
+    /// any. This is always `None` unless the outbox is
+    /// [acknowledged](Builder::acknowledged).
+    pub oldest_unacked: Option<Duration>,
+}
+
+struct Shared {
+    depth: AtomicUsize,
+    above_high: AtomicBool,
+    low: usize,
+    high: usize,
+    on_high: Option<Callback>,
+    on_low: Option<Callback>,
+    classes: Vec<Class>,
+    ttl: Option<Duration>,
+    acknowledged: bool,
+    clock: Arc<dyn Clock>,
+    epoch: Instant,
+}
+
+#[derive(Default)]
+struct Class {
+    lossy: Option<(usize, DropPolicy)>,
+    depth: AtomicUsize,
+    sampled: AtomicU64,
+    thrown: AtomicU64,
+    dropped: AtomicU64,
+    expired: AtomicU64,
+    compacted: AtomicU64,
+    oldest_unsent: Timestamp,
+    oldest_unacked: Timestamp,
+    /// Number of logs in the channel, not received by the sender thread
+    /// yet.
+    unstaged: AtomicUsize,
+    /// When the oldest log in the channel was queued. This is only
+    /// meaningful while `unstaged` isn't zero.
+    oldest_unstaged: Timestamp,
+}
+
+/// An optional instant, as nanoseconds since [`Shared::epoch`] plus one,
+/// or zero for none.
+#[derive(Default)]
+struct Timestamp(AtomicU64);
+
+struct Queued<T> {
+    class: usize,
+    queued_at: Instant,
+    expiry: Option<Instant>,
+    key: Option<u64>,
+    log: T,
+    on_ack: Option<Completion>,
+}
+
+/// Logs of a priority class received by the sender thread.
+struct Staging<T> {
+    logs: VecDeque<Queued<T>>,
+    /// Positions of the keyed logs, counting from the first log ever
+    /// staged.
+    keys: HashMap<u64, u64>,
+    popped: u64,
+}
+
+impl Builder {
+    /// Create a configuration with no watermarks.
+    pub fn new() -> Self {
+        Builder {
+            low: 0,
+            high: usize::MAX,
+            on_high: None,
+            on_low: None,
+            priorities: 1,
+            lossy: None,
+            lossy_classes: Vec::new(),
+            ttl: None,
+            acknowledged: false,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Set the `low` and `high` watermarks of the outbox.
+    ///
+    /// The outbox stops being [ready](Outbox::poll_ready) once `high`
+    /// logs are queued, and becomes ready again once the sender thread
+    /// brings the queue back down to `low` logs.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `low` is greater than `high`.
+    pub fn watermarks(mut self, low: usize, high: usize) -> Self {
+        assert!(low <= high, "low watermark is above high watermark");
+        self.low = low;
+        self.high = high;
+        self
+    }
+
+    /// Call `callback` whenever the queue reaches the high watermark.
+    ///
+    /// The callback runs on the producer thread whose throw crossed the
+    /// watermark.
+    pub fn on_high<F: Fn() + Send + Sync + 'static>(mut self, callback: F) -> Self {
+        self.on_high = Some(Box::new(callback));
+        self
+    }
+
+    /// Call `callback` whenever the queue drains back to the low
+    /// watermark, after having reached the high one.
+    ///
+    /// The callback runs on the sender thread.
+    pub fn on_low<F: Fn() + Send + Sync + 'static>(mut self, callback: F) -> Self {
+        self.on_low = Some(Box::new(callback));
+        self
+    }
+
+    /// Set the number of priority classes of the outbox.
+    ///
+    /// Classes range from 0, the lowest priority, to `priorities - 1`.
+    /// The outbox has a single class by default.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `priorities` is zero.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```no_run
+    /// use gutters::outbox::{Builder, DropPolicy};
+    /// use std::net::TcpStream;
+    /// let stream = TcpStream::connect("127.0.0.1:34567")?;
+    ///
+    /// const BULK: usize = 0;
+    /// const ALARM: usize = 1;
+    /// let (outbox, _sender) = Builder::new()
+    ///     .priorities(2)
+    ///     .lossy_class(BULK, 1000, DropPolicy::Oldest)
+    ///     .spawn(stream);
+    ///
+    /// outbox.throw_with_priority(12.5f64, BULK)?;
+    /// outbox.throw_with_priority(f64::NAN, ALARM)?;
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    pub fn priorities(mut self, priorities: usize) -> Self {
+        assert!(priorities > 0, "outbox has no priority class");
+        self.priorities = priorities;
+        self
+    }
+
+    /// Drop logs according to `policy` once `capacity` logs are queued.
+    ///
+    /// This applies to every priority class, each with its own
+    /// `capacity`. Dropped logs are counted in [`Stats::dropped`].
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `capacity` is zero, or if `policy` is
+    /// [`DropPolicy::Sample`] with a zero rate.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```no_run
+    /// use gutters::outbox::{Builder, DropPolicy};
+    /// use std::net::TcpStream;
+    /// let stream = TcpStream::connect("127.0.0.1:34567")?;
+    ///
+    /// let (outbox, _sender) = Builder::new()
+    ///     .lossy(100, DropPolicy::Oldest)
+    ///     .spawn(stream);
+    /// for i in 0..1_000_000u64 {
+    ///     outbox.throw(i)?;
+    /// }
+    /// println!("dropped {} logs", outbox.stats().dropped);
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    pub fn lossy(mut self, capacity: usize, policy: DropPolicy) -> Self {
+        assert!(capacity > 0, "lossy outbox capacity is zero");
+        assert!(policy != DropPolicy::Sample(0), "sampling rate is zero");
+        self.lossy = Some((capacity, policy));
+        self
+    }
+
+    /// Drop logs of the priority `class` according to `policy` once
+    /// `capacity` of them are queued.
+    ///
+    /// This overrides [`lossy`](Builder::lossy) for this class.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `capacity` is zero, or if `policy` is
+    /// [`DropPolicy::Sample`] with a zero rate. Spawning the outbox
+    /// panics if `class` is not one of its priority classes.
+    pub fn lossy_class(mut self, class: usize, capacity: usize, policy: DropPolicy) -> Self {
+        assert!(capacity > 0, "lossy outbox capacity is zero");
+        assert!(policy != DropPolicy::Sample(0), "sampling rate is zero");
+        self.lossy_classes.push((class, capacity, policy));
+        self
+    }
+
+    /// Give every log a time to live of `ttl`, unless thrown with
+    /// [`Outbox::throw_with_ttl`].
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```no_run
+    /// use gutters::outbox::Builder;
+    /// use std::net::TcpStream;
+    /// use std::time::Duration;
+    /// let stream = TcpStream::connect("127.0.0.1:34567")?;
+    ///
+    /// let (outbox, _sender) = Builder::new()
+    ///     .ttl(Duration::from_secs(1))
+    ///     .spawn(stream);
+    /// outbox.throw([1.0f64, 2.0, 3.0])?;
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// Make the sender thread wait for the peer to hail each log before
+    /// throwing the next one, as with [`throw_and_wait`](crate::throw_and_wait).
+    ///
+    /// The peer must pick logs up with
+    /// [`pick_up_and_hail`](crate::pick_up_and_hail). Callbacks given to
+    /// [`Outbox::throw_with_ack`] then run once the peer has hailed their
+    /// log. This costs a round trip per log.
+    pub fn acknowledged(mut self) -> Self {
+        self.acknowledged = true;
+        self
+    }
+
+    /// Read the time from `clock` rather than the system clock, for
+    /// times to live and backlog ages.
+    ///
+    /// Tests can pass a [`ManualClock`](crate::clock::ManualClock) to
+    /// expire logs without waiting.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```
+    /// use gutters::clock::ManualClock;
+    /// use gutters::outbox::Builder;
+    /// use gutters::{hail, pick_up, pick_up_and_hail};
+    /// use std::net::{TcpListener, TcpStream};
+    /// use std::time::Duration;
+    ///
+    /// let listener = TcpListener::bind("127.0.0.1:0")?;
+    /// let stream = TcpStream::connect(listener.local_addr()?)?;
+    /// let (mut peer, _) = listener.accept()?;
+    ///
+    /// let clock = ManualClock::new();
+    /// let (outbox, _sender) = Builder::new()
+    ///     .acknowledged()
+    ///     .clock(clock.clone())
+    ///     .spawn(stream);
+    /// outbox.throw(1u32)?;
+    /// outbox.throw_with_ttl(2u32, 0, Duration::from_secs(1))?;
+    /// outbox.throw(3u32)?;
+    ///
+    /// let mut log = 0u32;
+    /// pick_up(&mut peer, &mut log)?;
+    /// clock.advance(Duration::from_secs(2));
+    /// assert_eq!(outbox.stats().oldest_unacked, Some(Duration::from_secs(2)));
+    ///
+    /// // The second log expired while the first one waited for its hail.
+    /// hail(&mut peer)?;
+    /// pick_up_and_hail(&mut peer, &mut log)?;
+    /// assert_eq!(log, 3);
+    /// assert_eq!(outbox.stats().expired, 1);
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    pub fn clock<C: Clock + 'static>(mut self, clock: C) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Move the `gutter` to a new sender thread, and return the
+    /// producer side of its queue.
+    ///
+    /// See [`Outbox::spawn`].
+    pub fn spawn<G, T>(self, gutter: G) -> (Outbox<T>, JoinHandle<Result<G>>)
+    where
+        G: Read + Write + Send + 'static,
+        T: Log + Send + 'static,
+    {
+        let mut classes: Vec<Class> = (0..self.priorities)
+            .map(|_| Class {
+                lossy: self.lossy,
+                ..Class::default()
+            })
+            .collect();
+        for (class, capacity, policy) in self.lossy_classes {
+            assert!(class < self.priorities, "no such priority class");
+            classes[class].lossy = Some((capacity, policy));
+        }
+
+        let shared = Arc::new(Shared {
+            depth: AtomicUsize::new(0),
+            above_high: AtomicBool::new(false),
+            low: self.low,
+            high: self.high,
+            on_high: self.on_high,
+            on_low: self.on_low,
+            classes,
+            ttl: self.ttl,
+            acknowledged: self.acknowledged,
+            epoch: self.clock.now(),
+            clock: self.clock,
+        });
+        let (queue, receiver) = mpsc::channel();
+        let sender = {
+            let shared = shared.clone();
+            thread::spawn(move || send_all(gutter, receiver, &shared))
+        };
+        (Outbox { queue, shared }, sender)
+    }
+}
+
+impl Default for Builder {
+    fn default() -> Self {
+        Builder::new()
+    }
+}
+
+impl fmt::Debug for Builder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Builder")
+            .field("low", &self.low)
+            .field("high", &self.high)
+            .field("priorities", &self.priorities)
+            .field("lossy", &self.lossy)
+            .field("lossy_classes", &self.lossy_classes)
+            .field("ttl", &self.ttl)
+            .field("acknowledged", &self.acknowledged)
+            .field("clock", &self.clock)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T: Log + Send + 'static> Outbox<T> {
+    /// Move the `gutter` to a new sender thread, and return the
+    /// producer side of its queue.
+    ///
+    /// The sender thread throws logs of the same priority class in the
+    /// order they were queued, and flushes the `gutter` whenever the
+    /// queue runs empty. Joining it gives the `gutter` back, or the error
+    /// that stopped it.
+    ///
+    /// Use a [`Builder`] to configure the outbox.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```
+    /// use gutters::outbox::Outbox;
+    /// use std::io::Cursor;
+    ///
+    /// let (outbox, sender) = Outbox::spawn(Cursor::new(Vec::new()));
+    ///
+    /// let producers: Vec<_> = (0..4u64)
+    ///     .map(|i| {
+    ///         let outbox = outbox.clone();
+    ///         std::thread::spawn(move || outbox.throw(i))
+    ///     })
+    ///     .collect();
+    /// for producer in producers {
+    ///     producer.join().unwrap()?;
+    /// }
+    ///
+    /// drop(outbox);
+    /// let gutter = sender.join().unwrap()?;
+    /// assert_eq!(gutter.into_inner().len(), 4 * 8);
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    pub fn spawn<G>(gutter: G) -> (Self, JoinHandle<Result<G>>)
+    where
+        G: Read + Write + Send + 'static,
+    {
+        Builder::new().spawn(gutter)
+    }
+
+    /// Queue `log` to be thrown by the sender thread.
+    ///
+    /// This function never blocks. It fails with
+    /// [`ErrorKind::BrokenPipe`] if the sender thread has stopped, in
+    /// which case joining it gives the reason.
+    ///
+    /// A [lossy](Builder::lossy) outbox may drop `log` instead, which is
+    /// not an error.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```no_run
+    /// use gutters::outbox::Outbox;
+    /// use std::net::TcpStream;
+    /// let stream = TcpStream::connect("127.0.0.1:34567")?;
+    ///
+    /// let (outbox, _sender) = Outbox::spawn(stream);
+    /// outbox.throw(64.0f64)?;
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    pub fn throw(&self, log: T) -> Result<()> {
+        self.throw_with_priority(log, 0)
+    }
+
+    /// Queue `log` to be thrown by the sender thread, in the priority
+    /// `class`.
+    ///
+    /// The sender thread only throws logs of a class once no log of a
+    /// higher class is queued. Otherwise, this is the same as
+    /// [`throw`](Outbox::throw), which uses the lowest class.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `class` is not one of the
+    /// [priority classes](Builder::priorities) of the outbox.
+    pub fn throw_with_priority(&self, log: T, class: usize) -> Result<()> {
+        let expiry = self.shared.ttl.map(|ttl| self.shared.clock.now() + ttl);
+        self.queue(log, class, expiry, None, None)
+    }
+
+    /// Queue `log` to be thrown by the sender thread, in the priority
+    /// `class`, unless `ttl` elapses first.
+    ///
+    /// If the sender thread only gets to `log` after its time to live,
+    /// it drops it and counts it in [`Stats::expired`]. Otherwise, this
+    /// is the same as [`throw_with_priority`](Outbox::throw_with_priority).
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `class` is not one of the
+    /// [priority classes](Builder::priorities) of the outbox.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```no_run
+    /// use gutters::outbox::Outbox;
+    /// use std::net::TcpStream;
+    /// use std::time::Duration;
+    /// let stream = TcpStream::connect("127.0.0.1:34567")?;
+    ///
+    /// let (outbox, _sender) = Outbox::spawn(stream);
+    /// let position = [12.0f64, 7.5];
+    /// outbox.throw_with_ttl(position, 0, Duration::from_millis(500))?;
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    pub fn throw_with_ttl(&self, log: T, class: usize, ttl: Duration) -> Result<()> {
+        self.queue(log, class, Some(self.shared.clock.now() + ttl), None, None)
+    }
+
+    /// Queue `log` to be thrown by the sender thread, and call `on_ack`
+    /// once it gets through.
+    ///
+    /// With an [acknowledged](Builder::acknowledged) outbox, `on_ack`
+    /// runs on the sender thread once the peer has hailed `log`.
+    /// Otherwise, it runs as soon as `log` has been thrown, which doesn't
+    /// mean the peer has picked it up yet. If `log` is dropped or expires
+    /// instead, `on_ack` is dropped without being called. Otherwise, this
+    /// is the same as [`throw`](Outbox::throw).
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```
+    /// use gutters::outbox::Builder;
+    /// use gutters::pick_up_and_hail;
+    /// use std::net::{TcpListener, TcpStream};
+    /// use std::sync::mpsc;
+    ///
+    /// let listener = TcpListener::bind("127.0.0.1:0")?;
+    /// let stream = TcpStream::connect(listener.local_addr()?)?;
+    /// let (mut peer, _) = listener.accept()?;
+    ///
+    /// let (outbox, _sender) = Builder::new().acknowledged().spawn(stream);
+    /// let (acked, acks) = mpsc::channel();
+    /// outbox.throw_with_ack(42u32, move || acked.send(42).unwrap())?;
+    ///
+    /// let mut log = 0u32;
+    /// pick_up_and_hail(&mut peer, &mut log)?;
+    /// assert_eq!(acks.recv().unwrap(), log);
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    pub fn throw_with_ack<F: FnOnce() + Send + 'static>(&self, log: T, on_ack: F) -> Result<()> {
+        let expiry = self.shared.ttl.map(|ttl| self.shared.clock.now() + ttl);
+        self.queue(log, 0, expiry, None, Some(Box::new(on_ack)))
+    }
+
+    /// Queue `log` to be thrown by the sender thread, replacing any
+    /// queued log with the same `key`.
+    ///
+    /// The replaced log is counted in [`Stats::compacted`], and `log`
+    /// takes its place in the queue. Logs are only replaced once the
+    /// sender thread has received them, which it does whenever it isn't
+    /// throwing, so some replaced logs may still be thrown while the
+    /// gutter keeps up. Otherwise, this is the same as
+    /// [`throw`](Outbox::throw).
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```no_run
+    /// use gutters::outbox::Outbox;
+    /// use std::net::TcpStream;
+    /// let stream = TcpStream::connect("127.0.0.1:34567")?;
+    ///
+    /// let (outbox, _sender) = Outbox::spawn(stream);
+    /// for reading in 0..1_000_000u64 {
+    ///     let sensor = reading % 16;
+    ///     outbox.throw_with_key([sensor, reading], sensor)?;
+    /// }
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    pub fn throw_with_key(&self, log: T, key: u64) -> Result<()> {
+        let expiry = self.shared.ttl.map(|ttl| self.shared.clock.now() + ttl);
+        self.queue(log, 0, expiry, Some(key), None)
+    }
+
+    fn queue(
+        &self,
+        log: T,
+        class: usize,
+        expiry: Option<Instant>,
+        key: Option<u64>,
+        on_ack: Option<Completion>,
+    ) -> Result<()> {
+        let shared = &self.shared;
+        assert!(class < shared.classes.len(), "no such priority class");
+        if shared.should_drop_incoming(class) {
+            shared.classes[class].dropped.fetch_add(1, Ordering::SeqCst);
+            return Ok(());
+        }
+        shared.classes[class].depth.fetch_add(1, Ordering::SeqCst);
+        shared.depth.fetch_add(1, Ordering::SeqCst);
+        let queued_at = shared.clock.now();
+        if shared.classes[class]
+            .unstaged
+            .fetch_add(1, Ordering::SeqCst)
+            == 0
+        {
+            // The channel held no log of this class, so this one is the
+            // oldest there.
+            shared.classes[class]
+                .oldest_unstaged
+                .set(shared.epoch, Some(queued_at));
+        }
+        let queued = Queued {
+            class,
+            queued_at,
+            expiry,
+            key,
+            log,
+            on_ack,
+        };
+        if self.queue.send(queued).is_err() {
+            shared.classes[class]
+                .unstaged
+                .fetch_sub(1, Ordering::SeqCst);
+            shared.classes[class].depth.fetch_sub(1, Ordering::SeqCst);
+            shared.depth.fetch_sub(1, Ordering::SeqCst);
+            return Err(Error::new(
+                ErrorKind::BrokenPipe,
+                "outbox sender thread has stopped",
+            ));
+        }
+        shared.check_high();
+        Ok(())
+    }
+
+    /// Number of logs queued but not yet thrown.
+    pub fn depth(&self) -> usize {
+        self.shared.depth.load(Ordering::SeqCst)
+    }
+
+    /// Snapshot of the outbox counters, summed over all priority classes.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```
+    /// use gutters::clock::ManualClock;
+    /// use gutters::outbox::Builder;
+    /// use gutters::pick_up;
+    /// use std::net::{TcpListener, TcpStream};
+    /// use std::time::Duration;
+    ///
+    /// let listener = TcpListener::bind("127.0.0.1:0")?;
+    /// let stream = TcpStream::connect(listener.local_addr()?)?;
+    /// let (mut peer, _) = listener.accept()?;
+    ///
+    /// let clock = ManualClock::new();
+    /// let (outbox, _sender) = Builder::new()
+    ///     .acknowledged()
+    ///     .clock(clock.clone())
+    ///     .spawn(stream);
+    /// outbox.throw(1u32)?;
+    /// let mut log = 0u32;
+    /// pick_up(&mut peer, &mut log)?;
+    ///
+    /// // The sender thread waits for the hail, which never comes, while
+    /// // the second log stays in the channel.
+    /// outbox.throw(2u32)?;
+    /// clock.advance(Duration::from_secs(1));
+    /// let stats = outbox.stats();
+    /// assert_eq!(stats.queued, 2);
+    /// assert_eq!(stats.oldest_unsent, Some(Duration::from_secs(1)));
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    pub fn stats(&self) -> Stats {
+        (0..self.shared.classes.len())
+            .map(|class| self.class_stats(class))
+            .fold(Stats::default(), |total, stats| Stats {
+                queued: total.queued + stats.queued,
+                thrown: total.thrown + stats.thrown,
+                dropped: total.dropped + stats.dropped,
+                expired: total.expired + stats.expired,
+                compacted: total.compacted + stats.compacted,
+                oldest_unsent: total.oldest_unsent.max(stats.oldest_unsent),
+                oldest_unacked: total.oldest_unacked.max(stats.oldest_unacked),
+            })
+    }
+
+    /// Snapshot of the counters of the priority `class`.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `class` is not one of the
+    /// [priority classes](Builder::priorities) of the outbox.
+    pub fn class_stats(&self, class: usize) -> Stats {
+        let class = &self.shared.classes[class];
+        Stats {
+            queued: class.depth.load(Ordering::SeqCst),
+            thrown: class.thrown.load(Ordering::SeqCst),
+            dropped: class.dropped.load(Ordering::SeqCst),
+            expired: class.expired.load(Ordering::SeqCst),
+            compacted: class.compacted.load(Ordering::SeqCst),
+            oldest_unsent: class.oldest_unsent.age(&self.shared).max(
+                match class.unstaged.load(Ordering::SeqCst) {
+                    0 => None,
+                    _ => class.oldest_unstaged.age(&self.shared),
+                },
+            ),
+            oldest_unacked: class.oldest_unacked.age(&self.shared),
+        }
+    }
+
+    /// Check whether producers may keep throwing at full speed.
+    ///
+    /// This is `false` from the moment the queue reaches the high
+    /// watermark, until it has drained back to the low watermark. It is
+    /// always `true` if no watermarks were configured.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```no_run
+    /// use gutters::outbox::Builder;
+    /// use std::net::TcpStream;
+    /// use std::time::Duration;
+    /// let stream = TcpStream::connect("127.0.0.1:34567")?;
+    ///
+    /// let (outbox, _sender) = Builder::new().watermarks(100, 1000).spawn(stream);
+    /// for i in 0..1_000_000u64 {
+    ///     while !outbox.poll_ready() {
+    ///         std::thread::sleep(Duration::from_millis(1));
+    ///     }
+    ///     outbox.throw(i)?;
+    /// }
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    pub fn poll_ready(&self) -> bool {
+        !self.shared.above_high.load(Ordering::SeqCst)
+    }
+}
+
+impl<T> Clone for Outbox<T> {
+    fn clone(&self) -> Self {
+        Outbox {
+            queue: self.queue.clone(),
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> fmt::Debug for Outbox<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Outbox")
+            .field("depth", &self.shared.depth.load(Ordering::SeqCst))
+            .finish_non_exhaustive()
+    }
+}
+
+impl Shared {
+    fn should_drop_incoming(&self, class: usize) -> bool {
+        let class = &self.classes[class];
+        match class.lossy {
+            Some((capacity, policy)) if class.depth.load(Ordering::SeqCst) >= capacity => {
+                match policy {
+                    DropPolicy::Oldest => false,
+                    DropPolicy::Newest => true,
+                    DropPolicy::Sample(n) => !class
+                        .sampled
+                        .fetch_add(1, Ordering::SeqCst)
+                        .is_multiple_of(n as u64),
+                }
+            }
+            _ => false,
+        }
+    }
+
+    fn stage<T>(&self, staging: &mut [Staging<T>], queued: Queued<T>) {
+        let class = &self.classes[queued.class];
+        // The logs of the class left in the channel are newer than this
+        // one.
+        class.oldest_unstaged.raise(self.epoch, queued.queued_at);
+        class.unstaged.fetch_sub(1, Ordering::SeqCst);
+        if staging[queued.class].push(queued) {
+            class.depth.fetch_sub(1, Ordering::SeqCst);
+            class.compacted.fetch_add(1, Ordering::SeqCst);
+            self.depth.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    fn drop_oldest<T>(&self, staging: &mut [Staging<T>]) {
+        for (class, staging) in self.classes.iter().zip(staging) {
+            if let Some((capacity, DropPolicy::Oldest)) = class.lossy {
+                while staging.len() > capacity {
+                    staging.pop_front();
+                    class.depth.fetch_sub(1, Ordering::SeqCst);
+                    class.dropped.fetch_add(1, Ordering::SeqCst);
+                    self.depth.fetch_sub(1, Ordering::SeqCst);
+                }
+            }
+        }
+    }
+
+    fn check_high(&self) {
+        if self.depth.load(Ordering::SeqCst) >= self.high
+            && !self.above_high.swap(true, Ordering::SeqCst)
+        {
+            if let Some(on_high) = &self.on_high {
+                on_high();
+            }
+        }
+    }
+
+    fn check_low(&self) {
+        if self.depth.load(Ordering::SeqCst) <= self.low
+            && self.above_high.swap(false, Ordering::SeqCst)
+        {
+            if let Some(on_low) = &self.on_low {
+                on_low();
+            }
+        }
+    }
+}
+
+impl Timestamp {
+    fn set(&self, epoch: Instant, instant: Option<Instant>) {
+        let nanos = instant.map_or(0, |instant| {
+            instant.saturating_duration_since(epoch).as_nanos() as u64 + 1
+        });
+        self.0.store(nanos, Ordering::SeqCst);
+    }
+
+    /// Move the instant forward to `instant`, unless it is already later.
+    fn raise(&self, epoch: Instant, instant: Instant) {
+        let nanos = instant.saturating_duration_since(epoch).as_nanos() as u64 + 1;
+        self.0.fetch_max(nanos, Ordering::SeqCst);
+    }
+
+    fn age(&self, shared: &Shared) -> Option<Duration> {
+        match self.0.load(Ordering::SeqCst) {
+            0 => None,
+            nanos => {
+                let instant = shared.epoch + Duration::from_nanos(nanos - 1);
+                Some(shared.clock.now().saturating_duration_since(instant))
+            }
+        }
+    }
+}
+
+impl<T> Staging<T> {
+    fn new() -> Self {
+        Staging {
+            logs: VecDeque::new(),
+            keys: HashMap::new(),
+            popped: 0,
+        }
+    }
+
+    /// Stage `queued`, returning whether it replaced an older log.
+    fn push(&mut self, queued: Queued<T>) -> bool {
+        if let Some(key) = queued.key {
+            let position = self.popped + self.logs.len() as u64;
+            if let Some(&older) = self.keys.get(&key) {
+                // Keep the age of the replaced log, which the newer one
+                // takes the place of, so that the queue stays in order.
+                let older = &mut self.logs[(older - self.popped) as usize];
+                *older = Queued {
+                    queued_at: older.queued_at,
+                    ..queued
+                };
+                return true;
+            }
+            self.keys.insert(key, position);
+        }
+        self.logs.push_back(queued);
+        false
+    }
+
+    fn pop_front(&mut self) -> Option<Queued<T>> {
+        let queued = self.logs.pop_front()?;
+        if let Some(key) = queued.key {
+            self.keys.remove(&key);
+        }
+        self.popped += 1;
+        Some(queued)
+    }
+
+    fn len(&self) -> usize {
+        self.logs.len()
+    }
+
+    fn oldest(&self) -> Option<Instant> {
+        self.logs.front().map(|queued| queued.queued_at)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.logs.is_empty()
+    }
+}
+
+fn send_all<G: Read + Write, T: Log>(
+    mut gutter: G,
+    receiver: Receiver<Queued<T>>,
+    shared: &Shared,
+) -> Result<G> {
+    let mut staging: Vec<Staging<T>> = shared.classes.iter().map(|_| Staging::new()).collect();
+    loop {
+        if staging.iter().all(Staging::is_empty) {
+            let queued = match receiver.try_recv() {
+                Ok(queued) => queued,
+                Err(TryRecvError::Empty) => {
+                    gutter.flush()?;
+                    match receiver.recv() {
+                        Ok(queued) => queued,
+                        Err(_) => break,
+                    }
+                }
+                Err(TryRecvError::Disconnected) => break,
+            };
+            shared.stage(&mut staging, queued);
+        }
+        for queued in receiver.try_iter() {
+            shared.stage(&mut staging, queued);
+        }
+        shared.drop_oldest(&mut staging);
+
+        let next = staging.iter_mut().rev().find_map(Staging::pop_front);
+        for (class, staging) in shared.classes.iter().zip(&staging) {
+            class.oldest_unsent.set(shared.epoch, staging.oldest());
+        }
+        if let Some(queued) = next {
+            let class = &shared.classes[queued.class];
+            if queued
+                .expiry
+                .is_some_and(|expiry| shared.clock.now() >= expiry)
+            {
+                class.expired.fetch_add(1, Ordering::SeqCst);
+            } else {
+                class
+                    .oldest_unsent
+                    .set(shared.epoch, Some(queued.queued_at));
+                if shared.acknowledged {
+                    class
+                        .oldest_unacked
+                        .set(shared.epoch, Some(queued.queued_at));
+                }
+                throw(&mut gutter, &queued.log)?;
+                class
+                    .oldest_unsent
+                    .set(shared.epoch, staging[queued.class].oldest());
+                if shared.acknowledged {
+                    gutter.flush()?;
+                    wait(&mut gutter)?;
+                    class.oldest_unacked.set(shared.epoch, None);
+                }
+                class.thrown.fetch_add(1, Ordering::SeqCst);
+                if let Some(on_ack) = queued.on_ack {
+                    on_ack();
+                }
+            }
+            class.depth.fetch_sub(1, Ordering::SeqCst);
+            shared.depth.fetch_sub(1, Ordering::SeqCst);
+        }
+        shared.check_low();
+    }
+    gutter.flush()?;
+    Ok(gutter)
+}
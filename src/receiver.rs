@@ -0,0 +1,123 @@
+//! Pick-up loops on managed threads.
+//!
+//! [`Gutter::spawn_receiver`] moves a gutter to a new thread, which picks
+//! logs up and hands each of them to a callback until the peer closes
+//! the gutter. The returned [`ReceiverHandle`] can ask the thread to
+//! [stop](ReceiverHandle::stop), and [joining](ReceiverHandle::join) it
+//! gives the gutter back, or the error or panic that ended the loop.
+
+use std::any::Any;
+use std::io::{Error, Read, Result, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use crate::{Gutter, Log};
+
+/// Handle to a pick-up loop started by [`Gutter::spawn_receiver`].
+///
+/// Dropping the handle detaches the thread, which keeps running.
+#[derive(Debug)]
+pub struct ReceiverHandle<G: Write> {
+    thread: JoinHandle<Result<Gutter<G>>>,
+    stop: Arc<AtomicBool>,
+}
+
+impl<G: Read + Write> ReceiverHandle<G> {
+    /// Ask the loop to stop after the log being picked up.
+    ///
+    /// The loop is still blocked until that log arrives, or until the
+    /// peer closes the gutter.
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::SeqCst);
+    }
+
+    /// Check whether the loop has ended.
+    pub fn is_finished(&self) -> bool {
+        self.thread.is_finished()
+    }
+
+    /// Wait for the loop to end, and give the gutter back.
+    ///
+    /// This function is blocking.
+    ///
+    /// This function fails with the error that ended the loop, if any.
+    /// A panic of the callback is turned into an error of kind
+    /// [`ErrorKind::Other`](std::io::ErrorKind::Other), carrying the panic message.
+    pub fn join(self) -> Result<Gutter<G>> {
+        self.thread.join().unwrap_or_else(|panic| {
+            Err(Error::other(format!(
+                "receiver callback panicked: {}",
+                panic_message(&*panic)
+            )))
+        })
+    }
+}
+
+impl<G: Read + Write + Send + 'static> Gutter<G> {
+    /// Move the gutter to a new thread, which picks up messages of type
+    /// `T` and passes each of them to `f`, until the peer closes the
+    /// gutter.
+    ///
+    /// The loop ends without error if the gutter is closed between two
+    /// messages, or when [stopped](ReceiverHandle::stop). Any other
+    /// error, or a panic of `f`, also ends it, and is reported by
+    /// [`ReceiverHandle::join`].
+    ///
+    /// This function doesn't change endianness, so it must be the
+    /// same between the peers.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```
+    /// use gutters::{throw, Gutter};
+    /// use std::net::{TcpListener, TcpStream};
+    /// use std::sync::mpsc;
+    ///
+    /// let listener = TcpListener::bind("127.0.0.1:0")?;
+    /// let mut stream = TcpStream::connect(listener.local_addr()?)?;
+    /// let gutter = Gutter::new(listener.accept()?.0);
+    ///
+    /// let (samples, received) = mpsc::channel();
+    /// let receiver = gutter.spawn_receiver(move |sample: f64| samples.send(sample).unwrap());
+    /// for i in 0..3 {
+    ///     throw(&mut stream, &(i as f64))?;
+    /// }
+    /// drop(stream);
+    ///
+    /// receiver.join()?;
+    /// assert_eq!(received.try_iter().collect::<Vec<_>>(), [0.0, 1.0, 2.0]);
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    pub fn spawn_receiver<T, F>(mut self, mut f: F) -> ReceiverHandle<G>
+    where
+        T: Log + Default,
+        F: FnMut(T) + Send + 'static,
+    {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let stop = stop.clone();
+            thread::spawn(move || {
+                while !stop.load(Ordering::SeqCst) && !self.at_eof()? {
+                    let mut log = T::default();
+                    self.pick_up(&mut log)?;
+                    f(log);
+                }
+                Ok(self)
+            })
+        };
+        ReceiverHandle { thread, stop }
+    }
+}
+
+pub(crate) fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown panic payload"
+    }
+}
@@ -0,0 +1,107 @@
+//! A process-wide registry of named gutters.
+//!
+//! Subsystems of a process often need to reach the same gutter, e.g. a
+//! telemetry [`Outbox`](crate::outbox::Outbox) or a control connection.
+//! Instead of a global per subsystem, [`register`] the gutter under a
+//! name once, and [`get`] it back from anywhere. The registry can also
+//! be [enumerated](names), e.g. to feed a [health](crate::health)
+//! report.
+//!
+//! Gutters are stored behind an [`Arc`], so anything shared must be
+//! [`Sync`]: register an outbox as is, but a raw stream behind a
+//! [`Mutex`].
+
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+type Entries = BTreeMap<String, Arc<dyn Any + Send + Sync>>;
+
+static REGISTRY: Mutex<Entries> = Mutex::new(BTreeMap::new());
+
+/// Register `gutter` under `name`, and return a shared handle to it.
+///
+/// Any gutter previously registered under `name` is replaced.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```
+/// use gutters::outbox::Outbox;
+/// use gutters::registry;
+/// use std::io::Cursor;
+///
+/// let (outbox, _sender) = Outbox::<f64>::spawn(Cursor::new(Vec::new()));
+/// registry::register("telemetry", outbox);
+///
+/// // Somewhere else in the process.
+/// let outbox = registry::get::<Outbox<f64>>("telemetry").unwrap();
+/// outbox.throw(12.5)?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn register<T: Any + Send + Sync>(name: &str, gutter: T) -> Arc<T> {
+    let gutter = Arc::new(gutter);
+    entries().insert(name.to_owned(), gutter.clone());
+    gutter
+}
+
+/// Look up the gutter registered under `name`.
+///
+/// This returns `None` if no gutter is registered under `name`, or if
+/// it is not of type `T`.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use gutters::{registry, throw};
+/// use std::net::TcpStream;
+/// use std::sync::Mutex;
+///
+/// let stream = TcpStream::connect("127.0.0.1:34567")?;
+/// registry::register("control", Mutex::new(stream));
+///
+/// // Somewhere else in the process.
+/// let control = registry::get::<Mutex<TcpStream>>("control").unwrap();
+/// throw(&mut *control.lock().unwrap(), &1u8)?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn get<T: Any + Send + Sync>(name: &str) -> Option<Arc<T>> {
+    let gutter = entries().get(name)?.clone();
+    gutter.downcast().ok()
+}
+
+/// Remove the gutter registered under `name` from the registry.
+///
+/// This returns `false` if no gutter was registered under `name`.
+/// Handles previously returned by [`get`] remain valid.
+pub fn unregister(name: &str) -> bool {
+    entries().remove(name).is_some()
+}
+
+/// List the names of all registered gutters, in alphabetical order.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```
+/// use gutters::registry;
+/// use std::io::Cursor;
+/// use std::sync::Mutex;
+///
+/// registry::register("b", Mutex::new(Cursor::new(Vec::<u8>::new())));
+/// registry::register("a", Mutex::new(Cursor::new(Vec::<u8>::new())));
+/// assert_eq!(registry::names(), ["a", "b"]);
+/// ```
+pub fn names() -> Vec<String> {
+    entries().keys().cloned().collect()
+}
+
+fn entries() -> MutexGuard<'static, Entries> {
+    // Entries are only ever inserted or removed whole, so a panic while
+    // the lock is held can't leave the map in an inconsistent state.
+    REGISTRY.lock().unwrap_or_else(PoisonError::into_inner)
+}
@@ -0,0 +1,297 @@
+//! Reconnection of flaky gutters.
+//!
+//! A [`ResilientGutter`] holds a function establishing a connection
+//! rather than a connection, and calls it again whenever the connection
+//! breaks, waiting longer and longer between failed attempts. Callers
+//! just [`throw`](ResilientGutter::throw) and
+//! [`pick_up`](ResilientGutter::pick_up) as if the connection were
+//! reliable. A connection counts as broken when it fails with
+//! [`ErrorKind::BrokenPipe`], [`ErrorKind::ConnectionReset`],
+//! [`ErrorKind::ConnectionAborted`], [`ErrorKind::NotConnected`] or
+//! [`ErrorKind::UnexpectedEof`]; other errors are returned as is. A
+//! peer closing the connection on purpose, between two logs or with a
+//! [`farewell`](crate::farewell), isn't reconnected to: the resulting
+//! [`Closed`] error is returned too.
+//!
+//! Reconnecting can't make the connection reliable, though. A log that
+//! failed to be thrown is thrown again on the new connection, so the
+//! peer may receive it twice if the old one had actually delivered it,
+//! and a log the peer threw while the connection was broken is lost. See
+//! [`failover`](crate::failover) for duplicate detection.
+
+use std::fmt;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::clock::{Clock, SystemClock};
+use crate::{Closed, Log};
+
+type Handshake<G> = Box<dyn FnMut(&mut G) -> Result<()> + Send>;
+
+/// A gutter reconnecting when its connection breaks.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```
+/// use gutters::resilient::ResilientGutter;
+/// use gutters::{pick_up, throw};
+/// use std::net::{TcpListener, TcpStream};
+/// use std::thread;
+///
+/// let listener = TcpListener::bind("127.0.0.1:0")?;
+/// let address = listener.local_addr()?;
+/// let server = thread::spawn(move || -> std::io::Result<()> {
+///     let (mut hello, mut data) = (0u32, 0u32);
+///     let (mut first, _) = listener.accept()?;
+///     pick_up(&mut first, &mut hello)?;
+///     pick_up(&mut first, &mut data)?;
+///     first.peek(&mut [0])?;
+///     drop(first); // The Wi-Fi goes down, with a log left unread.
+///
+///     let (mut second, _) = listener.accept()?;
+///     pick_up(&mut second, &mut hello)?;
+///     throw(&mut second, &(data * 2))
+/// });
+///
+/// let mut gutter = ResilientGutter::new(move || TcpStream::connect(address))
+///     .handshake(|stream| throw(stream, &0xc0ffeeu32));
+/// gutter.throw(&21u32)?;
+/// gutter.throw(&0u32)?;
+/// let mut answer = 0u32;
+/// gutter.pick_up(&mut answer)?;
+/// assert_eq!(answer, 42);
+/// assert_eq!(gutter.reconnections(), 1);
+/// server.join().unwrap()?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub struct ResilientGutter<G, C> {
+    connect: C,
+    handshake: Option<Handshake<G>>,
+    gutter: Option<G>,
+    initial_delay: Duration,
+    max_delay: Duration,
+    max_attempts: Option<u32>,
+    clock: Arc<dyn Clock>,
+    connected: bool,
+    reconnections: u64,
+}
+
+impl<G: Read + Write, C: FnMut() -> Result<G>> ResilientGutter<G, C> {
+    /// Create a gutter whose connections are established by `connect`.
+    ///
+    /// The first connection is only established once the gutter is
+    /// used. By default, failed attempts are retried forever, with a
+    /// delay doubling from 100 milliseconds up to 10 seconds.
+    pub fn new(connect: C) -> Self {
+        ResilientGutter {
+            connect,
+            handshake: None,
+            gutter: None,
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(10),
+            max_attempts: None,
+            clock: Arc::new(SystemClock),
+            connected: false,
+            reconnections: 0,
+        }
+    }
+
+    /// Run `handshake` on each new connection before using it.
+    ///
+    /// A connection whose handshake fails counts as a failed attempt.
+    pub fn handshake<F: FnMut(&mut G) -> Result<()> + Send + 'static>(
+        mut self,
+        handshake: F,
+    ) -> Self {
+        self.handshake = Some(Box::new(handshake));
+        self
+    }
+
+    /// Wait `initial` after the first failed attempt to connect, then
+    /// twice as long after each following one, up to `max`.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `initial` is longer than `max`.
+    pub fn backoff(mut self, initial: Duration, max: Duration) -> Self {
+        assert!(initial <= max, "initial delay is longer than the maximum");
+        self.initial_delay = initial;
+        self.max_delay = max;
+        self
+    }
+
+    /// Give up after `attempts` failed attempts in a row, returning the
+    /// error of the last one.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `attempts` is 0.
+    pub fn max_attempts(mut self, attempts: u32) -> Self {
+        assert!(
+            attempts > 0,
+            "a gutter must attempt to connect at least once"
+        );
+        self.max_attempts = Some(attempts);
+        self
+    }
+
+    /// Wait between failed attempts by [sleeping](Clock::sleep) on
+    /// `clock` rather than the system clock.
+    ///
+    /// Tests can pass a [`ManualClock`](crate::clock::ManualClock) to
+    /// go through the backoff without waiting.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```
+    /// use gutters::clock::{Clock, ManualClock};
+    /// use gutters::resilient::ResilientGutter;
+    /// use std::io::{Cursor, Error, ErrorKind};
+    /// use std::time::Duration;
+    ///
+    /// let clock = ManualClock::new();
+    /// let start = clock.now();
+    /// let mut refusals = 3;
+    /// let mut gutter = ResilientGutter::new(move || {
+    ///     if refusals > 0 {
+    ///         refusals -= 1;
+    ///         return Err(Error::from(ErrorKind::ConnectionRefused));
+    ///     }
+    ///     Ok(Cursor::new(Vec::new()))
+    /// })
+    /// .clock(clock.clone());
+    ///
+    /// gutter.throw(&1u32)?;
+    /// assert_eq!(clock.now() - start, Duration::from_millis(100 + 200 + 400));
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    pub fn clock<K: Clock + 'static>(mut self, clock: K) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Send a message of type `T` down the gutter, and flush it.
+    ///
+    /// This function is blocking.
+    ///
+    /// If the connection is broken, the message is thrown again on a new
+    /// one.
+    ///
+    /// This function doesn't change endianness, so it must be the
+    /// same between the peers.
+    pub fn throw<T: Log>(&mut self, buffer: &T) -> Result<()> {
+        self.retry(|gutter| {
+            crate::throw(gutter, buffer)?;
+            gutter.flush()
+        })
+    }
+
+    /// Read a message of type `T` from the gutter.
+    ///
+    /// This function is blocking.
+    ///
+    /// If the connection is broken, the message is read from a new one.
+    ///
+    /// This function doesn't change endianness, so it must be the
+    /// same between the peers.
+    pub fn pick_up<T: Log>(&mut self, buffer: &mut T) -> Result<()> {
+        self.retry(|gutter| crate::pick_up(gutter, buffer))
+    }
+
+    /// Return the number of times the gutter reconnected after its
+    /// connection broke.
+    pub fn reconnections(&self) -> u64 {
+        self.reconnections
+    }
+
+    /// Check whether the gutter currently holds a connection.
+    pub fn is_connected(&self) -> bool {
+        self.gutter.is_some()
+    }
+
+    /// Get a reference to the current connection, if any.
+    pub fn get_ref(&self) -> Option<&G> {
+        self.gutter.as_ref()
+    }
+
+    /// Move the current connection out, if any.
+    pub fn into_inner(self) -> Option<G> {
+        self.gutter
+    }
+
+    fn retry<F: FnMut(&mut G) -> Result<()>>(&mut self, mut operation: F) -> Result<()> {
+        loop {
+            let gutter = match &mut self.gutter {
+                Some(gutter) => gutter,
+                None => self.reconnect()?,
+            };
+            match operation(gutter) {
+                Err(e) if is_broken(&e) => self.gutter = None,
+                result => return result,
+            }
+        }
+    }
+
+    fn reconnect(&mut self) -> Result<&mut G> {
+        let mut delay = self.initial_delay;
+        let mut attempts = 0;
+        loop {
+            let attempt = (self.connect)().and_then(|mut gutter| {
+                if let Some(handshake) = &mut self.handshake {
+                    handshake(&mut gutter)?;
+                }
+                Ok(gutter)
+            });
+            match attempt {
+                Ok(gutter) => {
+                    if self.connected {
+                        self.reconnections += 1;
+                    }
+                    self.connected = true;
+                    return Ok(self.gutter.insert(gutter));
+                }
+                Err(e) => {
+                    attempts += 1;
+                    if self.max_attempts.is_some_and(|max| attempts >= max) {
+                        return Err(e);
+                    }
+                }
+            }
+            self.clock.sleep(delay);
+            delay = delay.saturating_mul(2).min(self.max_delay);
+        }
+    }
+}
+
+impl<G: fmt::Debug, C> fmt::Debug for ResilientGutter<G, C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ResilientGutter")
+            .field("gutter", &self.gutter)
+            .field("handshake", &self.handshake.is_some())
+            .field("initial_delay", &self.initial_delay)
+            .field("max_delay", &self.max_delay)
+            .field("max_attempts", &self.max_attempts)
+            .field("clock", &self.clock)
+            .field("reconnections", &self.reconnections)
+            .finish()
+    }
+}
+
+/// Check whether `error` means the connection is gone, rather than
+/// anything the caller should hear about, such as the peer closing it.
+fn is_broken(error: &Error) -> bool {
+    !Closed::is(error)
+        && matches!(
+            error.kind(),
+            ErrorKind::BrokenPipe
+                | ErrorKind::ConnectionReset
+                | ErrorKind::ConnectionAborted
+                | ErrorKind::NotConnected
+                | ErrorKind::UnexpectedEof
+        )
+}
@@ -0,0 +1,212 @@
+//! Detection of mismatched log types between peers.
+//!
+//! Picking up a log into a different type of the same size silently
+//! gives garbage. [`handshake`] lets the peers compare the size and
+//! fingerprint of their log type once, when they connect, while
+//! [`throw`] tags every log with its fingerprint, for gutters carrying
+//! several types. [`pick_up`] checks the fingerprint of a log of a known
+//! type, while [`pick_up_fingerprint`] and [`pick_up_tagged`] let the
+//! receiver choose the type from the fingerprint.
+//!
+//! Fingerprints are any `u64` the peers agree on, such as a version
+//! number of the log type. [`fingerprint`] derives one from the name of
+//! the type.
+
+use std::any;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::mem;
+
+use crate::{as_u8_slice, as_u8_slice_mut, Log};
+
+/// Derive a fingerprint from the name of the type `T`.
+///
+/// This only tells apart types of different names, not different
+/// versions of a type. The name of a type may also change between
+/// compiler versions, so both peers should be built with the same.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```
+/// use gutters::schema::fingerprint;
+///
+/// assert_eq!(fingerprint::<f64>(), fingerprint::<f64>());
+/// assert_ne!(fingerprint::<f64>(), fingerprint::<u64>());
+/// ```
+pub fn fingerprint<T: ?Sized>() -> u64 {
+    // 64-bit FNV-1a.
+    any::type_name::<T>()
+        .bytes()
+        .fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+        })
+}
+
+/// Check that the peer uses the same log type `T`, with the same
+/// `fingerprint`.
+///
+/// Both peers must call this function, typically right after
+/// connecting. It fails with [`ErrorKind::InvalidData`] if their log
+/// types differ in size or fingerprint.
+///
+/// This function is blocking.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```
+/// use gutters::schema::{fingerprint, handshake};
+/// use std::io::ErrorKind;
+/// use std::net::{TcpListener, TcpStream};
+/// use std::thread;
+///
+/// let listener = TcpListener::bind("127.0.0.1:0")?;
+/// let mut stream = TcpStream::connect(listener.local_addr()?)?;
+/// let peer = thread::spawn(move || -> std::io::Result<()> {
+///     let (mut stream, _) = listener.accept()?;
+///     handshake::<[u32; 2]>(&mut stream, fingerprint::<[u32; 2]>())
+/// });
+///
+/// let error = handshake::<u64>(&mut stream, fingerprint::<u64>()).unwrap_err();
+/// assert_eq!(error.kind(), ErrorKind::InvalidData);
+/// assert!(peer.join().unwrap().is_err());
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn handshake<T: Log>(gutter: &mut (impl Read + Write), fingerprint: u64) -> Result<()> {
+    let ours = [mem::size_of::<T>() as u64, fingerprint];
+    gutter.write_all(as_u8_slice(&ours))?;
+    gutter.flush()?;
+    let mut theirs = [0u64; 2];
+    gutter.read_exact(as_u8_slice_mut(&mut theirs))?;
+    if theirs != ours {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "peer logs are {} bytes with fingerprint {:#018x}, \
+                 but {} are {} bytes with fingerprint {:#018x}",
+                theirs[0],
+                theirs[1],
+                any::type_name::<T>(),
+                ours[0],
+                ours[1],
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Send a message of type `T` down the `gutter`, preceded by its
+/// `fingerprint`.
+///
+/// This function is blocking.
+///
+/// This function doesn't change endianness, so it must be the
+/// same between the peers.
+pub fn throw<G: Write, T: Log>(gutter: &mut G, fingerprint: u64, buffer: &T) -> Result<()> {
+    gutter.write_all(as_u8_slice(&fingerprint))?;
+    gutter.write_all(as_u8_slice(buffer))
+}
+
+/// Read a message of type `T` from the `gutter`, checking that it was
+/// thrown with the same `fingerprint`.
+///
+/// This function is blocking.
+///
+/// This function fails with [`ErrorKind::InvalidData`] if the
+/// fingerprints differ. The fingerprint is consumed then, but the body
+/// of the message is left in the gutter, which can't be used any
+/// further without skipping it. Use [`pick_up_fingerprint`] instead
+/// when several types can come.
+///
+/// This function doesn't change endianness, so it must be the
+/// same between the peers.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```
+/// use gutters::schema::{self, fingerprint};
+/// use std::io::{Cursor, ErrorKind};
+///
+/// const SAMPLE_V2: u64 = 2;
+/// let mut gutter = Cursor::new(Vec::new());
+/// schema::throw(&mut gutter, SAMPLE_V2, &[1.0f32, 2.0])?;
+///
+/// gutter.set_position(0);
+/// let mut data = 0u64;
+/// let error = schema::pick_up(&mut gutter, fingerprint::<u64>(), &mut data).unwrap_err();
+/// assert_eq!(error.kind(), ErrorKind::InvalidData);
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn pick_up<G: Read, T: Log>(gutter: &mut G, fingerprint: u64, buffer: &mut T) -> Result<()> {
+    let theirs = pick_up_fingerprint(gutter)?;
+    if theirs != fingerprint {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "log has fingerprint {:#018x}, but {} has fingerprint {:#018x}",
+                theirs,
+                any::type_name::<T>(),
+                fingerprint,
+            ),
+        ));
+    }
+    pick_up_tagged(gutter, buffer)
+}
+
+/// Read the fingerprint of the next message thrown by [`throw`], leaving
+/// its body in the gutter for [`pick_up_tagged`].
+///
+/// This function is blocking.
+///
+/// This function doesn't change endianness, so it must be the
+/// same between the peers.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```
+/// use gutters::schema::{self, fingerprint};
+/// use std::io::Cursor;
+///
+/// let mut gutter = Cursor::new(Vec::new());
+/// schema::throw(&mut gutter, fingerprint::<f64>(), &0.5f64)?;
+/// schema::throw(&mut gutter, fingerprint::<u16>(), &7u16)?;
+///
+/// gutter.set_position(0);
+/// let mut logs = Vec::new();
+/// for _ in 0..2 {
+///     let tag = schema::pick_up_fingerprint(&mut gutter)?;
+///     if tag == fingerprint::<f64>() {
+///         let mut data = 0.0f64;
+///         schema::pick_up_tagged(&mut gutter, &mut data)?;
+///         logs.push(data);
+///     } else if tag == fingerprint::<u16>() {
+///         let mut data = 0u16;
+///         schema::pick_up_tagged(&mut gutter, &mut data)?;
+///         logs.push(data as f64);
+///     }
+/// }
+/// assert_eq!(logs, [0.5, 7.0]);
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn pick_up_fingerprint<G: Read>(gutter: &mut G) -> Result<u64> {
+    let mut fingerprint = 0u64;
+    gutter.read_exact(as_u8_slice_mut(&mut fingerprint))?;
+    Ok(fingerprint)
+}
+
+/// Read the body of a message of type `T`, whose fingerprint was read
+/// by [`pick_up_fingerprint`].
+///
+/// This function is blocking.
+///
+/// This function doesn't change endianness, so it must be the
+/// same between the peers.
+pub fn pick_up_tagged<G: Read, T: Log>(gutter: &mut G, buffer: &mut T) -> Result<()> {
+    gutter.read_exact(as_u8_slice_mut(buffer))
+}
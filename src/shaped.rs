@@ -0,0 +1,268 @@
+//! In-memory gutters with simulated network conditions.
+//!
+//! [`pair`] connects two [`ShapedGutter`]s through memory, delaying what
+//! each of them writes according to a [`Shape`]: a fixed latency, random
+//! jitter, a bandwidth cap, and occasional reordering. This lets tests
+//! check how a protocol behaves over a slow or unreliable link without
+//! leaving the process.
+//!
+//! Delays apply to each write as a whole, like packets on a network. A
+//! reordered write is held back long enough for later ones to overtake
+//! it, so reordering only keeps logs intact if each of them is written
+//! at once, as [`throw`](crate::throw) does.
+
+use std::cmp;
+use std::io::{Read, Result, Write};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+use crate::Rng;
+
+/// Network conditions simulated by a [`ShapedGutter`].
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```
+/// use gutters::shaped::{self, Shape};
+/// use gutters::{pick_up, throw};
+/// use std::time::{Duration, Instant};
+///
+/// let shape = Shape::new()
+///     .latency(Duration::from_millis(20))
+///     .jitter(Duration::from_millis(5))
+///     .bandwidth(1_000_000);
+/// let (mut left, mut right) = shaped::pair(shape);
+///
+/// let start = Instant::now();
+/// throw(&mut left, &64.0f64)?;
+/// let mut data = 0.0f64;
+/// pick_up(&mut right, &mut data)?;
+/// assert!(start.elapsed() >= Duration::from_millis(20));
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Shape {
+    latency: Duration,
+    jitter: Duration,
+    bandwidth: Option<u64>,
+    reorder: f64,
+    seed: u64,
+}
+
+impl Shape {
+    /// Create a shape with no delay at all.
+    pub fn new() -> Self {
+        Shape {
+            latency: Duration::ZERO,
+            jitter: Duration::ZERO,
+            bandwidth: None,
+            reorder: 0.0,
+            seed: 0x9e37_79b9_7f4a_7c15,
+        }
+    }
+
+    /// Delay every write by `latency`.
+    pub fn latency(mut self, latency: Duration) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    /// Delay every write by a further random duration, up to `jitter`.
+    ///
+    /// Writes are still delivered in order, unless
+    /// [reordered](Shape::reorder).
+    pub fn jitter(mut self, jitter: Duration) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Cap the throughput of each direction to `bytes_per_second`.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `bytes_per_second` is zero.
+    pub fn bandwidth(mut self, bytes_per_second: u64) -> Self {
+        assert!(bytes_per_second > 0, "bandwidth is zero");
+        self.bandwidth = Some(bytes_per_second);
+        self
+    }
+
+    /// Hold back writes with the given `probability`, so that later
+    /// writes overtake them.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `probability` is not between 0 and 1.
+    pub fn reorder(mut self, probability: f64) -> Self {
+        assert!(
+            (0.0..=1.0).contains(&probability),
+            "reordering probability is not between 0 and 1"
+        );
+        self.reorder = probability;
+        self
+    }
+
+    /// Seed the random jitter and reordering, for reproducible runs.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+}
+
+impl Default for Shape {
+    fn default() -> Self {
+        Shape::new()
+    }
+}
+
+/// One end of an in-memory gutter with simulated network conditions.
+///
+/// Writes never block, however much is in flight. Reads block until
+/// some data has been delivered, and return end of file once the other
+/// end has been dropped and everything it wrote has been read.
+#[derive(Debug)]
+pub struct ShapedGutter {
+    incoming: Arc<Pipe>,
+    outgoing: Arc<Pipe>,
+    shape: Shape,
+    rng: Rng,
+    link_free_at: Instant,
+    last_delivery: Instant,
+}
+
+/// Create two gutters connected to each other, both delaying what they
+/// write according to `shape`.
+pub fn pair(shape: Shape) -> (ShapedGutter, ShapedGutter) {
+    let forward = Arc::new(Pipe::default());
+    let backward = Arc::new(Pipe::default());
+    let now = Instant::now();
+    let end = |incoming: &Arc<Pipe>, outgoing: &Arc<Pipe>, seed: u64| ShapedGutter {
+        incoming: incoming.clone(),
+        outgoing: outgoing.clone(),
+        shape,
+        rng: Rng::new(seed),
+        link_free_at: now,
+        last_delivery: now,
+    };
+    (
+        end(&backward, &forward, shape.seed),
+        end(&forward, &backward, shape.seed.rotate_left(32)),
+    )
+}
+
+#[derive(Debug, Default)]
+struct Pipe {
+    state: Mutex<PipeState>,
+    delivered: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct PipeState {
+    /// Writes in flight, by delivery time.
+    packets: Vec<Packet>,
+    closed: bool,
+}
+
+#[derive(Debug)]
+struct Packet {
+    deliver_at: Instant,
+    bytes: Vec<u8>,
+}
+
+impl Pipe {
+    fn lock(&self) -> MutexGuard<'_, PipeState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Write for ShapedGutter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let shape = self.shape;
+        let start = cmp::max(Instant::now(), self.link_free_at);
+        if let Some(bandwidth) = shape.bandwidth {
+            let nanos = buf.len() as u128 * 1_000_000_000 / bandwidth as u128;
+            self.link_free_at = start + Duration::from_nanos(nanos as u64);
+        } else {
+            self.link_free_at = start;
+        }
+        let jitter = shape.jitter.mul_f64(self.rng.next_f64());
+        let mut deliver_at = self.link_free_at + shape.latency + jitter;
+        if shape.reorder > 0.0 && self.rng.next_f64() < shape.reorder {
+            deliver_at += shape.latency + shape.jitter + Duration::from_millis(1);
+        } else {
+            deliver_at = cmp::max(deliver_at, self.last_delivery);
+            self.last_delivery = deliver_at;
+        }
+
+        let mut state = self.outgoing.lock();
+        let position = state
+            .packets
+            .partition_point(|packet| packet.deliver_at <= deliver_at);
+        state.packets.insert(
+            position,
+            Packet {
+                deliver_at,
+                bytes: buf.to_vec(),
+            },
+        );
+        self.outgoing.delivered.notify_all();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for ShapedGutter {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let mut state = self.incoming.lock();
+        loop {
+            let now = Instant::now();
+            let closed = state.closed;
+            match state.packets.first_mut() {
+                Some(packet) if packet.deliver_at <= now => {
+                    let n = cmp::min(buf.len(), packet.bytes.len());
+                    buf[..n].copy_from_slice(&packet.bytes[..n]);
+                    packet.bytes.drain(..n);
+                    if packet.bytes.is_empty() {
+                        state.packets.remove(0);
+                    }
+                    return Ok(n);
+                }
+                Some(packet) => {
+                    let delay = packet.deliver_at - now;
+                    state = self
+                        .incoming
+                        .delivered
+                        .wait_timeout(state, delay)
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .0;
+                }
+                None if closed => return Ok(0),
+                None => {
+                    state = self
+                        .incoming
+                        .delivered
+                        .wait(state)
+                        .unwrap_or_else(PoisonError::into_inner);
+                }
+            }
+        }
+    }
+}
+
+impl Drop for ShapedGutter {
+    fn drop(&mut self) {
+        self.outgoing.lock().closed = true;
+        self.outgoing.delivered.notify_all();
+    }
+}
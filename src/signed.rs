@@ -0,0 +1,285 @@
+//! Integrity-protected logs.
+//!
+//! [`throw`] appends an HMAC-SHA256 tag to each log, computed with a
+//! secret [`Key`] shared by both peers, and [`pick_up`] rejects logs
+//! whose tag doesn't match. Logs are *not* encrypted: anyone on the link
+//! can still read them, but nobody without the key can alter or forge
+//! them undetected.
+//!
+//! Signing doesn't prevent replays: a captured log and its tag can be
+//! thrown again verbatim.
+
+use std::io::{Error, ErrorKind, Read, Result, Write};
+
+use crate::{as_u8_slice, as_u8_slice_mut, read_exact_or_closed, Log};
+
+/// Length in bytes of the tag appended to each log.
+pub const TAG_LEN: usize = 32;
+
+/// A secret key for signing logs.
+#[derive(Clone)]
+pub struct Key {
+    inner: Sha256,
+    outer: Sha256,
+}
+
+impl Key {
+    /// Create a key from arbitrary `secret` bytes.
+    ///
+    /// Secrets should be at least 32 bytes long and random.
+    pub fn new(secret: &[u8]) -> Self {
+        let mut block = [0u8; 64];
+        if secret.len() > block.len() {
+            let mut hash = Sha256::new();
+            hash.update(secret);
+            block[..32].copy_from_slice(&hash.finalize());
+        } else {
+            block[..secret.len()].copy_from_slice(secret);
+        }
+
+        let mut inner = Sha256::new();
+        inner.update(&block.map(|b| b ^ 0x36));
+        let mut outer = Sha256::new();
+        outer.update(&block.map(|b| b ^ 0x5c));
+        Key { inner, outer }
+    }
+
+    /// Compute the HMAC-SHA256 tag of `data`.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```
+    /// use gutters::signed::Key;
+    ///
+    /// let tag = Key::new(b"Jefe").sign(b"what do ya want for nothing?");
+    /// let hex: String = tag.iter().map(|b| format!("{:02x}", b)).collect();
+    /// assert_eq!(hex, "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+    /// ```
+    ///
+    /// Keys longer than a block are hashed first, and data spanning
+    /// several blocks is hashed in turn, as in RFC 4231 test cases 6 and
+    /// 7:
+    ///
+    /// ```
+    /// use gutters::signed::Key;
+    ///
+    /// let hex = |tag: [u8; 32]| tag.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+    /// let key = Key::new(&[0xaa; 131]);
+    /// assert_eq!(
+    ///     hex(key.sign(b"Test Using Larger Than Block-Size Key - Hash Key First")),
+    ///     "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54",
+    /// );
+    /// assert_eq!(
+    ///     hex(key.sign(
+    ///         b"This is a test using a larger than block-size key and a larger than \
+    ///           block-size data. The key needs to be hashed before being used by the \
+    ///           HMAC algorithm."
+    ///     )),
+    ///     "9b09ffa71b942fcb27635fbcd5b0e944bfdc63644f0713938a7f51535c3a35e2",
+    /// );
+    /// ```
+    pub fn sign(&self, data: &[u8]) -> [u8; TAG_LEN] {
+        let mut inner = self.inner.clone();
+        inner.update(data);
+        let mut outer = self.outer.clone();
+        outer.update(&inner.finalize());
+        outer.finalize()
+    }
+
+    fn verify(&self, data: &[u8], tag: &[u8; TAG_LEN]) -> bool {
+        // Compare in constant time, so that the position of the first
+        // wrong byte doesn't leak.
+        let expected = self.sign(data);
+        expected
+            .iter()
+            .zip(tag)
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+    }
+}
+
+impl std::fmt::Debug for Key {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Key(..)")
+    }
+}
+
+/// Send a message of type `T` to the `gutter`, followed by its tag.
+///
+/// This function is blocking.
+///
+/// This function doesn't change endianness, so it must be the
+/// same between the peers.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use gutters::signed::{self, Key};
+/// use std::net::TcpStream;
+/// let mut stream = TcpStream::connect("127.0.0.1:34567")?;
+///
+/// let key = Key::new(b"an example very very secret key.");
+/// signed::throw(&mut stream, &key, &64.0)?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn throw<G: Write, T: Log>(gutter: &mut G, key: &Key, buffer: &T) -> Result<()> {
+    let bytes = as_u8_slice(buffer);
+    gutter.write_all(bytes)?;
+    gutter.write_all(&key.sign(bytes))
+}
+
+/// Read a message of type `T` from the `gutter`, and check its tag.
+///
+/// This function is blocking.
+///
+/// This function fails with [`ErrorKind::InvalidData`] if the tag
+/// doesn't match, in which case the content of `buffer` must not be
+/// used.
+///
+/// This function fails with a [`Closed`](crate::Closed) error if the
+/// peer closed the gutter before sending the message.
+///
+/// This function doesn't change endianness, so it must be the
+/// same between the peers.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```
+/// use gutters::signed::{self, Key};
+/// use std::io::{Cursor, ErrorKind};
+///
+/// let key = Key::new(b"an example very very secret key.");
+/// let mut gutter = Cursor::new(Vec::new());
+/// signed::throw(&mut gutter, &key, &64.0f64)?;
+///
+/// gutter.set_position(0);
+/// let mut data = 0.0f64;
+/// signed::pick_up(&mut gutter, &key, &mut data)?;
+/// assert_eq!(data, 64.0);
+///
+/// // Tamper with the log.
+/// gutter.get_mut()[0] ^= 1;
+/// gutter.set_position(0);
+/// let error = signed::pick_up(&mut gutter, &key, &mut data).unwrap_err();
+/// assert_eq!(error.kind(), ErrorKind::InvalidData);
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn pick_up<G: Read, T: Log>(gutter: &mut G, key: &Key, buffer: &mut T) -> Result<()> {
+    let bytes = as_u8_slice_mut(buffer);
+    read_exact_or_closed(gutter, bytes)?;
+    let mut tag = [0u8; TAG_LEN];
+    gutter.read_exact(&mut tag)?;
+    if !key.verify(bytes, &tag) {
+        return Err(Error::new(ErrorKind::InvalidData, "log signature mismatch"));
+    }
+    Ok(())
+}
+
+#[derive(Clone)]
+struct Sha256 {
+    state: [u32; 8],
+    block: [u8; 64],
+    buffered: usize,
+    length: u64,
+}
+
+const K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+impl Sha256 {
+    fn new() -> Self {
+        Sha256 {
+            state: [
+                0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
+                0x5be0cd19,
+            ],
+            block: [0u8; 64],
+            buffered: 0,
+            length: 0,
+        }
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        self.length += data.len() as u64;
+        while !data.is_empty() {
+            let n = (64 - self.buffered).min(data.len());
+            self.block[self.buffered..self.buffered + n].copy_from_slice(&data[..n]);
+            self.buffered += n;
+            data = &data[n..];
+            if self.buffered == 64 {
+                self.compress();
+                self.buffered = 0;
+            }
+        }
+    }
+
+    fn finalize(mut self) -> [u8; 32] {
+        let bits = self.length * 8;
+        self.update(&[0x80]);
+        while self.buffered != 56 {
+            self.update(&[0]);
+        }
+        self.update(&bits.to_be_bytes());
+
+        let mut digest = [0u8; 32];
+        for (bytes, word) in digest.chunks_exact_mut(4).zip(self.state) {
+            bytes.copy_from_slice(&word.to_be_bytes());
+        }
+        digest
+    }
+
+    fn compress(&mut self) {
+        let mut w = [0u32; 64];
+        for (word, bytes) in w.iter_mut().zip(self.block.chunks_exact(4)) {
+            *word = u32::from_be_bytes(bytes.try_into().unwrap());
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = self.state;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = h
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(K[i])
+                .wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+
+        for (state, value) in self.state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *state = state.wrapping_add(value);
+        }
+    }
+}
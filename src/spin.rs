@@ -0,0 +1,125 @@
+//! Busy-polling variants of the receiving functions.
+//!
+//! Blocking reads put the thread to sleep until data arrives, and the
+//! wakeup alone can cost tens of microseconds. The functions of this
+//! module instead expect a gutter in non-blocking mode (e.g. after
+//! [`TcpStream::set_nonblocking`](std::net::TcpStream::set_nonblocking))
+//! and spin on it until a full message is available, trading a whole
+//! CPU core for latency. Pair them with [`pin_to_core`] to keep that
+//! core to yourself.
+
+use std::hint;
+use std::io::{Error, ErrorKind, Read, Result};
+
+use crate::{as_u8_slice_mut, Closed, Log};
+
+/// Read a message of type `T` from the non-blocking `gutter`, spinning
+/// until it is complete.
+///
+/// This function never sleeps: it retries reads that would block with
+/// [`std::hint::spin_loop`] in between. Bytes already received are kept
+/// across retries, so messages are never split.
+///
+/// This function fails with a [`Closed`] error if the peer closed the
+/// gutter before sending the message.
+///
+/// This function doesn't change endianness, so it must be the
+/// same between the peers.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use gutters::spin;
+/// use std::net::TcpStream;
+/// let mut stream = TcpStream::connect("127.0.0.1:34567")?;
+/// stream.set_nonblocking(true)?;
+///
+/// let mut data = 0.0f64;
+/// spin::pick_up(&mut stream, &mut data)?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn pick_up<G: Read, T: Log>(gutter: &mut G, buffer: &mut T) -> Result<()> {
+    read_exact_spinning(gutter, as_u8_slice_mut(buffer))
+}
+
+/// Wait for an acknowledgment from the non-blocking `gutter`, spinning
+/// until it arrives.
+///
+/// The exact byte value of the acknowledgment is *not* checked for.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use gutters::spin;
+/// use std::net::TcpStream;
+/// let mut stream = TcpStream::connect("127.0.0.1:34567")?;
+/// stream.set_nonblocking(true)?;
+///
+/// spin::wait(&mut stream)?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn wait<G: Read>(gutter: &mut G) -> Result<()> {
+    read_exact_spinning(gutter, &mut [0u8])
+}
+
+/// Pin the calling thread to the CPU core of index `core`.
+///
+/// This is only available on Linux.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use gutters::spin::pin_to_core;
+///
+/// std::thread::spawn(|| {
+///     pin_to_core(3).unwrap();
+///     // Spin on the gutter here.
+/// });
+/// ```
+#[cfg(target_os = "linux")]
+pub fn pin_to_core(core: usize) -> Result<()> {
+    extern "C" {
+        fn sched_setaffinity(pid: i32, cpusetsize: usize, mask: *const u64) -> i32;
+    }
+
+    let mut mask = [0u64; 16];
+    if core >= 64 * mask.len() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "core index out of range",
+        ));
+    }
+    mask[core / 64] |= 1 << (core % 64);
+
+    // SAFETY: `mask` is a valid cpu_set_t of the given size, and a pid
+    // of 0 designates the calling thread.
+    let result = unsafe { sched_setaffinity(0, std::mem::size_of_val(&mask), mask.as_ptr()) };
+    if result != 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn read_exact_spinning<G: Read>(gutter: &mut G, mut buffer: &mut [u8]) -> Result<()> {
+    let len = buffer.len();
+    while !buffer.is_empty() {
+        match gutter.read(buffer) {
+            Ok(0) if buffer.len() == len => {
+                return Err(Error::new(ErrorKind::UnexpectedEof, Closed))
+            }
+            Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+            Ok(n) => buffer = &mut buffer[n..],
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::Interrupted) => {
+                hint::spin_loop()
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,167 @@
+//! Independent halves of a gutter, for full-duplex use.
+//!
+//! [`Gutter::split`] turns a buffered gutter into a [`ThrowHalf`] and a
+//! [`PickUpHalf`], each owning its own handle to the underlying socket,
+//! so that one thread can throw while another picks up without sharing
+//! a lock.
+
+use std::io::{Read, Result, Write};
+use std::net::TcpStream;
+
+use crate::{Gutter, Log};
+
+/// Gutters that can be duplicated into another handle to the same
+/// connection.
+pub trait TryClone: Sized {
+    /// Create another handle to the same connection.
+    fn try_clone(&self) -> Result<Self>;
+}
+
+impl TryClone for TcpStream {
+    fn try_clone(&self) -> Result<Self> {
+        TcpStream::try_clone(self)
+    }
+}
+
+#[cfg(unix)]
+impl TryClone for std::os::unix::net::UnixStream {
+    fn try_clone(&self) -> Result<Self> {
+        std::os::unix::net::UnixStream::try_clone(self)
+    }
+}
+
+/// Sending half of a [split](Gutter::split) gutter.
+#[derive(Debug)]
+pub struct ThrowHalf<G: Read + Write>(Gutter<G>);
+
+/// Receiving half of a [split](Gutter::split) gutter.
+#[derive(Debug)]
+pub struct PickUpHalf<G: Read + Write>(Gutter<G>);
+
+impl<G: Read + Write + TryClone> Gutter<G> {
+    /// Flush pending logs, and split the gutter into halves that can be
+    /// moved to different threads.
+    ///
+    /// The throwing half gets a write buffer of the same capacity, and
+    /// the picking up half keeps any data already read.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```
+    /// use gutters::{pick_up, throw, Gutter};
+    /// use std::net::{TcpListener, TcpStream};
+    /// use std::thread;
+    ///
+    /// let listener = TcpListener::bind("127.0.0.1:0")?;
+    /// let gutter = Gutter::new(TcpStream::connect(listener.local_addr()?)?);
+    /// let (mut peer, _) = listener.accept()?;
+    ///
+    /// let (mut throw_half, mut pick_up_half) = gutter.split()?;
+    /// let thrower = thread::spawn(move || -> std::io::Result<()> {
+    ///     for i in 0..100u32 {
+    ///         throw_half.throw(&i)?;
+    ///     }
+    ///     throw_half.flush()
+    /// });
+    ///
+    /// throw(&mut peer, &64.0f64)?;
+    /// let mut data = 0.0f64;
+    /// pick_up_half.pick_up(&mut data)?;
+    /// assert_eq!(data, 64.0);
+    ///
+    /// thrower.join().unwrap()?;
+    /// let mut last = 0u32;
+    /// for _ in 0..100 {
+    ///     pick_up(&mut peer, &mut last)?;
+    /// }
+    /// assert_eq!(last, 99);
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    pub fn split(mut self) -> Result<(ThrowHalf<G>, PickUpHalf<G>)> {
+        self.flush()?;
+        let clone = self.get_ref().try_clone()?;
+        let thrower = Gutter::with_capacity(0, self.write_capacity(), clone);
+        Ok((ThrowHalf(thrower), PickUpHalf(self)))
+    }
+}
+
+impl<G: Read + Write> ThrowHalf<G> {
+    /// Buffer a message of type `T` to be sent.
+    ///
+    /// See [`Gutter::throw`].
+    pub fn throw<T: Log>(&mut self, buffer: &T) -> Result<()> {
+        self.0.throw(buffer)
+    }
+
+    /// Buffer an acknowledgment to be sent.
+    ///
+    /// See [`Gutter::hail`].
+    pub fn hail(&mut self) -> Result<()> {
+        self.0.hail()
+    }
+
+    /// Send all pending logs down the gutter.
+    ///
+    /// This function is blocking.
+    pub fn flush(&mut self) -> Result<()> {
+        self.0.flush()
+    }
+
+    /// Get a reference to the underlying gutter.
+    pub fn get_ref(&self) -> &G {
+        self.0.get_ref()
+    }
+}
+
+impl<G: Read + Write> Write for ThrowHalf<G> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.0.flush()
+    }
+}
+
+impl<G: Read + Write> PickUpHalf<G> {
+    /// Read a message of type `T`.
+    ///
+    /// See [`Gutter::pick_up`].
+    pub fn pick_up<T: Log>(&mut self, buffer: &mut T) -> Result<()> {
+        self.0.pick_up(buffer)
+    }
+
+    /// Read a message of type `T` if it has fully arrived.
+    ///
+    /// See [`Gutter::try_pick_up`].
+    pub fn try_pick_up<T: Log + Default>(&mut self) -> Result<Option<T>> {
+        self.0.try_pick_up()
+    }
+
+    /// Wait for an acknowledgment.
+    ///
+    /// See [`Gutter::wait`].
+    pub fn wait(&mut self) -> Result<()> {
+        self.0.wait()
+    }
+
+    /// Wait for an acknowledgment if it has arrived.
+    ///
+    /// See [`Gutter::try_wait`].
+    pub fn try_wait(&mut self) -> Result<Option<()>> {
+        self.0.try_wait()
+    }
+
+    /// Get a reference to the underlying gutter.
+    pub fn get_ref(&self) -> &G {
+        self.0.get_ref()
+    }
+}
+
+impl<G: Read + Write> Read for PickUpHalf<G> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.0.read(buf)
+    }
+}
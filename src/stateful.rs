@@ -0,0 +1,282 @@
+//! State synchronization with snapshots and deltas.
+//!
+//! A [`StatefulPublisher`] owns a [`State`] and any number of subscriber
+//! gutters. Each new subscriber first receives a snapshot of the whole
+//! state, then every delta [published](StatefulPublisher::publish) from
+//! then on. A [`StatefulSubscriber`] applies them as they arrive, so that
+//! it always holds a copy of the publisher's state.
+//!
+//! When both peers modify the state, each holds a [`Replica`] instead,
+//! which merges the updates of the other through a user-provided
+//! function.
+
+use std::io::{Error, ErrorKind, Read, Result, Write};
+
+use crate::{as_u8_slice, pick_up, Log};
+
+/// A state that can be updated by deltas.
+pub trait State {
+    /// Update of the state, thrown to subscribers.
+    type Delta: Log + Default;
+
+    /// Apply `delta` to the state.
+    fn apply(&mut self, delta: &Self::Delta);
+}
+
+/// Publishing side of a synchronized state.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```
+/// use gutters::stateful::{State, StatefulPublisher, StatefulSubscriber};
+/// use gutters::Log;
+/// use std::net::{TcpListener, TcpStream};
+///
+/// #[derive(Default, Clone, Copy)]
+/// #[repr(C)]
+/// struct Counters([u32; 4]);
+/// unsafe impl Log for Counters {}
+///
+/// impl State for Counters {
+///     type Delta = [u32; 2]; // index, increment
+///     fn apply(&mut self, delta: &[u32; 2]) {
+///         self.0[delta[0] as usize] += delta[1];
+///     }
+/// }
+///
+/// let listener = TcpListener::bind("127.0.0.1:0")?;
+/// let mut publisher = StatefulPublisher::new(Counters([1, 2, 3, 4]));
+/// publisher.publish(&[0, 10]);
+///
+/// let stream = TcpStream::connect(listener.local_addr()?)?;
+/// publisher.subscribe(listener.accept()?.0)?;
+/// let mut subscriber = StatefulSubscriber::<Counters, _>::connect(stream)?;
+/// assert_eq!(subscriber.state().0, [11, 2, 3, 4]);
+///
+/// publisher.publish(&[3, 1]);
+/// subscriber.update()?;
+/// assert_eq!(subscriber.state().0, [11, 2, 3, 5]);
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Debug)]
+pub struct StatefulPublisher<S, G> {
+    state: S,
+    subscribers: Vec<G>,
+}
+
+impl<S: State + Log, G: Write> StatefulPublisher<S, G> {
+    /// Create a publisher of `state`, with no subscriber.
+    pub fn new(state: S) -> Self {
+        StatefulPublisher {
+            state,
+            subscribers: Vec::new(),
+        }
+    }
+
+    /// Send a snapshot of the state to `gutter`, and keep it as a
+    /// subscriber.
+    ///
+    /// This function is blocking.
+    pub fn subscribe(&mut self, mut gutter: G) -> Result<()> {
+        gutter.write_all(as_u8_slice(&self.state))?;
+        gutter.flush()?;
+        self.subscribers.push(gutter);
+        Ok(())
+    }
+
+    /// Apply `delta` to the state, and send it to every subscriber.
+    ///
+    /// This function is blocking.
+    ///
+    /// Subscribers whose gutter fails are dropped. This returns the
+    /// number of subscribers left.
+    pub fn publish(&mut self, delta: &S::Delta) -> usize {
+        self.state.apply(delta);
+        let bytes = as_u8_slice(delta);
+        self.subscribers.retain_mut(|gutter| {
+            gutter
+                .write_all(bytes)
+                .and_then(|()| gutter.flush())
+                .is_ok()
+        });
+        self.subscribers.len()
+    }
+
+    /// Get the current state.
+    pub fn state(&self) -> &S {
+        &self.state
+    }
+
+    /// Return the number of subscribers.
+    pub fn subscribers(&self) -> usize {
+        self.subscribers.len()
+    }
+}
+
+/// Subscribing side of a synchronized state.
+///
+/// See [`StatefulPublisher`].
+#[derive(Debug)]
+pub struct StatefulSubscriber<S, G> {
+    state: S,
+    gutter: G,
+}
+
+impl<S: State + Log + Default, G: Read> StatefulSubscriber<S, G> {
+    /// Pick up the snapshot of the state from `gutter`.
+    ///
+    /// This function is blocking.
+    pub fn connect(mut gutter: G) -> Result<Self> {
+        let mut state = S::default();
+        pick_up(&mut gutter, &mut state)?;
+        Ok(StatefulSubscriber { state, gutter })
+    }
+
+    /// Pick up the next delta, apply it to the state, and return it.
+    ///
+    /// This function is blocking.
+    pub fn update(&mut self) -> Result<S::Delta> {
+        let mut delta = S::Delta::default();
+        pick_up(&mut self.gutter, &mut delta)?;
+        self.state.apply(&delta);
+        Ok(delta)
+    }
+
+    /// Get the current state.
+    pub fn state(&self) -> &S {
+        &self.state
+    }
+
+    /// Move the gutter out.
+    pub fn into_inner(self) -> G {
+        self.gutter
+    }
+}
+
+/// One of two peers that both modify a shared state.
+///
+/// Each update is applied locally, then thrown with a two-entry vector
+/// clock: the number of updates made by the sender, and the number of
+/// updates of the receiver it had applied when making it. Picked up
+/// updates go through the merge function rather than [`State::apply`],
+/// along with whether they are concurrent with a local update the
+/// sender hadn't seen yet. Merge functions that commute for concurrent
+/// updates keep both replicas equal.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```
+/// use gutters::stateful::{Replica, State};
+/// use gutters::Log;
+/// use std::net::{TcpListener, TcpStream};
+///
+/// #[derive(Default, Clone, Copy)]
+/// #[repr(C)]
+/// struct Counter(i64);
+/// unsafe impl Log for Counter {}
+///
+/// impl State for Counter {
+///     type Delta = i64;
+///     fn apply(&mut self, delta: &i64) {
+///         self.0 += delta;
+///     }
+/// }
+///
+/// // Additions commute, so concurrent updates need no special care.
+/// let merge = |state: &mut Counter, delta: &i64, _concurrent: bool| state.apply(delta);
+///
+/// let listener = TcpListener::bind("127.0.0.1:0")?;
+/// let stream = TcpStream::connect(listener.local_addr()?)?;
+/// let mut left = Replica::new(Counter(0), stream, merge);
+/// let mut right = Replica::new(Counter(0), listener.accept()?.0, merge);
+///
+/// left.update(&5)?;
+/// right.update(&-2)?;
+/// assert!(left.sync()?.1);
+/// assert!(right.sync()?.1);
+/// assert_eq!(left.state().0, 3);
+/// assert_eq!(right.state().0, 3);
+/// assert_eq!(left.clock(), [1, 1]);
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Debug)]
+pub struct Replica<S, G, F> {
+    state: S,
+    gutter: G,
+    merge: F,
+    clock: [u64; 2],
+}
+
+impl<S, G, F> Replica<S, G, F>
+where
+    S: State,
+    G: Read + Write,
+    F: FnMut(&mut S, &S::Delta, bool),
+{
+    /// Create a replica of `state` synchronized through `gutter`.
+    ///
+    /// Both peers must start from the same state.
+    pub fn new(state: S, gutter: G, merge: F) -> Self {
+        Replica {
+            state,
+            gutter,
+            merge,
+            clock: [0, 0],
+        }
+    }
+
+    /// Apply `delta` to the local state, and send it to the peer.
+    ///
+    /// This function is blocking.
+    pub fn update(&mut self, delta: &S::Delta) -> Result<()> {
+        self.state.apply(delta);
+        self.clock[0] += 1;
+        self.gutter.write_all(as_u8_slice(&self.clock))?;
+        self.gutter.write_all(as_u8_slice(delta))?;
+        self.gutter.flush()
+    }
+
+    /// Pick up the next update of the peer, and merge it into the local
+    /// state.
+    ///
+    /// This function is blocking.
+    ///
+    /// This returns the update, and whether it was concurrent with a
+    /// local one.
+    pub fn sync(&mut self) -> Result<(S::Delta, bool)> {
+        let mut clock = [0u64; 2];
+        pick_up(&mut self.gutter, &mut clock)?;
+        let mut delta = S::Delta::default();
+        pick_up(&mut self.gutter, &mut delta)?;
+        if clock[0] != self.clock[1] + 1 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "replica update is out of sequence",
+            ));
+        }
+        self.clock[1] = clock[0];
+        let concurrent = clock[1] < self.clock[0];
+        (self.merge)(&mut self.state, &delta, concurrent);
+        Ok((delta, concurrent))
+    }
+
+    /// Get the current state.
+    pub fn state(&self) -> &S {
+        &self.state
+    }
+
+    /// Return the vector clock: the number of local updates, and the
+    /// number of updates picked up from the peer.
+    pub fn clock(&self) -> [u64; 2] {
+        self.clock
+    }
+
+    /// Move the gutter out.
+    pub fn into_inner(self) -> G {
+        self.gutter
+    }
+}
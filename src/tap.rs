@@ -0,0 +1,373 @@
+//! Live inspection of running gutters.
+//!
+//! Wrapping a gutter in a [`TappedGutter`] gives it a name an inspector
+//! can select it by. As long as no inspector is attached, this costs an
+//! atomic load per read or write. Once [`serve`] runs on a debug socket,
+//! an inspector can [`attach`] to it at any time, and receive copies of
+//! the traffic of the gutters it selected as a
+//! [capture](crate::capture), without restarting the process with
+//! recording enabled.
+//!
+//! Each gutter keeps the inspectors that selected it, and only looks
+//! them up again when one attaches or goes away, so gutters nobody
+//! selected don't contend with the others.
+//!
+//! Copies are queued for each inspector, and dropped if it doesn't keep
+//! up, so that a slow inspector never holds up the tapped gutters. The
+//! [`Filter`] of an inspector can also sample the traffic, to keep the
+//! overhead low on busy gutters.
+//!
+//! Tap names are unrelated to the names of the
+//! [registry](crate::registry): a registry name refers to a single
+//! shared gutter, while a tap name typically covers many connections,
+//! e.g. all those served by a [`Drain`](crate::drain::Drain), which are
+//! never registered. Register a `TappedGutter` to have both.
+//!
+//! The debug socket gives access to all the traffic of the tapped
+//! gutters, so it should only be reachable by trusted peers.
+
+use std::io::{self, BufWriter, Error, ErrorKind, Read, Result, Write};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, PoisonError, RwLock};
+use std::thread;
+use std::time::Instant;
+
+use crate::capture::{CaptureReader, CaptureWriter, Direction};
+use crate::drain::Listener;
+use crate::split::TryClone;
+use crate::{pick_up, pick_up_framed, throw, throw_framed};
+
+/// Number of copies queued for an inspector before new ones are dropped.
+const QUEUE_LEN: usize = 1024;
+
+const SENT: u32 = 1;
+const RECEIVED: u32 = 2;
+
+#[derive(Debug)]
+struct Inspector {
+    names: Vec<String>,
+    directions: u32,
+    sample: u32,
+    seen: AtomicU64,
+    /// Copies for the inspector, or `None` once it has hung up.
+    queue: SyncSender<Option<Copied>>,
+    gone: AtomicBool,
+}
+
+struct Copied {
+    direction: Direction,
+    peer: u32,
+    instant: Instant,
+    bytes: Vec<u8>,
+}
+
+static INSPECTORS: RwLock<Vec<Arc<Inspector>>> = RwLock::new(Vec::new());
+static ATTACHED: AtomicUsize = AtomicUsize::new(0);
+/// Incremented whenever an inspector attaches or goes away, so that
+/// tapped gutters know to look up their inspectors again.
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// Change the list of inspectors with `change`.
+fn change_inspectors<F: FnOnce(&mut Vec<Arc<Inspector>>)>(change: F) {
+    let mut inspectors = INSPECTORS.write().unwrap_or_else(PoisonError::into_inner);
+    change(&mut inspectors);
+    ATTACHED.store(inspectors.len(), Ordering::Relaxed);
+    GENERATION.fetch_add(1, Ordering::Release);
+}
+
+/// Selection of the traffic an inspector receives.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use gutters::capture::Direction;
+/// use gutters::tap::{self, Filter};
+/// use std::net::TcpStream;
+///
+/// let stream = TcpStream::connect("127.0.0.1:9001")?;
+/// let filter = Filter::new(["telemetry"]).direction(Direction::Sent).sample(100);
+/// let mut capture = tap::attach(stream, &filter)?;
+/// while let Some(frame) = capture.read_frame()? {
+///     println!("{:?} {} bytes", frame.timestamp, frame.bytes.len());
+/// }
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Filter {
+    names: Vec<String>,
+    directions: u32,
+    sample: u32,
+}
+
+impl Filter {
+    /// Select the traffic of the gutters tapped under any of `names`,
+    /// in both directions and without sampling.
+    ///
+    /// Frames received by the inspector have the index of the name of
+    /// their gutter in `names` as peer id.
+    pub fn new<I: IntoIterator<Item = S>, S: Into<String>>(names: I) -> Self {
+        Filter {
+            names: names.into_iter().map(Into::into).collect(),
+            directions: SENT | RECEIVED,
+            sample: 1,
+        }
+    }
+
+    /// Only select the traffic going in `direction`.
+    pub fn direction(mut self, direction: Direction) -> Self {
+        self.directions = match direction {
+            Direction::Sent => SENT,
+            Direction::Received => RECEIVED,
+        };
+        self
+    }
+
+    /// Only select one in every `n` frames.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `n` is 0.
+    pub fn sample(mut self, n: u32) -> Self {
+        assert!(n > 0, "sampling must keep one in at least one frame");
+        self.sample = n;
+        self
+    }
+}
+
+/// A gutter whose traffic can be inspected live.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```
+/// use gutters::tap::{self, Filter, TappedGutter};
+/// use gutters::throw;
+/// use std::io::Cursor;
+/// use std::net::{TcpListener, TcpStream};
+/// use std::thread;
+///
+/// let listener = TcpListener::bind("127.0.0.1:0")?;
+/// let address = listener.local_addr()?;
+/// thread::spawn(move || tap::serve(listener));
+///
+/// let mut gutter = TappedGutter::new("telemetry", Cursor::new(Vec::new()));
+/// throw(&mut gutter, &1u32)?; // Nobody is looking yet.
+///
+/// let mut capture = tap::attach(TcpStream::connect(address)?, &Filter::new(["telemetry"]))?;
+/// throw(&mut gutter, &2u32)?;
+/// let frame = capture.read_frame()?.unwrap();
+/// assert_eq!(frame.bytes, 2u32.to_ne_bytes());
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Debug)]
+pub struct TappedGutter<G> {
+    name: String,
+    gutter: G,
+    generation: u64,
+    selected: Vec<(Arc<Inspector>, u32)>,
+}
+
+impl<G> TappedGutter<G> {
+    /// Wrap `gutter`, letting inspectors select it by `name`.
+    ///
+    /// Several gutters may share a name, in which case inspectors
+    /// selecting it receive the traffic of all of them.
+    pub fn new(name: &str, gutter: G) -> Self {
+        TappedGutter {
+            name: name.to_owned(),
+            gutter,
+            generation: u64::MAX,
+            selected: Vec::new(),
+        }
+    }
+
+    fn tap(&mut self, direction: Direction, bytes: &[u8]) {
+        if bytes.is_empty() || ATTACHED.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        let generation = GENERATION.load(Ordering::Acquire);
+        if generation != self.generation {
+            let inspectors = INSPECTORS.read().unwrap_or_else(PoisonError::into_inner);
+            self.selected = inspectors
+                .iter()
+                .filter_map(|inspector| {
+                    let peer = inspector.names.iter().position(|n| *n == self.name)?;
+                    Some((Arc::clone(inspector), peer as u32))
+                })
+                .collect();
+            self.generation = generation;
+        }
+
+        let instant = Instant::now();
+        let bit = match direction {
+            Direction::Sent => SENT,
+            Direction::Received => RECEIVED,
+        };
+        let mut gone = false;
+        for (inspector, peer) in &self.selected {
+            if inspector.directions & bit == 0 {
+                continue;
+            }
+            let seen = inspector.seen.fetch_add(1, Ordering::Relaxed) + 1;
+            if seen % u64::from(inspector.sample) != 0 {
+                continue;
+            }
+            let copy = Copied {
+                direction,
+                peer: *peer,
+                instant,
+                bytes: bytes.to_vec(),
+            };
+            if let Err(TrySendError::Disconnected(_)) = inspector.queue.try_send(Some(copy)) {
+                inspector.gone.store(true, Ordering::Relaxed);
+                gone = true;
+            }
+        }
+        if gone {
+            change_inspectors(|inspectors| {
+                inspectors.retain(|inspector| !inspector.gone.load(Ordering::Relaxed))
+            });
+        }
+    }
+
+    /// Get the name of the gutter.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Get a reference to the underlying gutter.
+    pub fn get_ref(&self) -> &G {
+        &self.gutter
+    }
+
+    /// Move the underlying gutter out.
+    pub fn into_inner(self) -> G {
+        self.gutter
+    }
+}
+
+impl<G: Read> Read for TappedGutter<G> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let len = self.gutter.read(buf)?;
+        self.tap(Direction::Received, &buf[..len]);
+        Ok(len)
+    }
+}
+
+impl<G: Write> Write for TappedGutter<G> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let len = self.gutter.write(buf)?;
+        self.tap(Direction::Sent, &buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.gutter.flush()
+    }
+}
+
+/// Accept inspectors on `listener` forever, streaming them the traffic
+/// they select.
+///
+/// Each inspector is served on a thread of its own, and another one
+/// watching a [clone](TryClone) of its connection, so that it is
+/// detached as soon as it hangs up, even if the gutters it selected
+/// see no traffic.
+///
+/// This function is blocking, and only returns if accepting a
+/// connection fails, other than because the peer gave up first.
+pub fn serve<L: Listener>(listener: L) -> Result<()>
+where
+    L::Stream: TryClone,
+{
+    loop {
+        let stream = match listener.accept_stream() {
+            Ok(stream) => stream,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    ErrorKind::ConnectionAborted | ErrorKind::Interrupted
+                ) =>
+            {
+                continue
+            }
+            Err(e) => return Err(e),
+        };
+        thread::spawn(move || {
+            // An inspector going away is its own problem, not the
+            // process'.
+            let _ = stream_to(stream);
+        });
+    }
+}
+
+fn stream_to<S: Read + Write + TryClone + Send + 'static>(mut stream: S) -> Result<()> {
+    let mut request = [0u32; 2];
+    pick_up(&mut stream, &mut request)?;
+    let [directions, sample] = request;
+    let names = String::from_utf8(pick_up_framed(&mut stream)?)
+        .map_err(|_| Error::new(ErrorKind::InvalidData, "gutter names are not UTF-8"))?;
+    if sample == 0 {
+        return Err(Error::new(ErrorKind::InvalidData, "invalid sampling"));
+    }
+
+    let (queue, copies) = mpsc::sync_channel(QUEUE_LEN);
+    let inspector = Arc::new(Inspector {
+        names: names.split('\n').map(str::to_owned).collect(),
+        directions,
+        sample,
+        seen: AtomicU64::new(0),
+        queue: queue.clone(),
+        gone: AtomicBool::new(false),
+    });
+
+    // The inspector never sends anything once attached, so the end of
+    // its connection means it hung up.
+    let mut watched = stream.try_clone()?;
+    thread::spawn(move || {
+        let _ = io::copy(&mut watched, &mut io::sink());
+        let _ = queue.send(None);
+    });
+
+    change_inspectors(|inspectors| inspectors.push(Arc::clone(&inspector)));
+    // The header tells the inspector it is attached, so it must only be
+    // sent once the inspector is registered.
+    let result = CaptureWriter::new(BufWriter::new(stream), &[]).and_then(|mut capture| {
+        capture.flush()?;
+        forward(copies, capture)
+    });
+    inspector.gone.store(true, Ordering::Relaxed);
+    change_inspectors(|inspectors| inspectors.retain(|other| !Arc::ptr_eq(other, &inspector)));
+    result
+}
+
+fn forward<W: Write>(
+    copies: Receiver<Option<Copied>>,
+    mut capture: CaptureWriter<W>,
+) -> Result<()> {
+    while let Ok(Some(copy)) = copies.recv() {
+        capture.write_frame_at(copy.direction, copy.peer, copy.instant, &copy.bytes)?;
+        capture.flush()?;
+    }
+    Ok(())
+}
+
+/// Attach an inspector to the debug socket at the other end of
+/// `stream`, and return the capture of the traffic selected by
+/// `filter`.
+///
+/// This function is blocking.
+///
+/// Only traffic from after this function returns is captured. Reading
+/// frames from the capture blocks until the selected gutters see some
+/// traffic.
+pub fn attach<S: Read + Write>(mut stream: S, filter: &Filter) -> Result<CaptureReader<S>> {
+    throw(&mut stream, &[filter.directions, filter.sample])?;
+    throw_framed(&mut stream, filter.names.join("\n").as_bytes())?;
+    stream.flush()?;
+    CaptureReader::new(stream)
+}
@@ -0,0 +1,528 @@
+//! Helpers for testing protocols built on top of gutters.
+//!
+//! The roundtrip functions run messages through the library against an
+//! in-memory gutter, so message types can be checked without a peer
+//! on the other side. [`roundtrip_with`] goes through any pair of
+//! throwing and picking up functions, e.g. those of the
+//! [checksum](crate::checksum) or [endian](crate::endian) modules, and
+//! [`roundtrip_framed`] and [`roundtrip_compressed`] through frames.
+//! The servers provide ready-made peers for load and soak tests.
+//!
+//! A [`pair`] of in-memory gutters connects two ends of a protocol
+//! without sockets, and a [`FaultyGutter`] makes either of them
+//! misbehave in the ways real gutters do.
+//!
+//! Golden files, checked with [`assert_golden`], catch unintended
+//! changes to the wire format of message types.
+
+use std::cmp;
+use std::collections::VecDeque;
+use std::fmt::{Debug, Write as _};
+use std::fs;
+use std::io::{self, Cursor, Error, ErrorKind, Read, Result, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+use crate::compressed::{Codec, CompressedGutter};
+use crate::shaped::{self, Shape, ShapedGutter};
+use crate::{as_u8_slice, pick_up, pick_up_vec, throw, throw_framed, Log};
+
+/// Send `log` down an in-memory gutter and pick it back up.
+///
+/// The returned value is what a peer would have received.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```
+/// use gutters::testing::roundtrip;
+///
+/// let log = roundtrip(&[1.0f64, 2.0, 3.0])?;
+/// assert_eq!(log, [1.0, 2.0, 3.0]);
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn roundtrip<T: Log + Default>(log: &T) -> Result<T> {
+    let mut gutter = Cursor::new(Vec::new());
+    throw(&mut gutter, log)?;
+
+    gutter.set_position(0);
+    let mut received = T::default();
+    pick_up(&mut gutter, &mut received)?;
+    Ok(received)
+}
+
+/// Assert that `log` survives a [`roundtrip`] unchanged.
+///
+/// This function panics if the gutter fails or if the received value
+/// differs from `log`, which makes it usable directly inside tests and
+/// property-based test cases.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```
+/// use gutters::testing::assert_roundtrip;
+///
+/// for i in 0..1000u32 {
+///     assert_roundtrip(&[i as f32, i as f32 * 0.5]);
+/// }
+/// ```
+pub fn assert_roundtrip<T: Log + Default + PartialEq + Debug>(log: &T) {
+    match roundtrip(log) {
+        Ok(received) => assert_eq!(&received, log, "log did not survive the roundtrip"),
+        Err(e) => panic!("roundtrip failed: {}", e),
+    }
+}
+
+/// Send `log` down an in-memory gutter with `throw`, and pick it back up
+/// with `pick_up`.
+///
+/// This runs `log` through layers such as checksums, signatures, byte
+/// order conversion or schema tags, which have throwing and picking up
+/// functions of their own.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```
+/// use gutters::signed::{self, Key};
+/// use gutters::testing::roundtrip_with;
+/// use gutters::{checksum, endian, schema};
+///
+/// let log = [1.0f64, 2.0, 3.0];
+/// assert_eq!(roundtrip_with(&log, checksum::throw, checksum::pick_up)?, log);
+/// assert_eq!(roundtrip_with(&log, endian::throw_be, endian::pick_up_be)?, log);
+///
+/// let key = Key::new(b"an example very very secret key.");
+/// let received = roundtrip_with(
+///     &log,
+///     |gutter, log| signed::throw(gutter, &key, log),
+///     |gutter, log| signed::pick_up(gutter, &key, log),
+/// )?;
+/// assert_eq!(received, log);
+///
+/// let fingerprint = schema::fingerprint::<[f64; 3]>();
+/// let received = roundtrip_with(
+///     &log,
+///     |gutter, log| schema::throw(gutter, fingerprint, log),
+///     |gutter, log| schema::pick_up(gutter, fingerprint, log),
+/// )?;
+/// assert_eq!(received, log);
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn roundtrip_with<T, F, P>(log: &T, throw: F, pick_up: P) -> Result<T>
+where
+    T: Log + Default,
+    F: FnOnce(&mut Cursor<Vec<u8>>, &T) -> Result<()>,
+    P: FnOnce(&mut Cursor<Vec<u8>>, &mut T) -> Result<()>,
+{
+    let mut gutter = Cursor::new(Vec::new());
+    throw(&mut gutter, log)?;
+
+    gutter.set_position(0);
+    let mut received = T::default();
+    pick_up(&mut gutter, &mut received)?;
+    Ok(received)
+}
+
+/// Send `payload` down an in-memory gutter with [`throw_framed`], and
+/// pick it back up with [`pick_up_vec`].
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```
+/// use gutters::testing::roundtrip_framed;
+///
+/// let samples = roundtrip_framed(&[1.0f64, 2.0, 3.0])?;
+/// assert_eq!(samples, [1.0, 2.0, 3.0]);
+/// assert!(roundtrip_framed::<f64>(&[])?.is_empty());
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn roundtrip_framed<T: Log>(payload: &[T]) -> Result<Vec<T>> {
+    let mut gutter = Cursor::new(Vec::new());
+    throw_framed(&mut gutter, payload)?;
+
+    gutter.set_position(0);
+    pick_up_vec(&mut gutter)
+}
+
+/// Send `payload` down an in-memory [`CompressedGutter`] using `codec`,
+/// and pick it back up.
+///
+/// The gutter negotiates with itself, so `codec` is always picked, and
+/// every payload goes through it, however short: the compressed frame
+/// is only thrown if it is shorter, as with any threshold.
+///
+/// # Examples
+///
+/// Basic usage, with the `RunLength` codec of [`Codec`]:
+///
+/// ```
+/// # use gutters::compressed::Codec;
+/// # use std::io::{Error, ErrorKind, Result};
+/// # #[derive(Debug)]
+/// # pub struct RunLength;
+/// # impl Codec for RunLength {
+/// #     fn name(&self) -> &str { "rle" }
+/// #     fn compress(&mut self, input: &[u8], output: &mut Vec<u8>) -> Result<()> {
+/// #         for run in input.chunk_by(|a, b| a == b) {
+/// #             for chunk in run.chunks(255) { output.extend_from_slice(&[chunk.len() as u8, chunk[0]]); }
+/// #         }
+/// #         Ok(())
+/// #     }
+/// #     fn decompress(&mut self, input: &[u8], max_len: usize, output: &mut Vec<u8>) -> Result<()> {
+/// #         for pair in input.chunks(2) {
+/// #             let [count, byte] = pair else { return Err(Error::new(ErrorKind::InvalidData, "truncated run")) };
+/// #             if output.len() + *count as usize > max_len { return Err(Error::new(ErrorKind::InvalidData, "too many runs")) }
+/// #             output.extend(std::iter::repeat_n(*byte, *count as usize));
+/// #         }
+/// #         Ok(())
+/// #     }
+/// # }
+/// use gutters::testing::roundtrip_compressed;
+///
+/// let samples = vec![0.0f32; 10_000];
+/// assert_eq!(roundtrip_compressed(&samples, Box::new(RunLength))?, samples);
+/// let noise: Vec<u8> = (0..=255).collect();
+/// assert_eq!(roundtrip_compressed(&noise, Box::new(RunLength))?, noise);
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn roundtrip_compressed<T: Log>(payload: &[T], codec: Box<dyn Codec>) -> Result<Vec<T>> {
+    // Reads return what was written, so the gutter can negotiate with
+    // itself.
+    let mut gutter = CompressedGutter::negotiate(VecDeque::new(), vec![codec], 0)?;
+    gutter.throw_framed(payload)?;
+    gutter.pick_up_vec()
+}
+
+/// Assert that the bytes of `log` match the golden file at `path`.
+///
+/// Golden files hold a canonical hex dump of a sample log, and are meant
+/// to be committed along with the tests, so that changes to the wire
+/// format of a message type show up in review. A missing golden file is
+/// created from `log`. Setting the `GUTTERS_BLESS` environment variable
+/// rewrites golden files instead of checking them, once a change is
+/// intended.
+///
+/// Logs are dumped with the endianness of the target, so golden files
+/// only hold for targets of the same endianness.
+///
+/// This function panics if the bytes differ, or if the golden file
+/// can't be read or written.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```
+/// use gutters::testing::assert_golden;
+/// use gutters::Log;
+///
+/// #[derive(Clone, Copy)]
+/// #[repr(C)]
+/// struct Sample {
+///     timestamp: u64,
+///     channels: [f32; 2],
+/// }
+/// unsafe impl Log for Sample {}
+///
+/// let path = std::env::temp_dir().join("gutters-doctest-sample.golden");
+/// # let _ = std::fs::remove_file(&path);
+/// let sample = Sample { timestamp: 7, channels: [0.5, -1.0] };
+/// assert_golden(&path, &sample);
+///
+/// // Committed golden files look like this.
+/// let golden = std::fs::read_to_string(&path).unwrap();
+/// assert_eq!(golden.lines().next(), Some("# gutters golden file, 16 bytes"));
+/// # std::fs::remove_file(&path).unwrap();
+/// ```
+pub fn assert_golden<T: Log, P: AsRef<Path>>(path: P, log: &T) {
+    let path = path.as_ref();
+    let dump = hex_dump(as_u8_slice(log));
+    let bless = std::env::var_os("GUTTERS_BLESS").is_some();
+    match fs::read_to_string(path) {
+        Ok(golden) if !bless => {
+            if golden != dump {
+                panic!(
+                    "log doesn't match the golden file {}\n\
+                     expected:\n{}\nfound:\n{}\n\
+                     set GUTTERS_BLESS to update it if the change is intended",
+                    path.display(),
+                    golden,
+                    dump
+                );
+            }
+        }
+        Err(e) if !bless && e.kind() != ErrorKind::NotFound => {
+            panic!("failed to read the golden file {}: {}", path.display(), e);
+        }
+        _ => {
+            if let Err(e) = fs::write(path, &dump) {
+                panic!("failed to write the golden file {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+/// Dump `bytes` like `hexdump -C`, with a header.
+fn hex_dump(bytes: &[u8]) -> String {
+    let mut dump = format!("# gutters golden file, {} bytes\n", bytes.len());
+    for (line, chunk) in bytes.chunks(16).enumerate() {
+        let _ = write!(dump, "{:08x} ", line * 16);
+        for (i, byte) in chunk.iter().enumerate() {
+            let separator = if i == 8 { "  " } else { " " };
+            let _ = write!(dump, "{}{:02x}", separator, byte);
+        }
+        let padding = (16 - chunk.len()) * 3 + usize::from(chunk.len() <= 8);
+        let text: String = chunk
+            .iter()
+            .map(|&byte| {
+                if byte.is_ascii_graphic() || byte == b' ' {
+                    byte as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        let _ = writeln!(dump, "{:padding$}  |{}|", "", text, padding = padding);
+    }
+    dump
+}
+
+/// Accept connections on `listener` forever, echoing back everything
+/// each peer sends.
+///
+/// Every connection is served by [`bench::echo_server`](crate::bench::echo_server)
+/// on its own thread, which makes this a canonical peer for load and
+/// soak tests. Since raw bytes are echoed, any message type or sequence
+/// of calls works, including [`hail`](crate::hail) and
+/// [`wait`](crate::wait).
+///
+/// This function is blocking, and only returns if accepting a
+/// connection fails.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```
+/// use gutters::testing::echo_server;
+/// use gutters::{pick_up, throw};
+/// use std::net::{TcpListener, TcpStream};
+///
+/// let listener = TcpListener::bind("127.0.0.1:0")?;
+/// let address = listener.local_addr()?;
+/// std::thread::spawn(move || echo_server(listener));
+///
+/// let mut stream = TcpStream::connect(address)?;
+/// throw(&mut stream, &42u64)?;
+/// let mut log = 0u64;
+/// pick_up(&mut stream, &mut log)?;
+/// assert_eq!(log, 42);
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn echo_server(listener: TcpListener) -> Result<()> {
+    serve(listener, |mut stream| {
+        crate::bench::echo_server(&mut stream)
+    })
+}
+
+/// Accept connections on `listener` forever, discarding everything each
+/// peer sends.
+///
+/// Every connection is served on its own thread until the peer
+/// disconnects. The sink never answers, so peers must only [`throw`]:
+/// a [`wait`](crate::wait) would block forever.
+///
+/// This function is blocking, and only returns if accepting a
+/// connection fails.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```
+/// use gutters::testing::sink_server;
+/// use gutters::throw;
+/// use std::net::{TcpListener, TcpStream};
+///
+/// let listener = TcpListener::bind("127.0.0.1:0")?;
+/// let address = listener.local_addr()?;
+/// std::thread::spawn(move || sink_server(listener));
+///
+/// let mut stream = TcpStream::connect(address)?;
+/// for i in 0..1000u32 {
+///     throw(&mut stream, &i)?;
+/// }
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn sink_server(listener: TcpListener) -> Result<()> {
+    serve(listener, |mut stream| {
+        io::copy(&mut stream, &mut io::sink())?;
+        Ok(())
+    })
+}
+
+fn serve<F>(listener: TcpListener, handler: F) -> Result<()>
+where
+    F: Fn(TcpStream) -> Result<()> + Copy + Send + 'static,
+{
+    loop {
+        let (stream, _) = listener.accept()?;
+        thread::spawn(move || handler(stream));
+    }
+}
+
+/// Create two in-memory gutters connected to each other.
+///
+/// What one end writes, the other reads, in both directions. Each end
+/// can be moved to its own thread, and reads end of file once the other
+/// end has been dropped. This is a [`shaped::pair`] without any delay.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```
+/// use gutters::testing::pair;
+/// use gutters::{pick_up_and_hail, throw_and_wait};
+///
+/// let (mut client, mut server) = pair();
+/// let server = std::thread::spawn(move || {
+///     let mut log = 0u32;
+///     pick_up_and_hail(&mut server, &mut log).map(|()| log)
+/// });
+///
+/// throw_and_wait(&mut client, &42u32)?;
+/// assert_eq!(server.join().unwrap()?, 42);
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn pair() -> (ShapedGutter, ShapedGutter) {
+    shaped::pair(Shape::new())
+}
+
+/// A gutter misbehaving in predictable ways, for robustness tests.
+///
+/// Unlike a [`ChaosGutter`](crate::chaos::ChaosGutter), faults are
+/// deterministic: reads can be cut short, every operation delayed, and
+/// the gutter can fail after a given number of bytes, in the middle of
+/// a message.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```
+/// use gutters::testing::{pair, FaultyGutter};
+/// use gutters::{pick_up, throw};
+/// use std::io::ErrorKind;
+///
+/// let (mut left, right) = pair();
+/// let mut right = FaultyGutter::new(right).short_reads(1).fail_after(12);
+///
+/// throw(&mut left, &[1.0f64, 2.0])?;
+/// let mut data = 0.0f64;
+/// pick_up(&mut right, &mut data)?; // Read one byte at a time.
+/// assert_eq!(data, 1.0);
+/// let error = pick_up(&mut right, &mut data).unwrap_err();
+/// assert_eq!(error.kind(), ErrorKind::ConnectionAborted);
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Debug)]
+pub struct FaultyGutter<G> {
+    gutter: G,
+    short_reads: Option<usize>,
+    delay: Duration,
+    fail_after: Option<u64>,
+    transferred: u64,
+}
+
+impl<G> FaultyGutter<G> {
+    /// Wrap `gutter`, without any fault yet.
+    pub fn new(gutter: G) -> Self {
+        FaultyGutter {
+            gutter,
+            short_reads: None,
+            delay: Duration::ZERO,
+            fail_after: None,
+            transferred: 0,
+        }
+    }
+
+    /// Return at most `max_len` bytes per read.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `max_len` is zero.
+    pub fn short_reads(mut self, max_len: usize) -> Self {
+        assert!(max_len > 0, "short reads of zero bytes");
+        self.short_reads = Some(max_len);
+        self
+    }
+
+    /// Sleep for `delay` before every read and write.
+    pub fn delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    /// Fail with [`ErrorKind::ConnectionAborted`] once `len` bytes have
+    /// been read and written in total.
+    pub fn fail_after(mut self, len: u64) -> Self {
+        self.fail_after = Some(len);
+        self
+    }
+
+    /// Move the gutter out.
+    pub fn into_inner(self) -> G {
+        self.gutter
+    }
+
+    /// Apply the delay, and return how many bytes the next operation may
+    /// transfer.
+    fn allowance(&mut self, len: usize) -> Result<usize> {
+        if !self.delay.is_zero() {
+            thread::sleep(self.delay);
+        }
+        match self.fail_after {
+            Some(limit) if self.transferred >= limit => Err(Error::new(
+                ErrorKind::ConnectionAborted,
+                "faulty gutter failed on purpose",
+            )),
+            Some(limit) => Ok(cmp::min(len as u64, limit - self.transferred) as usize),
+            None => Ok(len),
+        }
+    }
+}
+
+impl<G: Read> Read for FaultyGutter<G> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let mut len = self.allowance(buf.len())?;
+        if let Some(max_len) = self.short_reads {
+            len = cmp::min(len, max_len);
+        }
+        let n = self.gutter.read(&mut buf[..len])?;
+        self.transferred += n as u64;
+        Ok(n)
+    }
+}
+
+impl<G: Write> Write for FaultyGutter<G> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let len = self.allowance(buf.len())?;
+        let n = self.gutter.write(&buf[..len])?;
+        self.transferred += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.gutter.flush()
+    }
+}
@@ -0,0 +1,206 @@
+//! Receiving functions with a timeout.
+//!
+//! [`pick_up`] and [`wait`] give up with [`ErrorKind::TimedOut`] once
+//! their timeout elapses, so that a peer dying silently doesn't block
+//! the caller forever. They rely on the socket read timeout of gutters
+//! implementing [`ReadTimeout`], and restore it before returning. Other
+//! gutters can be used in non-blocking mode with [`pick_up_polling`]
+//! and [`wait_polling`] instead.
+//!
+//! When the timeout elapses in the middle of a log, the bytes of it
+//! already received are lost, so the gutter can't be used any further.
+//! A [`Gutter`] with a read buffer makes this unlikely for small logs.
+
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{as_u8_slice_mut, Gutter, Log};
+
+/// Gutters with a configurable read timeout.
+pub trait ReadTimeout {
+    /// Return the current read timeout, `None` meaning no timeout.
+    fn read_timeout(&self) -> Result<Option<Duration>>;
+
+    /// Set the read timeout, `None` meaning no timeout.
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()>;
+}
+
+impl ReadTimeout for TcpStream {
+    fn read_timeout(&self) -> Result<Option<Duration>> {
+        TcpStream::read_timeout(self)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+}
+
+#[cfg(unix)]
+impl ReadTimeout for std::os::unix::net::UnixStream {
+    fn read_timeout(&self) -> Result<Option<Duration>> {
+        std::os::unix::net::UnixStream::read_timeout(self)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        std::os::unix::net::UnixStream::set_read_timeout(self, timeout)
+    }
+}
+
+impl<G: Read + Write + ReadTimeout> ReadTimeout for Gutter<G> {
+    fn read_timeout(&self) -> Result<Option<Duration>> {
+        self.get_ref().read_timeout()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        self.get_ref().set_read_timeout(timeout)
+    }
+}
+
+/// Read a message of type `T` from the `gutter`, giving up once
+/// `timeout` has elapsed.
+///
+/// This function is blocking.
+///
+/// This function fails with [`ErrorKind::TimedOut`] if the message
+/// hasn't fully arrived in time.
+///
+/// This function doesn't change endianness, so it must be the
+/// same between the peers.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```
+/// use gutters::timeout;
+/// use std::io::ErrorKind;
+/// use std::net::{TcpListener, TcpStream};
+/// use std::time::Duration;
+///
+/// let listener = TcpListener::bind("127.0.0.1:0")?;
+/// let mut stream = TcpStream::connect(listener.local_addr()?)?;
+/// let _silent_peer = listener.accept()?;
+///
+/// let mut data = 0.0f64;
+/// let error = timeout::pick_up(&mut stream, &mut data, Duration::from_millis(50)).unwrap_err();
+/// assert_eq!(error.kind(), ErrorKind::TimedOut);
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn pick_up<G: Read + ReadTimeout, T: Log>(
+    gutter: &mut G,
+    buffer: &mut T,
+    timeout: Duration,
+) -> Result<()> {
+    read_exact_with_timeout(gutter, as_u8_slice_mut(buffer), timeout)
+}
+
+/// Wait for an acknowledgment from the `gutter`, giving up once
+/// `timeout` has elapsed.
+///
+/// This function is blocking.
+///
+/// This function fails with [`ErrorKind::TimedOut`] if no
+/// acknowledgment arrived in time. The exact byte value of the
+/// acknowledgment is *not* checked for.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use gutters::{throw, timeout};
+/// use std::net::TcpStream;
+/// use std::time::Duration;
+/// let mut stream = TcpStream::connect("127.0.0.1:34567")?;
+///
+/// throw(&mut stream, &64.0)?;
+/// timeout::wait(&mut stream, Duration::from_secs(5))?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn wait<G: Read + ReadTimeout>(gutter: &mut G, timeout: Duration) -> Result<()> {
+    read_exact_with_timeout(gutter, &mut [0u8], timeout)
+}
+
+/// Read a message of type `T` from the non-blocking `gutter`, giving up
+/// once `timeout` has elapsed.
+///
+/// Reads that would block are retried after a short sleep. This
+/// function fails with [`ErrorKind::TimedOut`] if the message hasn't
+/// fully arrived in time.
+///
+/// This function doesn't change endianness, so it must be the
+/// same between the peers.
+pub fn pick_up_polling<G: Read, T: Log>(
+    gutter: &mut G,
+    buffer: &mut T,
+    timeout: Duration,
+) -> Result<()> {
+    read_exact_polling(gutter, as_u8_slice_mut(buffer), timeout)
+}
+
+/// Wait for an acknowledgment from the non-blocking `gutter`, giving up
+/// once `timeout` has elapsed.
+///
+/// See [`pick_up_polling`].
+pub fn wait_polling<G: Read>(gutter: &mut G, timeout: Duration) -> Result<()> {
+    read_exact_polling(gutter, &mut [0u8], timeout)
+}
+
+fn read_exact_with_timeout<G: Read + ReadTimeout>(
+    gutter: &mut G,
+    buf: &mut [u8],
+    timeout: Duration,
+) -> Result<()> {
+    let previous = gutter.read_timeout()?;
+    let result = read_exact_until(
+        gutter,
+        buf,
+        Instant::now() + timeout,
+        |gutter, remaining| gutter.set_read_timeout(Some(remaining)),
+    );
+    gutter.set_read_timeout(previous)?;
+    result
+}
+
+fn read_exact_polling<G: Read>(gutter: &mut G, buf: &mut [u8], timeout: Duration) -> Result<()> {
+    let mut first = true;
+    read_exact_until(gutter, buf, Instant::now() + timeout, |_, remaining| {
+        if !first {
+            thread::sleep(remaining.min(Duration::from_millis(1)));
+        }
+        first = false;
+        Ok(())
+    })
+}
+
+fn read_exact_until<G: Read, F: FnMut(&mut G, Duration) -> Result<()>>(
+    gutter: &mut G,
+    mut buf: &mut [u8],
+    deadline: Instant,
+    mut before_read: F,
+) -> Result<()> {
+    while !buf.is_empty() {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(Error::new(ErrorKind::TimedOut, "gutter timed out"));
+        }
+        before_read(gutter, remaining)?;
+        match gutter.read(buf) {
+            Ok(0) => {
+                return Err(Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "failed to fill whole buffer",
+                ))
+            }
+            Ok(n) => buf = &mut buf[n..],
+            // Sockets report their read timeout as either of these,
+            // depending on the platform.
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,171 @@
+//! Trace context propagation.
+//!
+//! [`throw`] sends a [`TraceContext`] right before each log, and
+//! [`pick_up`] restores it on the other end, so that distributed traces
+//! can follow a request across gutter hops. The context holds the same
+//! fields as a W3C `traceparent` header, and converts to and from its
+//! textual form for use with any tracing library.
+//!
+//! On the wire, the context takes 26 bytes: a version byte (always 0),
+//! the trace id, the parent id and the trace flags.
+
+use std::fmt;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::str::FromStr;
+
+use crate::Log;
+
+/// A W3C trace context.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct TraceContext {
+    /// Identifier of the whole trace.
+    pub trace_id: [u8; 16],
+    /// Identifier of the span that threw the log.
+    pub parent_id: [u8; 8],
+    /// Trace flags, where bit 0 means "sampled".
+    pub flags: u8,
+}
+
+impl TraceContext {
+    fn to_bytes(self) -> [u8; 26] {
+        let mut bytes = [0u8; 26];
+        bytes[1..17].copy_from_slice(&self.trace_id);
+        bytes[17..25].copy_from_slice(&self.parent_id);
+        bytes[25] = self.flags;
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8; 26]) -> Result<Self> {
+        if bytes[0] != 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "unsupported trace context version",
+            ));
+        }
+        Ok(TraceContext {
+            trace_id: bytes[1..17].try_into().unwrap(),
+            parent_id: bytes[17..25].try_into().unwrap(),
+            flags: bytes[25],
+        })
+    }
+}
+
+/// Formats the context as a `traceparent` header value.
+impl fmt::Display for TraceContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("00-")?;
+        for byte in self.trace_id {
+            write!(f, "{:02x}", byte)?;
+        }
+        f.write_str("-")?;
+        for byte in self.parent_id {
+            write!(f, "{:02x}", byte)?;
+        }
+        write!(f, "-{:02x}", self.flags)
+    }
+}
+
+/// Parses a `traceparent` header value.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```
+/// use gutters::trace::TraceContext;
+///
+/// let header = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+/// let context: TraceContext = header.parse()?;
+/// assert_eq!(context.flags, 1);
+/// assert_eq!(context.to_string(), header);
+/// # Ok::<(), std::io::Error>(())
+/// ```
+impl FromStr for TraceContext {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::new(ErrorKind::InvalidData, "invalid traceparent header");
+        let fields: Vec<&str> = s.split('-').collect();
+        let [version, trace_id, parent_id, flags] = fields[..] else {
+            return Err(invalid());
+        };
+        if version != "00" || trace_id.len() != 32 || parent_id.len() != 16 || flags.len() != 2 {
+            return Err(invalid());
+        }
+
+        let mut bytes = [0u8; 26];
+        let hex = trace_id
+            .as_bytes()
+            .chunks(2)
+            .chain(parent_id.as_bytes().chunks(2))
+            .chain(flags.as_bytes().chunks(2));
+        for (byte, digits) in bytes[1..].iter_mut().zip(hex) {
+            if !digits.iter().all(u8::is_ascii_hexdigit) {
+                return Err(invalid());
+            }
+            let digits = std::str::from_utf8(digits).map_err(|_| invalid())?;
+            *byte = u8::from_str_radix(digits, 16).map_err(|_| invalid())?;
+        }
+        TraceContext::from_bytes(&bytes)
+    }
+}
+
+/// Send a message of type `T` to the `gutter`, preceded by the trace
+/// `context` it belongs to.
+///
+/// This function is blocking.
+///
+/// This function doesn't change endianness, so it must be the
+/// same between the peers.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use gutters::trace::{self, TraceContext};
+/// use std::net::TcpStream;
+/// let mut stream = TcpStream::connect("127.0.0.1:34567")?;
+///
+/// let context: TraceContext = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01".parse()?;
+/// trace::throw(&mut stream, &context, &64.0)?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn throw<G: Write, T: Log>(gutter: &mut G, context: &TraceContext, buffer: &T) -> Result<()> {
+    gutter.write_all(&context.to_bytes())?;
+    gutter.write_all(crate::as_u8_slice(buffer))
+}
+
+/// Read a message of type `T` from the `gutter`, and return the trace
+/// context it was thrown with.
+///
+/// This function is blocking.
+///
+/// This function doesn't change endianness, so it must be the
+/// same between the peers.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```
+/// use gutters::trace::{self, TraceContext};
+/// use std::io::Cursor;
+///
+/// let sent: TraceContext = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01".parse()?;
+/// let mut gutter = Cursor::new(Vec::new());
+/// trace::throw(&mut gutter, &sent, &64.0f64)?;
+///
+/// gutter.set_position(0);
+/// let mut data = 0.0f64;
+/// let received = trace::pick_up(&mut gutter, &mut data)?;
+/// assert_eq!(received, sent);
+/// assert_eq!(data, 64.0);
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn pick_up<G: Read, T: Log>(gutter: &mut G, buffer: &mut T) -> Result<TraceContext> {
+    let mut bytes = [0u8; 26];
+    gutter.read_exact(&mut bytes)?;
+    gutter.read_exact(crate::as_u8_slice_mut(buffer))?;
+    TraceContext::from_bytes(&bytes)
+}
@@ -0,0 +1,184 @@
+//! Establishing gutters through an HTTP/1.1 upgrade.
+//!
+//! Some networks only let HTTP through, often via middleboxes that
+//! inspect it. [`connect`] opens a gutter by sending an HTTP request
+//! asking to upgrade the connection to the `gutters` protocol, and
+//! [`accept`] answers it on the server side. Once the upgrade is done,
+//! the stream carries logs like any other gutter.
+
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+
+use crate::proxy::{check_head_field, read_head};
+
+/// Name of the protocol in the `Upgrade` header.
+pub const PROTOCOL: &str = "gutters";
+
+/// Connect to the HTTP server at `addr`, and upgrade the connection to a
+/// gutter.
+///
+/// `host` and `path` are sent as the `Host` header and request target,
+/// so that reverse proxies can route the request.
+///
+/// This function is blocking.
+///
+/// This function fails with [`ErrorKind::InvalidInput`] if `host` or
+/// `path` is empty or contains spaces or control characters, and with
+/// [`ErrorKind::ConnectionRefused`] if the server doesn't upgrade the
+/// connection.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use gutters::{throw, upgrade};
+///
+/// let mut stream = upgrade::connect("203.0.113.7:80", "collector.example.com", "/telemetry")?;
+/// throw(&mut stream, &64.0)?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+///
+/// Against a server that doesn't speak the `gutters` protocol:
+///
+/// ```
+/// use gutters::upgrade;
+/// use std::io::{ErrorKind, Read, Write};
+/// use std::net::TcpListener;
+/// use std::thread;
+///
+/// let listener = TcpListener::bind("127.0.0.1:0")?;
+/// let addr = listener.local_addr()?;
+/// thread::spawn(move || -> std::io::Result<()> {
+///     let (mut stream, _) = listener.accept()?;
+///     stream.read(&mut [0u8; 1024])?;
+///     stream.write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n")
+/// });
+///
+/// let error = upgrade::connect(addr, "localhost", "/telemetry\r\nX-Evil: 1").unwrap_err();
+/// assert_eq!(error.kind(), ErrorKind::InvalidInput);
+/// let error = upgrade::connect(addr, "localhost", "/telemetry").unwrap_err();
+/// assert_eq!(error.kind(), ErrorKind::ConnectionRefused);
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn connect<A: ToSocketAddrs>(addr: A, host: &str, path: &str) -> Result<TcpStream> {
+    check_head_field(host, "host")?;
+    check_head_field(path, "path")?;
+    let mut stream = TcpStream::connect(addr)?;
+    request(&mut stream, host, path)?;
+    Ok(stream)
+}
+
+/// Upgrade an already open HTTP connection to a gutter.
+///
+/// This is the client side of [`connect`], for connections that aren't
+/// plain TCP streams, e.g. ones opened through a [proxy](crate::proxy).
+///
+/// This function is blocking.
+///
+/// See [`connect`].
+pub fn request<G: Read + Write>(gutter: &mut G, host: &str, path: &str) -> Result<()> {
+    check_head_field(host, "host")?;
+    check_head_field(path, "path")?;
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: Upgrade\r\nUpgrade: {}\r\n\r\n",
+        path, host, PROTOCOL
+    );
+    gutter.write_all(request.as_bytes())?;
+    gutter.flush()?;
+
+    let response = read_head(gutter)?;
+    let status = response
+        .split(|&b| b == b' ')
+        .nth(1)
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "malformed HTTP response"))?;
+    if status != b"101" {
+        return Err(Error::new(
+            ErrorKind::ConnectionRefused,
+            format!(
+                "server refused to upgrade: {}",
+                String::from_utf8_lossy(&response[..response.len() - 4])
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Answer an upgrade request on a freshly accepted connection, and
+/// return the requested path.
+///
+/// Requests that don't ask for the `gutters` protocol are answered with
+/// `426 Upgrade Required`, and this function then fails with
+/// [`ErrorKind::InvalidData`].
+///
+/// This function is blocking.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```
+/// use gutters::{pick_up, throw, upgrade};
+/// use std::net::TcpListener;
+/// use std::thread;
+///
+/// let listener = TcpListener::bind("127.0.0.1:0")?;
+/// let addr = listener.local_addr()?;
+/// let server = thread::spawn(move || -> std::io::Result<f64> {
+///     let (mut stream, _) = listener.accept()?;
+///     assert_eq!(upgrade::accept(&mut stream)?, "/telemetry");
+///     let mut data = 0.0f64;
+///     pick_up(&mut stream, &mut data)?;
+///     Ok(data)
+/// });
+///
+/// let mut stream = upgrade::connect(addr, "localhost", "/telemetry")?;
+/// throw(&mut stream, &64.0f64)?;
+/// assert_eq!(server.join().unwrap()?, 64.0);
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn accept<G: Read + Write>(gutter: &mut G) -> Result<String> {
+    let head = read_head(gutter)?;
+    let head = std::str::from_utf8(&head)
+        .map_err(|_| Error::new(ErrorKind::InvalidData, "malformed HTTP request"))?;
+    let mut lines = head.split("\r\n");
+    let path = lines
+        .next()
+        .and_then(|line| line.split(' ').nth(1))
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "malformed HTTP request"))?;
+
+    let mut connection = false;
+    let mut upgrade = false;
+    for line in lines {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let mut tokens = value.split(',').map(str::trim);
+        if name.eq_ignore_ascii_case("connection") {
+            connection |= tokens.any(|token| token.eq_ignore_ascii_case("upgrade"));
+        } else if name.eq_ignore_ascii_case("upgrade") {
+            upgrade |= tokens.any(|token| token.eq_ignore_ascii_case(PROTOCOL));
+        }
+    }
+
+    if !(connection && upgrade) {
+        let response = format!(
+            "HTTP/1.1 426 Upgrade Required\r\nConnection: Upgrade\r\nUpgrade: {}\r\nContent-Length: 0\r\n\r\n",
+            PROTOCOL
+        );
+        gutter.write_all(response.as_bytes())?;
+        gutter.flush()?;
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "not a gutter upgrade request",
+        ));
+    }
+
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: {}\r\n\r\n",
+        PROTOCOL
+    );
+    gutter.write_all(response.as_bytes())?;
+    gutter.flush()?;
+    Ok(path.to_owned())
+}